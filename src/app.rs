@@ -0,0 +1,964 @@
+//! State for the interactive parts of the dashboard (popups, messages and actions),
+//! and the handling of key presses that change it.
+//! Actions which talk to AdGuard are run in the background, and report back over a channel,
+//! so the UI never blocks waiting on the network
+
+use std::{
+    future::Future,
+    ops::Range,
+    time::{Duration, Instant},
+};
+use chrono::{Days, Local, TimeZone};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use tokio::sync::{mpsc, watch};
+use tui::widgets::{ListState, TableState};
+
+use crate::config::{LayoutOptions, Theme};
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+use crate::fetch::fetch_filters::{AdGuardFilteringStatus, Filter};
+use crate::fetch::fetch_query_log::{Query, QueryFilter, QueryKey, ResponseStatus};
+use crate::fetch::fetch_stats::StatsResponse;
+use crate::fetch::fetch_status::StatusResponse;
+use crate::instances::{Instance, View};
+use crate::rules::{make_rule, rule_target, validate_rule, RuleKind, RuleScope};
+
+/// How long a message stays visible after an action completes
+const FLASH_DURATION: Duration = Duration::from_secs(5);
+
+/// Choices for how long to pause protection for. `None` means until midnight
+pub const PAUSE_OPTIONS: [(&str, Option<Duration>); 5] = [
+    ("30 seconds", Some(Duration::from_secs(30))),
+    ("1 minute", Some(Duration::from_secs(60))),
+    ("10 minutes", Some(Duration::from_secs(10 * 60))),
+    ("1 hour", Some(Duration::from_secs(60 * 60))),
+    ("Until tomorrow", None),
+];
+
+/// A popup drawn over the top of the dashboard, which captures key presses while open
+pub enum Popup {
+    /// Picker for how long to pause protection, with the index of the selected option
+    PauseProtection { selected: usize },
+    /// Every detail of a single query, scrolled down by a number of lines
+    QueryDetails { query: Box<Query>, scroll: u16 },
+    /// Confirmation before adding a rule to block or allow a host (or its parent domain)
+    ConfirmRule { host: String, kind: RuleKind, scope: RuleScope },
+    /// Prompt for searching the query log, by domain or client, and response status
+    Search { input: String, status: ResponseStatus },
+    /// Filter lists ranked by how many queries they've blocked, scrolled down by a number of lines
+    FilterEffectiveness { scroll: u16 },
+    /// Form for adding a new filter list
+    AddFilter(FilterForm),
+    /// Confirmation before removing a filter list
+    ConfirmRemoveFilter { filter: Filter },
+    /// Editor for the custom user rules
+    UserRules(RulesEditor),
+}
+
+/// The custom user rules, being edited as lines of text
+pub struct RulesEditor {
+    pub lines: Vec<String>,
+    /// Line the cursor is on
+    pub row: usize,
+    /// Character (not byte) within the line that the cursor is before
+    pub col: usize,
+}
+
+impl RulesEditor {
+    fn new(rules: &[String]) -> Self {
+        let mut lines = rules.to_vec();
+        if lines.is_empty() {
+            lines.push(String::new());
+        }
+        RulesEditor { lines, row: 0, col: 0 }
+    }
+
+    /// Byte offset of the cursor within its line
+    fn byte_index(&self) -> usize {
+        let line = &self.lines[self.row];
+        line.char_indices().nth(self.col).map_or(line.len(), |(index, _)| index)
+    }
+
+    fn line_len(&self, row: usize) -> usize {
+        self.lines[row].chars().count()
+    }
+
+    /// Moves the cursor to another line, keeping it within the line's length
+    fn move_to_row(&mut self, row: usize) {
+        self.row = row.min(self.lines.len() - 1);
+        self.col = self.col.min(self.line_len(self.row));
+    }
+
+    /// Applies a key press which moves the cursor or changes the text
+    fn handle_key(&mut self, key: &KeyEvent) {
+        match key.code {
+            KeyCode::Up => self.move_to_row(self.row.saturating_sub(1)),
+            KeyCode::Down => self.move_to_row(self.row + 1),
+            KeyCode::Left if self.col > 0 => self.col -= 1,
+            KeyCode::Left if self.row > 0 => {
+                self.row -= 1;
+                self.col = self.line_len(self.row);
+            }
+            KeyCode::Right if self.col < self.line_len(self.row) => self.col += 1,
+            KeyCode::Right if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.col = 0;
+            }
+            KeyCode::Home => self.col = 0,
+            KeyCode::End => self.col = self.line_len(self.row),
+            KeyCode::Enter => {
+                let index = self.byte_index();
+                let rest = self.lines[self.row].split_off(index);
+                self.lines.insert(self.row + 1, rest);
+                self.row += 1;
+                self.col = 0;
+            }
+            KeyCode::Backspace if self.col > 0 => {
+                self.col -= 1;
+                let index = self.byte_index();
+                self.lines[self.row].remove(index);
+            }
+            // At the start of a line, Backspace joins it onto the one above
+            KeyCode::Backspace if self.row > 0 => {
+                let line = self.lines.remove(self.row);
+                self.row -= 1;
+                self.col = self.line_len(self.row);
+                self.lines[self.row].push_str(&line);
+            }
+            KeyCode::Delete if self.col < self.line_len(self.row) => {
+                let index = self.byte_index();
+                self.lines[self.row].remove(index);
+            }
+            KeyCode::Delete if self.row + 1 < self.lines.len() => {
+                let line = self.lines.remove(self.row + 1);
+                self.lines[self.row].push_str(&line);
+            }
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let index = self.byte_index();
+                self.lines[self.row].insert(index, c);
+                self.col += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// The first line (counting from 0) that doesn't look like a valid rule, and why
+    pub fn first_invalid(&self) -> Option<(usize, String)> {
+        self.lines.iter().enumerate()
+            .find_map(|(row, line)| validate_rule(line).err().map(|e| (row, e)))
+    }
+
+    /// The rules to save, without any trailing blank lines
+    fn rules(&self) -> Vec<String> {
+        let mut rules = self.lines.clone();
+        while rules.last().is_some_and(|line| line.trim().is_empty()) {
+            rules.pop();
+        }
+        rules
+    }
+}
+
+/// Fields of the form for adding a filter list
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterField {
+    #[default]
+    Name,
+    Url,
+    /// Whether it's a blocklist or an allowlist
+    Kind,
+}
+
+impl FilterField {
+    fn next(self) -> Self {
+        match self {
+            FilterField::Name => FilterField::Url,
+            FilterField::Url => FilterField::Kind,
+            FilterField::Kind => FilterField::Name,
+        }
+    }
+
+    fn previous(self) -> Self {
+        match self {
+            FilterField::Name => FilterField::Kind,
+            FilterField::Url => FilterField::Name,
+            FilterField::Kind => FilterField::Url,
+        }
+    }
+}
+
+/// What's been entered so far in the form for adding a filter list, and the field being changed
+#[derive(Default)]
+pub struct FilterForm {
+    pub name: String,
+    pub url: String,
+    pub allowlist: bool,
+    pub field: FilterField,
+}
+
+/// The panel that navigation and actions currently apply to
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Focus {
+    QueryLog,
+    Filters,
+    TopQueried,
+    TopBlocked,
+}
+
+impl Focus {
+    /// The next panel along, wrapping around. Lists are skipped if they're not on screen
+    fn next(self, lists_visible: bool) -> Self {
+        match self {
+            Focus::QueryLog if lists_visible => Focus::Filters,
+            Focus::Filters => Focus::TopQueried,
+            Focus::TopQueried => Focus::TopBlocked,
+            _ => Focus::QueryLog,
+        }
+    }
+
+    /// The previous panel, wrapping around
+    fn previous(self, lists_visible: bool) -> Self {
+        match self {
+            Focus::QueryLog if lists_visible => Focus::TopBlocked,
+            Focus::TopBlocked => Focus::TopQueried,
+            Focus::TopQueried => Focus::Filters,
+            _ => Focus::QueryLog,
+        }
+    }
+}
+
+/// The latest data, for key presses which act on whatever is currently on screen
+pub struct Snapshot<'a> {
+    pub queries: &'a [Query],
+    pub stats: Option<&'a StatsResponse>,
+    pub status: Option<&'a StatusResponse>,
+    pub filters: Option<&'a AdGuardFilteringStatus>,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Blocklists then allowlists, in the order they're shown in the Filters panel
+    fn filter_lists(&self) -> Vec<&'a Filter> {
+        self.filters.map(|filters| filters.lists().collect()).unwrap_or_default()
+    }
+}
+
+/// A short message, shown after an action completes, or when something goes wrong in the background
+pub struct Flash {
+    pub message: String,
+    pub is_error: bool,
+    shown_at: Instant,
+}
+
+impl Flash {
+    pub fn new(message: String, is_error: bool) -> Self {
+        Flash { message, is_error, shown_at: Instant::now() }
+    }
+}
+
+
+pub struct App {
+    /// Every instance on the dashboard. Actions apply to those in view
+    instances: Vec<Instance>,
+    /// Which instances are shown, which they're combined for
+    view: watch::Sender<View>,
+    /// The search applied to the query log, which the fetcher picks up
+    query_filter: watch::Sender<QueryFilter>,
+    flash_tx: mpsc::UnboundedSender<Flash>,
+    pub popup: Option<Popup>,
+    flash: Option<Flash>,
+    status_received_at: Instant,
+    /// Scroll position and selected row of the query log
+    pub query_table: TableState,
+    selected_query: Option<QueryKey>,
+    /// Index of the first query log row on screen
+    query_offset: usize,
+    /// Number of query log rows that fit on screen, for paging up and down
+    query_page_size: usize,
+    /// How far the open popup can be scrolled, before it runs out of content
+    popup_scroll_limit: u16,
+    pub focus: Focus,
+    pub filters_list: ListState,
+    pub top_queried_list: ListState,
+    pub top_blocked_list: ListState,
+    /// Whether the lists along the bottom fit on screen, and so can be focused
+    lists_visible: bool,
+    pub theme: Theme,
+    pub layout: LayoutOptions,
+}
+
+impl App {
+    /// Creates the app state. Action results are sent to `flash_tx`
+    pub fn new(
+        instances: Vec<Instance>,
+        view: watch::Sender<View>,
+        query_filter: watch::Sender<QueryFilter>,
+        flash_tx: mpsc::UnboundedSender<Flash>,
+        theme: Theme,
+        layout: LayoutOptions,
+    ) -> Self {
+        App {
+            instances,
+            view,
+            query_filter,
+            flash_tx,
+            popup: None,
+            flash: None,
+            status_received_at: Instant::now(),
+            query_table: TableState::default(),
+            selected_query: None,
+            query_offset: 0,
+            query_page_size: 10,
+            popup_scroll_limit: 0,
+            focus: Focus::QueryLog,
+            filters_list: ListState::default(),
+            top_queried_list: ListState::default(),
+            top_blocked_list: ListState::default(),
+            lists_visible: false,
+            theme,
+            layout,
+        }
+    }
+
+    /// Which instances are being shown
+    pub fn view(&self) -> View {
+        *self.view.borrow()
+    }
+
+    /// Switches to showing the next instance on its own, or all of them after the last
+    fn next_view(&mut self) {
+        if self.instances.len() < 2 {
+            return;
+        }
+        let view = self.view().next(self.instances.len());
+        self.view.send_replace(view);
+        self.selected_query = None;
+        self.query_table.select(None);
+        let message = match view {
+            View::All => "Showing all instances".to_string(),
+            View::Single(index) => format!("Showing {}", self.instances[index].name),
+        };
+        self.show_flash(Flash::new(message, false));
+    }
+
+    /// Whether a single instance is being shown. Filter lists and custom rules differ between instances,
+    /// so they can only be changed for one at a time, rather than copying one instance's to the rest
+    fn single_instance_shown(&self) -> bool {
+        self.instances.len() < 2 || self.view() != View::All
+    }
+
+    /// Explains how to pick a single instance, after trying to change filter lists or rules while several are shown
+    fn show_single_instance_needed(&mut self) {
+        self.show_flash(Flash::new("Press i to show a single instance, to change its filter lists or rules".to_string(), true));
+    }
+
+    /// The instances that actions apply to: the one being shown, or all of them
+    fn targets(&self) -> Vec<Instance> {
+        self.instances.iter().enumerate()
+            .filter(|(index, _)| self.view().includes(*index))
+            .map(|(_, instance)| instance.clone())
+            .collect()
+    }
+
+    /// A warning, if any instance being shown doesn't have its TLS certificate verified
+    pub fn insecure_warning(&self) -> Option<String> {
+        let insecure: Vec<&str> = self.instances.iter().enumerate()
+            .filter(|(index, instance)| self.view().includes(*index) && instance.client.is_insecure())
+            .map(|(_, instance)| instance.name.as_str())
+            .collect();
+        match insecure.as_slice() {
+            [] => None,
+            _ if self.instances.len() < 2 => Some("TLS NOT VERIFIED - connection is insecure".to_string()),
+            names => Some(format!("TLS NOT VERIFIED for {} - insecure", names.join(", "))),
+        }
+    }
+
+    /// Shows the result of a completed action
+    pub fn show_flash(&mut self, flash: Flash) {
+        self.flash = Some(flash);
+    }
+
+    /// The current message, if there is one and it hasn't expired yet
+    pub fn flash(&self) -> Option<&Flash> {
+        self.flash.as_ref().filter(|flash| flash.shown_at.elapsed() < FLASH_DURATION)
+    }
+
+    /// Should be called whenever a new status arrives, so the countdown stays accurate
+    pub fn status_received(&mut self) {
+        self.status_received_at = Instant::now();
+    }
+
+    /// Should be called whenever a new page of the query log arrives,
+    /// to keep the same query selected, even if it's moved
+    pub fn queries_received(&mut self, queries: &[Query]) {
+        let selected = match (&self.selected_query, self.query_table.selected()) {
+            (Some(key), Some(index)) => queries
+                .iter()
+                .position(|query| &query.key() == key)
+                .unwrap_or_else(|| index.min(queries.len().saturating_sub(1))),
+            _ => return,
+        };
+        self.select_query(queries, Some(selected));
+    }
+
+    /// Should be called whenever the filter lists arrive, so the selection stays within the list
+    pub fn filters_received(&mut self, count: usize) {
+        if let Some(selected) = self.filters_list.selected() {
+            self.filters_list.select(count.checked_sub(1).map(|last| selected.min(last)));
+        }
+    }
+
+    /// The search currently applied to the query log
+    pub fn query_filter(&self) -> QueryFilter {
+        self.query_filter.borrow().clone()
+    }
+
+    /// Should be called when drawing, with the number of query log rows that fit on screen
+    pub fn set_query_page_size(&mut self, rows: usize) {
+        self.query_page_size = rows.max(1);
+    }
+
+    /// Should be called when drawing, with how far the open popup can be scrolled
+    pub fn set_popup_scroll_limit(&mut self, limit: u16) {
+        self.popup_scroll_limit = limit;
+    }
+
+    /// Should be called when drawing, with whether the lists along the bottom are on screen
+    pub fn set_lists_visible(&mut self, visible: bool) {
+        self.lists_visible = visible;
+        if !visible && self.focus != Focus::QueryLog {
+            self.focus = Focus::QueryLog;
+        }
+    }
+
+    /// The range of query log rows to draw, scrolled so that the selected row is on screen,
+    /// along with the table state to draw them with (relative to the start of the range)
+    pub fn query_window(&mut self, len: usize) -> (Range<usize>, TableState) {
+        let page_size = self.query_page_size;
+        self.query_offset = match self.query_table.selected() {
+            Some(selected) if selected < self.query_offset => selected,
+            Some(selected) if selected >= self.query_offset + page_size => selected + 1 - page_size,
+            Some(_) => self.query_offset,
+            None => 0,
+        }
+        .min(len.saturating_sub(page_size));
+        let window = self.query_offset..(self.query_offset + page_size).min(len);
+
+        let mut state = TableState::default();
+        state.select(self.query_table.selected().map(|selected| selected - window.start));
+        (window, state)
+    }
+
+    fn select_query(&mut self, queries: &[Query], index: Option<usize>) {
+        let index = index.filter(|&i| i < queries.len());
+        self.selected_query = index.map(|i| queries[i].key());
+        self.query_table.select(index);
+    }
+
+    /// Moves the selected row of the query log up (negative) or down (positive)
+    fn move_query_selection(&mut self, queries: &[Query], delta: isize) {
+        if queries.is_empty() {
+            return;
+        }
+        let next = match self.query_table.selected() {
+            Some(current) => (current as isize + delta).clamp(0, queries.len() as isize - 1) as usize,
+            None => 0,
+        };
+        self.select_query(queries, Some(next));
+    }
+
+    /// Moves the selection of the focused panel by `delta` rows, or to the given end if
+    /// `delta` is `isize::MIN` (top) or `isize::MAX` (bottom)
+    fn move_selection(&mut self, data: &Snapshot, delta: isize) {
+        let (state, len) = match self.focus {
+            Focus::QueryLog => {
+                let target = match delta {
+                    isize::MIN => Some(0),
+                    isize::MAX => data.queries.len().checked_sub(1),
+                    _ => return self.move_query_selection(data.queries, delta),
+                };
+                return self.select_query(data.queries, target);
+            }
+            Focus::Filters => (&mut self.filters_list, data.filter_lists().len()),
+            Focus::TopQueried => (&mut self.top_queried_list, data.stats.map_or(0, |s| s.top_queried_domains.len())),
+            Focus::TopBlocked => (&mut self.top_blocked_list, data.stats.map_or(0, |s| s.top_blocked_domains.len())),
+        };
+        if len == 0 {
+            state.select(None);
+            return;
+        }
+        let next = match (state.selected(), delta) {
+            (_, isize::MIN) | (None, _) => 0,
+            (_, isize::MAX) => len - 1,
+            (Some(current), _) => (current as isize + delta).clamp(0, len as isize - 1) as usize,
+        };
+        state.select(Some(next));
+    }
+
+    /// The domain selected in the focused panel, if any
+    fn selected_domain(&self, data: &Snapshot) -> Option<String> {
+        match self.focus {
+            Focus::QueryLog => self.query_table.selected()
+                .and_then(|i| data.queries.get(i))
+                .map(|query| query.question.name.clone()),
+            Focus::TopQueried => self.top_queried_list.selected()
+                .and_then(|i| data.stats?.top_queried_domains.get(i))
+                .map(|domain| domain.name.clone()),
+            Focus::TopBlocked => self.top_blocked_list.selected()
+                .and_then(|i| data.stats?.top_blocked_domains.get(i))
+                .map(|domain| domain.name.clone()),
+            Focus::Filters => None,
+        }
+    }
+
+    /// The filter list selected in the Filters panel, if it's focused
+    fn selected_filter<'a>(&self, data: &'a Snapshot) -> Option<&'a Filter> {
+        if self.focus != Focus::Filters {
+            return None;
+        }
+        self.filters_list.selected().and_then(|i| data.filter_lists().get(i).copied())
+    }
+
+    /// How long until protection is automatically re-enabled, if it's been paused for a set time
+    pub fn protection_resumes_in(&self, status: &StatusResponse) -> Option<Duration> {
+        if status.protection_enabled || status.protection_disabled_duration == 0 {
+            return None;
+        }
+        let paused_for = Duration::from_millis(status.protection_disabled_duration);
+        Some(paused_for.saturating_sub(self.status_received_at.elapsed()))
+    }
+
+    /// Handles a terminal event, returning true if the dashboard should close
+    pub fn handle_event(&mut self, event: &Event, data: &Snapshot) -> bool {
+        let key = match event {
+            Event::Key(key) => key,
+            _ => return false,
+        };
+        if key.code == KeyCode::Char('c') && key.modifiers == KeyModifiers::CONTROL {
+            return true;
+        }
+        match self.popup.take() {
+            Some(popup) => {
+                self.popup = self.handle_popup_key(popup, key);
+                false
+            }
+            None => self.handle_key(key, data),
+        }
+    }
+
+    /// Handles a key press on the main dashboard, returning true if it should close
+    fn handle_key(&mut self, key: &KeyEvent, data: &Snapshot) -> bool {
+        let page = self.query_page_size as isize;
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Char('Q') => return true,
+            KeyCode::Tab => self.focus = self.focus.next(self.lists_visible),
+            KeyCode::BackTab => self.focus = self.focus.previous(self.lists_visible),
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(data, -1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(data, 1),
+            KeyCode::PageUp => self.move_selection(data, -page),
+            KeyCode::PageDown => self.move_selection(data, page),
+            KeyCode::Home | KeyCode::Char('g') => self.move_selection(data, isize::MIN),
+            KeyCode::End | KeyCode::Char('G') => self.move_selection(data, isize::MAX),
+            // With nothing selected, Esc clears the search instead
+            KeyCode::Esc if self.focus == Focus::QueryLog && self.query_table.selected().is_none() => {
+                self.apply_query_filter(QueryFilter::default());
+            }
+            KeyCode::Esc => match self.focus {
+                Focus::QueryLog => self.select_query(data.queries, None),
+                Focus::Filters => self.filters_list.select(None),
+                Focus::TopQueried => self.top_queried_list.select(None),
+                Focus::TopBlocked => self.top_blocked_list.select(None),
+            },
+            KeyCode::Enter if self.focus == Focus::QueryLog => {
+                if let Some(query) = self.query_table.selected().and_then(|i| data.queries.get(i)) {
+                    self.popup = Some(Popup::QueryDetails { query: Box::new(query.clone()), scroll: 0 });
+                }
+            }
+            KeyCode::Char(c @ ('b' | 'a')) => {
+                if let Some(host) = self.selected_domain(data) {
+                    let kind = if c == 'b' { RuleKind::Block } else { RuleKind::Allow };
+                    self.popup = Some(Popup::ConfirmRule { host, kind, scope: RuleScope::ExactHost });
+                }
+            }
+            KeyCode::Char('p') => {
+                if let Some(status) = data.status {
+                    self.toggle_protection(status.protection_enabled);
+                }
+            }
+            KeyCode::Char('P') => {
+                self.popup = Some(Popup::PauseProtection { selected: 0 });
+            }
+            KeyCode::Char('/') => {
+                let filter = self.query_filter();
+                self.popup = Some(Popup::Search { input: filter.search, status: filter.response_status });
+            }
+            KeyCode::Char('e') => {
+                self.popup = Some(Popup::FilterEffectiveness { scroll: 0 });
+            }
+            KeyCode::Char(' ') if self.focus == Focus::Filters => {
+                if !self.single_instance_shown() {
+                    self.show_single_instance_needed();
+                    return false;
+                }
+                if let Some(filter) = self.selected_filter(data) {
+                    self.set_filter_enabled(filter.clone(), !filter.enabled);
+                }
+            }
+            KeyCode::Char('d') | KeyCode::Delete if self.focus == Focus::Filters => {
+                if !self.single_instance_shown() {
+                    self.show_single_instance_needed();
+                    return false;
+                }
+                if let Some(filter) = self.selected_filter(data) {
+                    self.popup = Some(Popup::ConfirmRemoveFilter { filter: filter.clone() });
+                }
+            }
+            KeyCode::Char('n') if self.focus == Focus::Filters => {
+                self.popup = Some(Popup::AddFilter(FilterForm::default()));
+            }
+            KeyCode::Char('r') if self.focus == Focus::Filters => self.refresh_filters(),
+            KeyCode::Char('i') => self.next_view(),
+            KeyCode::Char('u') => {
+                if !self.single_instance_shown() {
+                    self.show_single_instance_needed();
+                    return false;
+                }
+                if let Some(filters) = data.filters {
+                    self.popup = Some(Popup::UserRules(RulesEditor::new(&filters.user_rules)));
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Handles a key press while a popup is open, returning the popup if it should stay open
+    fn handle_popup_key(&mut self, popup: Popup, key: &KeyEvent) -> Option<Popup> {
+        match popup {
+            Popup::PauseProtection { selected } => match key.code {
+                KeyCode::Esc | KeyCode::Char('q') => None,
+                KeyCode::Up | KeyCode::Char('k') => Some(Popup::PauseProtection {
+                    selected: selected.saturating_sub(1),
+                }),
+                KeyCode::Down | KeyCode::Char('j') => Some(Popup::PauseProtection {
+                    selected: (selected + 1).min(PAUSE_OPTIONS.len() - 1),
+                }),
+                KeyCode::Enter => {
+                    self.pause_protection(selected);
+                    None
+                }
+                _ => Some(popup),
+            },
+            Popup::QueryDetails { query, scroll } => match key.code {
+                KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => None,
+                KeyCode::Up | KeyCode::Char('k') => Some(Popup::QueryDetails {
+                    query,
+                    scroll: scroll.min(self.popup_scroll_limit).saturating_sub(1),
+                }),
+                KeyCode::Down | KeyCode::Char('j') => Some(Popup::QueryDetails {
+                    query,
+                    scroll: scroll.saturating_add(1).min(self.popup_scroll_limit),
+                }),
+                _ => Some(Popup::QueryDetails { query, scroll }),
+            },
+            Popup::ConfirmRule { host, kind, scope } => match key.code {
+                KeyCode::Esc | KeyCode::Char('n') | KeyCode::Char('q') => None,
+                KeyCode::Enter | KeyCode::Char('y') => {
+                    self.add_rule(rule_target(&host, scope), kind);
+                    None
+                }
+                KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down | KeyCode::Tab | KeyCode::Char(' ') => {
+                    let scope = match scope {
+                        RuleScope::ExactHost => RuleScope::ParentDomain,
+                        RuleScope::ParentDomain => RuleScope::ExactHost,
+                    };
+                    Some(Popup::ConfirmRule { host, kind, scope })
+                }
+                _ => Some(Popup::ConfirmRule { host, kind, scope }),
+            },
+            Popup::Search { mut input, status } => match key.code {
+                KeyCode::Esc => None,
+                KeyCode::Enter => {
+                    self.apply_query_filter(QueryFilter { search: input.trim().to_string(), response_status: status });
+                    None
+                }
+                KeyCode::Tab | KeyCode::Down => Some(Popup::Search { input, status: status.cycle(1) }),
+                KeyCode::BackTab | KeyCode::Up => Some(Popup::Search { input, status: status.cycle(-1) }),
+                _ => {
+                    edit_text(&mut input, key);
+                    Some(Popup::Search { input, status })
+                }
+            },
+            Popup::FilterEffectiveness { scroll } => match key.code {
+                KeyCode::Esc | KeyCode::Char('e') | KeyCode::Char('q') => None,
+                KeyCode::Up | KeyCode::Char('k') => Some(Popup::FilterEffectiveness {
+                    scroll: scroll.min(self.popup_scroll_limit).saturating_sub(1),
+                }),
+                KeyCode::Down | KeyCode::Char('j') => Some(Popup::FilterEffectiveness {
+                    scroll: scroll.saturating_add(1).min(self.popup_scroll_limit),
+                }),
+                _ => Some(Popup::FilterEffectiveness { scroll }),
+            },
+            Popup::AddFilter(mut form) => {
+                match key.code {
+                    KeyCode::Esc => return None,
+                    KeyCode::Tab | KeyCode::Down => form.field = form.field.next(),
+                    KeyCode::BackTab | KeyCode::Up => form.field = form.field.previous(),
+                    // Enter moves on to the next field, until there's a URL to add
+                    KeyCode::Enter if form.url.trim().is_empty() => form.field = form.field.next(),
+                    KeyCode::Enter => {
+                        self.add_filter(form);
+                        return None;
+                    }
+                    KeyCode::Left | KeyCode::Right | KeyCode::Char(' ') if form.field == FilterField::Kind => {
+                        form.allowlist = !form.allowlist;
+                    }
+                    _ => match form.field {
+                        FilterField::Name => edit_text(&mut form.name, key),
+                        FilterField::Url => edit_text(&mut form.url, key),
+                        FilterField::Kind => {}
+                    },
+                }
+                Some(Popup::AddFilter(form))
+            }
+            Popup::UserRules(mut editor) => match key.code {
+                KeyCode::Esc => None,
+                // Rules are saved even if some look wrong, as AdGuard may understand syntax that isn't checked for
+                KeyCode::Char('s') if key.modifiers == KeyModifiers::CONTROL => {
+                    self.save_user_rules(editor.rules());
+                    None
+                }
+                _ => {
+                    editor.handle_key(key);
+                    Some(Popup::UserRules(editor))
+                }
+            },
+            Popup::ConfirmRemoveFilter { filter } => match key.code {
+                KeyCode::Enter | KeyCode::Char('y') => {
+                    self.remove_filter(filter);
+                    None
+                }
+                KeyCode::Esc | KeyCode::Char('n') | KeyCode::Char('q') => None,
+                _ => Some(Popup::ConfirmRemoveFilter { filter }),
+            },
+        }
+    }
+
+    /// Searches the query log. The fetchers drop what they have, and fetch matching entries straight away
+    fn apply_query_filter(&mut self, filter: QueryFilter) {
+        if *self.query_filter.borrow() == filter {
+            return;
+        }
+        self.query_filter.send_replace(filter);
+        self.selected_query = None;
+        self.query_table.select(None);
+        for instance in &self.instances {
+            instance.refresh.notify_one();
+        }
+    }
+
+    /// Adds a rule to AdGuard's custom rules, to block or allow a domain
+    fn add_rule(&self, domain: String, kind: RuleKind) {
+        let rule = make_rule(&domain, kind);
+        self.run_action(move |client| {
+            let rule = rule.clone();
+            async move {
+                match client.add_user_rule(&rule).await? {
+                    true => Ok(format!("Added rule {}", rule)),
+                    false => Ok(format!("Rule {} already exists", rule)),
+                }
+            }
+        });
+    }
+
+    /// Enables or disables a filter list
+    fn set_filter_enabled(&self, filter: Filter, enabled: bool) {
+        self.run_action(move |client| {
+            let filter = filter.clone();
+            async move {
+                client.set_filter_enabled(&filter, enabled).await?;
+                let state = if enabled { "Enabled" } else { "Disabled" };
+                Ok(format!("{} filter list {}", state, filter.name))
+            }
+        });
+    }
+
+    /// Has AdGuard download the latest version of every filter list
+    fn refresh_filters(&self) {
+        self.show_pending("Refreshing filter lists...");
+        self.run_action(|client| async move {
+            let updated = client.refresh_filters().await?;
+            Ok(format!("Refreshed filter lists, {} updated", updated))
+        });
+    }
+
+    /// Adds the filter list from the form. If it wasn't given a name, it's named after its URL
+    fn add_filter(&self, form: FilterForm) {
+        let url = form.url.trim().to_string();
+        let name = match form.name.trim() {
+            "" => url.clone(),
+            name => name.to_string(),
+        };
+        let kind = if form.allowlist { "allowlist" } else { "filter list" };
+        self.show_pending(&format!("Adding {}...", kind));
+        self.run_action(move |client| {
+            let (name, url) = (name.clone(), url.clone());
+            async move {
+                client.add_filter(&name, &url, form.allowlist).await?;
+                Ok(format!("Added {} {}", kind, name))
+            }
+        });
+    }
+
+    fn remove_filter(&self, filter: Filter) {
+        self.run_action(move |client| {
+            let filter = filter.clone();
+            async move {
+                client.remove_filter(&filter).await?;
+                Ok(format!("Removed filter list {}", filter.name))
+            }
+        });
+    }
+
+    /// Replaces the custom user rules
+    fn save_user_rules(&self, rules: Vec<String>) {
+        self.run_action(move |client| {
+            let rules = rules.clone();
+            async move {
+                client.set_user_rules(&rules).await?;
+                let count = rules.iter().filter(|rule| !rule.trim().is_empty()).count();
+                Ok(format!("Saved {} custom rules", count))
+            }
+        });
+    }
+
+    /// Shows a message straight away, for actions which may take a while (e.g. downloading lists)
+    fn show_pending(&self, message: &str) {
+        let _ = self.flash_tx.send(Flash::new(message.to_string(), false));
+    }
+
+    /// Turns protection off indefinitely if it's on, or back on if it's off
+    fn toggle_protection(&self, currently_enabled: bool) {
+        let message = if currently_enabled { "Protection disabled" } else { "Protection enabled" };
+        self.run_action(move |client| async move {
+            client.set_protection(!currently_enabled, None).await?;
+            Ok(message.to_string())
+        });
+    }
+
+    /// Turns protection off, for the duration at the given index of `PAUSE_OPTIONS`
+    fn pause_protection(&self, option: usize) {
+        let (label, duration) = PAUSE_OPTIONS[option];
+        let duration = duration.unwrap_or_else(until_midnight);
+        self.run_action(move |client| async move {
+            client.set_protection(false, Some(duration)).await?;
+            Ok(format!("Protection paused for {}", label.to_lowercase()))
+        });
+    }
+
+    /// Runs an action in the background against each instance in view (one after another),
+    /// then shows its outcome, and refreshes their data. On success, the action returns the message to show.
+    /// If it fails for any instance, the rest are skipped
+    fn run_action<F, Fut>(&self, action: F)
+    where
+        F: Fn(AdGuardClient) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<String, AdGuardError>> + Send,
+    {
+        let flash_tx = self.flash_tx.clone();
+        let targets = self.targets();
+        let multiple = self.instances.len() > 1;
+        tokio::spawn(async move {
+            let mut messages = Vec::new();
+            let mut failure = None;
+            for instance in &targets {
+                match action(instance.client.clone()).await {
+                    Ok(message) => messages.push(message),
+                    Err(e) if multiple => failure = Some(format!("Failed on {}: {}", instance.name, e)),
+                    Err(e) => failure = Some(format!("Failed: {}", e)),
+                }
+                instance.refresh.notify_one();
+                if failure.is_some() {
+                    break;
+                }
+            }
+            let flash = match (failure, messages.first()) {
+                (Some(failure), _) => Flash::new(failure, true),
+                (None, Some(message)) if targets.len() > 1 => {
+                    Flash::new(format!("{} (on {} instances)", message, targets.len()), false)
+                }
+                (None, message) => Flash::new(message.cloned().unwrap_or_default(), false),
+            };
+            let _ = flash_tx.send(flash);
+        });
+    }
+}
+
+/// Applies a key press to a single-line text input
+fn edit_text(input: &mut String, key: &KeyEvent) {
+    match key.code {
+        KeyCode::Char('u') if key.modifiers == KeyModifiers::CONTROL => input.clear(),
+        KeyCode::Backspace => {
+            input.pop();
+        }
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => input.push(c),
+        _ => {}
+    }
+}
+
+/// Time remaining until midnight, local time
+fn until_midnight() -> Duration {
+    let now = Local::now();
+    now.date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+        .and_then(|midnight| (midnight - now).to_std().ok())
+        .unwrap_or(Duration::from_secs(24 * 60 * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use serde_json::json;
+    use tokio::sync::Notify;
+
+    fn app(instances: usize, view: View) -> App {
+        let instances = (0..instances)
+            .map(|i| Instance {
+                name: format!("adguard{}", i),
+                client: AdGuardClient::new("http://127.0.0.1:9", "", "").unwrap(),
+                refresh: Arc::new(Notify::new()),
+            })
+            .collect();
+        let (flash_tx, _) = mpsc::unbounded_channel();
+        let mut app = App::new(
+            instances, watch::channel(view).0, watch::channel(QueryFilter::default()).0, flash_tx,
+            Theme::default(), LayoutOptions::default(),
+        );
+        app.focus = Focus::Filters;
+        app.filters_list.select(Some(0));
+        app
+    }
+
+    fn press(app: &mut App, code: KeyCode, filters: &AdGuardFilteringStatus) {
+        let data = Snapshot { queries: &[], stats: None, status: None, filters: Some(filters) };
+        app.handle_key(&KeyEvent::new(code, KeyModifiers::NONE), &data);
+    }
+
+    #[test]
+    fn lists_and_rules_are_only_changed_for_a_single_instance() {
+        let filters: AdGuardFilteringStatus = serde_json::from_value(json!({
+            "filters": [{ "id": 1, "name": "List", "url": "https://a/list.txt", "rules_count": 1, "enabled": true }],
+        })).unwrap();
+
+        let mut combined = app(2, View::All);
+        for code in [KeyCode::Char('d'), KeyCode::Char('u')] {
+            press(&mut combined, code, &filters);
+            assert!(combined.popup.is_none());
+            assert!(combined.flash().is_some_and(|flash| flash.is_error));
+        }
+
+        let mut single = app(2, View::Single(1));
+        press(&mut single, KeyCode::Char('d'), &filters);
+        assert!(matches!(single.popup, Some(Popup::ConfirmRemoveFilter { .. })));
+        assert!(single.flash().is_none());
+    }
+}
@@ -0,0 +1,297 @@
+//! Command line arguments and subcommands
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+/// Terminal-based, real-time traffic monitoring and statistics for your AdGuard Home instance
+#[derive(Parser)]
+#[command(name = "adguardian", version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    /// Instead of showing the dashboard, run as a Prometheus exporter, serving /metrics
+    /// on this address (e.g. :9617 or 127.0.0.1:9617)
+    #[arg(long, value_name = "ADDR", value_parser = parse_listen_addr)]
+    pub serve_metrics: Option<SocketAddr>,
+
+    /// What to do. If omitted, the live dashboard is shown
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options for connecting to AdGuard, which apply to every subcommand.
+/// Each of these can also be set with an environment variable, or in the config file
+#[derive(Args)]
+pub struct GlobalArgs {
+    /// Path to the config file [default: $XDG_CONFIG_HOME/adguardian/config.toml, or ~/.config/adguardian/config.toml]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Name of the instance profile to use, from the config file
+    #[arg(long, global = true, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Names of several instance profiles to show together on the dashboard, separated by commas
+    #[arg(long, global = true, value_name = "NAMES", value_delimiter = ',', conflicts_with = "profile")]
+    pub profiles: Vec<String>,
+
+    /// The IP address of your AdGuard Home instance [env: ADGUARD_IP]
+    #[arg(long = "adguard-ip", global = true, value_name = "IP")]
+    pub ip: Option<String>,
+
+    /// The port that AdGuard is running on [env: ADGUARD_PORT]
+    #[arg(long = "adguard-port", global = true, value_name = "PORT")]
+    pub port: Option<String>,
+
+    /// An AdGuard Home username [env: ADGUARD_USERNAME]
+    #[arg(long = "adguard-username", global = true, value_name = "USERNAME")]
+    pub username: Option<String>,
+
+    /// An AdGuard Home password [env: ADGUARD_PASSWORD]
+    #[arg(long = "adguard-password", global = true, value_name = "PASSWORD")]
+    pub password: Option<String>,
+
+    /// Path to a PEM file of extra CA certificates to trust, e.g. for an internal CA [env: ADGUARD_CA_CERT]
+    #[arg(long = "adguard-ca-cert", global = true, value_name = "PATH")]
+    pub ca_cert: Option<String>,
+
+    /// Path to a PEM client certificate, for mutual TLS [env: ADGUARD_CLIENT_CERT]
+    #[arg(long = "adguard-client-cert", global = true, value_name = "PATH", requires = "client_key")]
+    pub client_cert: Option<String>,
+
+    /// Path to the PEM key for the client certificate [env: ADGUARD_CLIENT_KEY]
+    #[arg(long = "adguard-client-key", global = true, value_name = "PATH", requires = "client_cert")]
+    pub client_key: Option<String>,
+
+    /// Don't verify AdGuard's TLS certificate. DANGEROUS: anyone on the network could intercept
+    /// the connection, including your credentials [env: ADGUARD_INSECURE_SKIP_VERIFY]
+    #[arg(long, global = true)]
+    pub insecure_skip_verify: bool,
+}
+
+impl GlobalArgs {
+    /// Values of the flags that were passed, keyed by their equivalent environment variable
+    pub fn flag_values(&self) -> Vec<(&'static str, String)> {
+        [
+            ("ADGUARD_IP", &self.ip),
+            ("ADGUARD_PORT", &self.port),
+            ("ADGUARD_USERNAME", &self.username),
+            ("ADGUARD_PASSWORD", &self.password),
+            ("ADGUARD_CA_CERT", &self.ca_cert),
+            ("ADGUARD_CLIENT_CERT", &self.client_cert),
+            ("ADGUARD_CLIENT_KEY", &self.client_key),
+            ("ADGUARD_INSECURE_SKIP_VERIFY", &self.insecure_skip_verify.then(|| "true".to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.clone().map(|value| (key, value)))
+        .collect()
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show the live dashboard (the default)
+    Dash,
+    /// Print the current status of AdGuard
+    Status(OutputArgs),
+    /// Print query counts, and the top domains and clients
+    Stats(OutputArgs),
+    /// Print the configured filter lists and custom rules
+    Filters(OutputArgs),
+    /// Print the most recent page of the query log (as NDJSON, with --json)
+    Log(OutputArgs),
+    /// Continuously print new entries from the query log, like tcpdump for DNS
+    Tail(TailArgs),
+    /// Block a domain, by adding ||domain^ to the custom rules
+    Block(RuleArgs),
+    /// Allow a domain, by adding @@||domain^ to the custom rules
+    Allow(RuleArgs),
+    /// Copy filter lists, custom rules, rewrites, blocked services and clients from one instance to others,
+    /// so they end up the same. Instances are named by their profiles in the config file
+    Sync(SyncArgs),
+    /// Turn protection on or off, optionally for a set time
+    Protection {
+        state: ProtectionState,
+        /// Only turn protection off for this long, e.g. 30s, 10m, 1h30m
+        #[arg(long = "for", value_name = "DURATION", value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+}
+
+#[derive(Args)]
+pub struct OutputArgs {
+    /// Print machine-readable JSON, instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args)]
+pub struct TailArgs {
+    /// How to print each query [default: color if printing to a terminal, otherwise plain]
+    #[arg(long, value_enum)]
+    pub format: Option<TailFormat>,
+    /// Only show queries from this client (IP address or name)
+    #[arg(long)]
+    pub client: Option<String>,
+    /// Only show queries which were blocked
+    #[arg(long)]
+    pub blocked_only: bool,
+    /// Only show queries of this record type, e.g. AAAA
+    #[arg(long = "type", value_name = "TYPE")]
+    pub query_type: Option<String>,
+    /// Only show queries for domains matching this pattern, where * matches anything, e.g. '*.tiktok.com'
+    #[arg(long, value_name = "PATTERN")]
+    pub domain: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TailFormat {
+    /// One line per query, coloured by whether it was allowed or blocked
+    Color,
+    /// The same, without colours
+    Plain,
+    /// One JSON object per line (NDJSON)
+    Json,
+}
+
+#[derive(Args)]
+pub struct RuleArgs {
+    /// The domain to add a rule for
+    pub domain: String,
+    /// Apply the rule to the registrable parent domain (e.g. example.com for ads.example.com)
+    #[arg(long)]
+    pub parent: bool,
+}
+
+#[derive(Args)]
+pub struct SyncArgs {
+    /// Profile of the instance to copy settings from
+    #[arg(long, value_name = "PROFILE")]
+    pub from: String,
+    /// Profiles of the instances to copy settings to, separated by commas
+    #[arg(long, value_name = "PROFILES", value_delimiter = ',', required = true)]
+    pub to: Vec<String>,
+    /// Only show what would change, without changing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProtectionState {
+    On,
+    Off,
+}
+
+/// Parses an address to listen on, where the host can be left out (e.g. :9617) to listen on all interfaces
+fn parse_listen_addr(input: &str) -> Result<SocketAddr, String> {
+    match input.strip_prefix(':') {
+        Some(port) => port.parse()
+            .map(|port| SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+            .map_err(|_| format!("Invalid port '{}'", port)),
+        None => input.parse()
+            .map_err(|_| format!("Invalid address '{}', expected something like :9617 or 127.0.0.1:9617", input)),
+    }
+}
+
+/// Parses a duration made of one or more number and unit pairs, e.g. 90s, 10m or 1h30m
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}', expected something like 30s, 10m or 1h30m", input);
+    let too_long = || format!("Duration '{}' is too long", input);
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in input.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total = value.checked_mul(multiplier)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(too_long)?;
+        number.clear();
+    }
+    // A trailing number without a unit is taken as seconds
+    if !number.is_empty() {
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total = total.checked_add(value).ok_or_else(too_long)?;
+    }
+    if total == 0 {
+        return Err(invalid());
+    }
+    // AdGuard takes the duration in milliseconds, so it has to fit in those too
+    if total.checked_mul(1000).is_none() {
+        return Err(too_long());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_duration_with_a_single_unit() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+    }
+
+    #[test]
+    fn parses_a_duration_with_several_units() {
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration(" 1d2h3m4s "), Ok(Duration::from_secs(93784)));
+    }
+
+    #[test]
+    fn takes_a_number_without_a_unit_as_seconds() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1m30"), Ok(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for input in ["", "0s", "h", "10x", "1.5h", "-5m", "m10"] {
+            assert!(parse_duration(input).is_err(), "{} should be rejected", input);
+        }
+    }
+
+    #[test]
+    fn rejects_durations_which_overflow() {
+        assert_eq!(parse_duration("99999999999999999h"), Err("Duration '99999999999999999h' is too long".to_string()));
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+        assert!(parse_duration("99999999999999999999999s").is_err());
+        // Fits in seconds, but not in the milliseconds sent to AdGuard
+        assert!(parse_duration("18446744073709552s").is_err());
+    }
+
+    #[test]
+    fn parses_a_listen_address_without_a_host() {
+        assert_eq!(parse_listen_addr(":9617"), Ok(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 9617))));
+    }
+
+    #[test]
+    fn parses_a_listen_address_with_a_host() {
+        assert_eq!(parse_listen_addr("127.0.0.1:9617"), Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 9617))));
+        assert_eq!(parse_listen_addr("[::1]:9617"), Ok("[::1]:9617".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_listen_addresses() {
+        for input in [":", ":http", ":70000", "9617", "localhost:9617", "127.0.0.1"] {
+            assert!(parse_listen_addr(input).is_err(), "{} should be rejected", input);
+        }
+    }
+}
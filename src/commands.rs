@@ -0,0 +1,408 @@
+//! One-shot subcommands, for use from scripts and the shell, rather than the dashboard.
+//! These share the same client and fetch layer as the dashboard does
+
+use std::{
+    io::{self, IsTerminal, Write},
+    time::Duration,
+};
+use anyhow::{bail, Context};
+use colored::*;
+use serde::Serialize;
+use tokio::time::sleep;
+
+use crate::cli::{Command, OutputArgs, ProtectionState, RuleArgs, TailArgs, TailFormat};
+use crate::config::Settings;
+use crate::connection::Backoff;
+use crate::fetch::{
+    client::AdGuardClient,
+    fetch_filters::Filter,
+    fetch_query_log::{Query, QueryCursor, QueryFilter},
+    fetch_stats::DomainData,
+};
+use crate::query_log::QueryLog;
+use crate::rules::{make_rule, rule_target, RuleKind, RuleScope};
+use crate::widgets::table::{block_status_text, make_row_color};
+
+/// Runs a subcommand (other than the dashboard) against the configured instance
+pub async fn run_command(command: Command, settings: &Settings) -> anyhow::Result<()> {
+    let client = settings.client()?;
+    let result = match command {
+        Command::Dash => unreachable!("the dashboard is started from main"),
+        Command::Sync(_) => unreachable!("sync is run from main, as it uses several profiles"),
+        Command::Status(output) => status(&client, &output).await,
+        Command::Stats(output) => stats(&client, &output).await,
+        Command::Filters(output) => filters(&client, &output).await,
+        Command::Log(output) => log(&client, &output).await,
+        Command::Tail(args) => tail(&client, &args, settings).await,
+        Command::Block(args) => add_rule(&client, &args, RuleKind::Block).await,
+        Command::Allow(args) => add_rule(&client, &args, RuleKind::Allow).await,
+        Command::Protection { state, duration } => protection(&client, state, duration).await,
+    };
+    // Whatever was reading the output has stopped, so there's no one left to tell
+    match result {
+        Err(e) if is_broken_pipe(&e) => Ok(()),
+        result => result,
+    }
+}
+
+/// Prints a line to stdout, returning an error rather than panicking if it can't be written,
+/// e.g. once whatever it's piped into (like `head`) has exited
+macro_rules! out {
+    ($($arg:tt)*) => {
+        writeln!(io::stdout().lock(), $($arg)*)
+    };
+}
+
+/// Whether an error is from writing to a pipe that's been closed
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe))
+}
+
+/// Prints a label, padded so that values line up
+fn print_field(label: &str, value: impl std::fmt::Display) -> io::Result<()> {
+    out!("{:<22}{}", format!("{}:", label).bold(), value)
+}
+
+/// Prints a value as a single JSON document, with the same field names as AdGuard's API
+fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    out!("{}", serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+fn on_off(enabled: bool) -> ColoredString {
+    if enabled { "on".green() } else { "off".red() }
+}
+
+async fn status(client: &AdGuardClient, output: &OutputArgs) -> anyhow::Result<()> {
+    let status = client.fetch_status().await?;
+    if output.json {
+        return print_json(&status);
+    }
+    print_field("Version", &status.version)?;
+    print_field("Running", on_off(status.running))?;
+    let resumes = match status.protection_disabled_duration {
+        0 => String::new(),
+        ms => format!(" (resumes in {}s)", ms / 1000),
+    };
+    print_field("Protection", format!("{}{}", on_off(status.protection_enabled), resumes))?;
+    print_field("DNS Port", status.dns_port)?;
+    print_field("HTTP Port", status.http_port)?;
+    print_field("DHCP Available", on_off(status.dhcp_available))?;
+    Ok(())
+}
+
+/// Prints a ranked list of domains or clients, with their counts
+fn print_top(title: &str, items: &[DomainData]) -> io::Result<()> {
+    out!("\n{}", title.bold())?;
+    if items.is_empty() {
+        out!("  {}", "(none)".dimmed())?;
+    }
+    for (rank, item) in items.iter().enumerate() {
+        out!("  {:>2}. {:<50} {}", rank + 1, item.name, item.count.to_string().blue())?;
+    }
+    Ok(())
+}
+
+async fn stats(client: &AdGuardClient, output: &OutputArgs) -> anyhow::Result<()> {
+    let stats = client.fetch_stats().await?;
+    if output.json {
+        return print_json(&stats);
+    }
+    let blocked_percent = match stats.num_dns_queries {
+        0 => 0.0,
+        total => stats.num_blocked_filtering as f64 / total as f64 * 100.0,
+    };
+    print_field("DNS Queries", stats.num_dns_queries)?;
+    print_field("Blocked", format!("{} ({:.2}%)", stats.num_blocked_filtering, blocked_percent))?;
+    print_field("Safe Browsing", stats.num_replaced_safebrowsing)?;
+    print_field("Safe Search", stats.num_replaced_safesearch)?;
+    print_field("Parental Control", stats.num_replaced_parental)?;
+    print_field("Avg Processing Time", format!("{:.2} ms", stats.avg_processing_time * 1000.0))?;
+    print_top("Top Queried Domains", &stats.top_queried_domains)?;
+    print_top("Top Blocked Domains", &stats.top_blocked_domains)?;
+    print_top("Top Clients", &stats.top_clients)?;
+    Ok(())
+}
+
+fn print_filter_lists(title: &str, filters: &[Filter]) -> io::Result<()> {
+    out!("\n{}", title.bold())?;
+    if filters.is_empty() {
+        out!("  {}", "(none)".dimmed())?;
+    }
+    for filter in filters {
+        out!("  {:<4} {:<50} {:>9} rules  {}", on_off(filter.enabled), filter.name, filter.rules_count, filter.url.dimmed())?;
+    }
+    Ok(())
+}
+
+async fn filters(client: &AdGuardClient, output: &OutputArgs) -> anyhow::Result<()> {
+    let filtering = client.fetch_filters().await?;
+    if output.json {
+        return print_json(&filtering);
+    }
+    print_field("Filtering", on_off(filtering.enabled))?;
+    print_field("Update Interval", match filtering.interval {
+        0 => "never".to_string(),
+        hours => format!("{}h", hours),
+    })?;
+    print_filter_lists("Blocklists", filtering.filters.as_deref().unwrap_or_default())?;
+    print_filter_lists("Allowlists", filtering.whitelist_filters.as_deref().unwrap_or_default())?;
+    out!("\n{}", "Custom Rules".bold())?;
+    let rules: Vec<_> = filtering.user_rules.iter().filter(|rule| !rule.trim().is_empty()).collect();
+    if rules.is_empty() {
+        out!("  {}", "(none)".dimmed())?;
+    }
+    for rule in rules {
+        out!("  {}", rule)?;
+    }
+    Ok(())
+}
+
+/// Prints the latest page of the query log, oldest first.
+/// As JSON, each entry is printed on its own line (NDJSON), so it can be streamed into other tools
+async fn log(client: &AdGuardClient, output: &OutputArgs) -> anyhow::Result<()> {
+    let queries = client.fetch_new_queries(&QueryFilter::default(), &QueryCursor::default()).await?;
+    for query in queries.iter().rev() {
+        if output.json {
+            out!("{}", serde_json::to_string(query)?)?;
+        } else {
+            out!("{}", format_query(query))?;
+        }
+    }
+    Ok(())
+}
+
+/// Converts one of the dashboard's colours to the nearest terminal colour
+fn term_color(color: tui::style::Color) -> Color {
+    match color {
+        tui::style::Color::Red => Color::Red,
+        tui::style::Color::Green => Color::Green,
+        tui::style::Color::Yellow => Color::Yellow,
+        tui::style::Color::Blue => Color::Blue,
+        tui::style::Color::Magenta => Color::Magenta,
+        tui::style::Color::Cyan => Color::Cyan,
+        tui::style::Color::LightRed => Color::BrightRed,
+        tui::style::Color::LightGreen => Color::BrightGreen,
+        tui::style::Color::LightYellow => Color::BrightYellow,
+        tui::style::Color::LightBlue => Color::BrightBlue,
+        tui::style::Color::LightMagenta => Color::BrightMagenta,
+        tui::style::Color::LightCyan => Color::BrightCyan,
+        tui::style::Color::DarkGray => Color::BrightBlack,
+        _ => Color::White,
+    }
+}
+
+/// Formats a single query log entry as one line, coloured the same as in the dashboard's query log
+fn format_query(query: &Query) -> String {
+    let (status, status_color) = block_status_text(query);
+    format!(
+        "{} {:<15} {:<6} {} {}",
+        query.time.dimmed(),
+        status.color(term_color(status_color)),
+        query.question.question_type.magenta(),
+        query.question.name.color(term_color(make_row_color(&query.reason))).bold(),
+        query.client.blue(),
+    )
+}
+
+/// Whether `text` matches a pattern, where `*` matches any number of characters (ignoring case)
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.trim_end_matches('.').to_lowercase();
+    let mut parts = pattern.split('*');
+    // The first part must be at the start, the last at the end, and the rest in order between
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Whether a query passes all of the filters given to `tail`
+fn tail_matches(args: &TailArgs, query: &Query) -> bool {
+    let client_matches = |client: &str| {
+        query.client == client
+            || query.client_info.as_ref().is_some_and(|info| info.name.eq_ignore_ascii_case(client))
+    };
+    args.client.as_deref().is_none_or(client_matches)
+        && (!args.blocked_only || query.is_blocked())
+        && args.query_type.as_deref().is_none_or(|t| query.question.question_type.eq_ignore_ascii_case(t))
+        && args.domain.as_deref().is_none_or(|pattern| glob_match(pattern, &query.question.name))
+}
+
+/// Polls the query log, printing new entries (oldest first) which match the filters, until interrupted.
+/// If AdGuard can't be reached, the error is printed, and it backs off before retrying
+async fn tail(client: &AdGuardClient, args: &TailArgs, settings: &Settings) -> anyhow::Result<()> {
+    let format = args.format.unwrap_or(if io::stdout().is_terminal() { TailFormat::Color } else { TailFormat::Plain });
+    colored::control::set_override(format == TailFormat::Color);
+
+    // Each fetch only gets entries newer than those already in the log, so none are printed twice
+    let mut log = QueryLog::new(settings.query_log_size);
+    let mut backoff = Backoff::default();
+    loop {
+        let added = match log.fetch(client).await {
+            Ok(added) => {
+                backoff.reset();
+                added
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                eprintln!("{} {}", format!("Failed to fetch the query log, retrying in {}s:", delay.as_secs()).red(), e);
+                sleep(delay).await;
+                continue;
+            }
+        };
+        for query in log.as_slice()[..added].iter().rev() {
+            if query.gap_before {
+                eprintln!("{}", "Some queries were missed here, as there were too many to catch up on".yellow());
+            }
+            if !tail_matches(args, query) {
+                continue;
+            }
+            match format {
+                TailFormat::Json => out!("{}", serde_json::to_string(query)?)?,
+                TailFormat::Color | TailFormat::Plain => out!("{}", format_query(query))?,
+            }
+        }
+        sleep(settings.update_interval).await;
+    }
+}
+
+async fn add_rule(client: &AdGuardClient, args: &RuleArgs, kind: RuleKind) -> anyhow::Result<()> {
+    let scope = if args.parent { RuleScope::ParentDomain } else { RuleScope::ExactHost };
+    let domain = rule_target(&args.domain, scope);
+    if domain.is_empty() || domain.contains(char::is_whitespace) {
+        bail!("Invalid domain '{}'", args.domain);
+    }
+    let rule = make_rule(&domain, kind);
+    let added = client.add_user_rule(&rule).await
+        .with_context(|| format!("Unable to add rule {}", rule))?;
+    if added {
+        out!("{} {}", "Added rule".green(), rule.bold())?;
+    } else {
+        out!("{} {}", "Rule already exists:".yellow(), rule.bold())?;
+    }
+    Ok(())
+}
+
+async fn protection(client: &AdGuardClient, state: ProtectionState, duration: Option<Duration>) -> anyhow::Result<()> {
+    match (state, duration) {
+        (ProtectionState::On, Some(_)) => bail!("--for can only be used when turning protection off"),
+        (ProtectionState::On, None) => {
+            client.set_protection(true, None).await?;
+            out!("{}", "Protection turned on".green())?;
+        }
+        (ProtectionState::Off, duration) => {
+            client.set_protection(false, duration).await?;
+            match duration {
+                Some(duration) => out!("{} {}s", "Protection turned off for".yellow(), duration.as_secs())?,
+                None => out!("{}", "Protection turned off".yellow())?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn query(name: &str, query_type: &str, client: &str, client_name: &str, reason: &str) -> Query {
+        serde_json::from_value(json!({
+            "cached": false,
+            "client": client,
+            "client_info": { "name": client_name },
+            "upstream": "",
+            "elapsedMs": "1.0",
+            "question": { "class": "IN", "name": name, "type": query_type },
+            "reason": reason,
+            "time": "2024-05-01T12:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    fn args() -> TailArgs {
+        TailArgs { format: None, client: None, blocked_only: false, query_type: None, domain: None }
+    }
+
+    #[test]
+    fn glob_matches_exact_text_without_wildcards() {
+        assert!(glob_match("example.com", "example.com"));
+        assert!(!glob_match("example.com", "ads.example.com"));
+        assert!(!glob_match("example.com", "example.co"));
+    }
+
+    #[test]
+    fn glob_matches_wildcards_anywhere() {
+        assert!(glob_match("*.tiktok.com", "v16.tiktok.com"));
+        assert!(!glob_match("*.tiktok.com", "tiktok.com"));
+        assert!(glob_match("ads.*", "ads.example.org"));
+        assert!(glob_match("*track*", "www.tracker.net"));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(glob_match("*", "anything.at.all"));
+    }
+
+    #[test]
+    fn glob_parts_dont_overlap() {
+        assert!(!glob_match("ab*bc", "abc"));
+        assert!(!glob_match("a*a", "a"));
+        assert!(glob_match("a*a", "aa"));
+    }
+
+    #[test]
+    fn glob_ignores_case_and_trailing_dot() {
+        assert!(glob_match("*.Example.COM", "www.example.com."));
+    }
+
+    #[test]
+    fn tail_matches_everything_without_filters() {
+        assert!(tail_matches(&args(), &query("example.com", "A", "192.168.1.2", "", "NotFilteredNotFound")));
+    }
+
+    #[test]
+    fn tail_matches_client_by_address_or_name() {
+        let query = query("example.com", "A", "192.168.1.2", "Laptop", "NotFilteredNotFound");
+        let by_address = TailArgs { client: Some("192.168.1.2".to_string()), ..args() };
+        let by_name = TailArgs { client: Some("laptop".to_string()), ..args() };
+        let other = TailArgs { client: Some("192.168.1.3".to_string()), ..args() };
+        assert!(tail_matches(&by_address, &query));
+        assert!(tail_matches(&by_name, &query));
+        assert!(!tail_matches(&other, &query));
+    }
+
+    #[test]
+    fn tail_matches_only_blocked_queries() {
+        let blocked_only = TailArgs { blocked_only: true, ..args() };
+        assert!(tail_matches(&blocked_only, &query("ads.com", "A", "1.1.1.1", "", "FilteredBlackList")));
+        assert!(tail_matches(&blocked_only, &query("ads.com", "A", "1.1.1.1", "", "FilteredBlockedService")));
+        assert!(!tail_matches(&blocked_only, &query("ok.com", "A", "1.1.1.1", "", "NotFilteredNotFound")));
+        assert!(!tail_matches(&blocked_only, &query("ok.com", "A", "1.1.1.1", "", "NotFilteredWhiteList")));
+    }
+
+    #[test]
+    fn tail_matches_type_and_domain_together() {
+        let filtered = TailArgs { query_type: Some("aaaa".to_string()), domain: Some("*.tiktok.com".to_string()), ..args() };
+        assert!(tail_matches(&filtered, &query("v16.tiktok.com", "AAAA", "1.1.1.1", "", "NotFilteredNotFound")));
+        assert!(!tail_matches(&filtered, &query("v16.tiktok.com", "A", "1.1.1.1", "", "NotFilteredNotFound")));
+        assert!(!tail_matches(&filtered, &query("example.com", "AAAA", "1.1.1.1", "", "NotFilteredNotFound")));
+    }
+
+    #[test]
+    fn broken_pipes_are_recognised_through_context() {
+        let closed = anyhow::Error::from(io::Error::from(io::ErrorKind::BrokenPipe)).context("Unable to print");
+        let other = anyhow::Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(is_broken_pipe(&closed));
+        assert!(!is_broken_pipe(&other));
+    }
+}
@@ -0,0 +1,705 @@
+//! Loading of the optional TOML config file, which holds one or more named AdGuard instances
+//! (profiles), and resolving the final settings from it, environment variables and flags.
+//!
+//! Each setting is taken from the first of these places where it's set:
+//! 1. Command line flags (e.g. `--adguard-ip`)
+//! 2. Environment variables (e.g. `ADGUARD_IP`)
+//! 3. The selected profile in the config file
+//! 4. The default value, or if there isn't one, the user is prompted to enter it
+//!
+//! The config file is read from `--config <path>` if given, otherwise from
+//! `$XDG_CONFIG_HOME/adguardian/config.toml` (if it exists), where `$XDG_CONFIG_HOME` defaults to `~/.config`.
+//! On Windows, it's read from `%APPDATA%\adguardian\config.toml` instead.
+//! The profile is chosen with `--profile <name>`, otherwise `default_profile` in the file,
+//! otherwise whichever profile there is, if there's only one.
+//!
+//! The dashboard can instead show several profiles at once, listed with `--profiles <names>`
+//! or `dashboard` in the file. Each of those is set entirely from its profile (and defaults),
+//! as flags and environment variables would otherwise apply to all of them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use anyhow::{anyhow, bail, Context};
+use colored::*;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Certificate, Identity, Url,
+};
+use serde::Deserialize;
+
+use crate::cli::GlobalArgs;
+use crate::fetch::client::{AdGuardClient, AdGuardError, AuthMethod, TlsOptions};
+use crate::query_log;
+
+/// How often to fetch new data, if not otherwise specified
+const DEFAULT_UPDATE_INTERVAL: u64 = 2;
+
+/// How many days of history to keep in the database, if not otherwise specified
+const DEFAULT_HISTORY_RETENTION_DAYS: u64 = 90;
+
+/// Contents of the config file
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Profile to use when none is specified with `--profile`
+    pub default_profile: Option<String>,
+    /// Profiles to show together on the dashboard, when none are specified with `--profile` or `--profiles`
+    #[serde(default)]
+    pub dashboard: Vec<String>,
+    /// Named AdGuard instances, e.g. `[instances.home]`
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceConfig>,
+}
+
+/// A single named AdGuard instance, from the config file. Every field is optional,
+/// as it can instead come from environment variables or flags
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    /// Full address of the instance, e.g. http://192.168.1.2:3000
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// How to authenticate: `basic` (the default), `session` to log in and use a cookie,
+    /// `bearer` to send `token`, or `none` (e.g. when a proxy in front of AdGuard handles auth)
+    pub auth: Option<AuthMethod>,
+    /// Token to send with `bearer` auth
+    pub token: Option<String>,
+    /// Extra headers to send with every request, e.g. `{ "X-Api-Key" = "abc" }`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Path to a PEM file of extra CA certificates to trust, e.g. for an internal CA
+    pub ca_cert: Option<PathBuf>,
+    /// Paths to a PEM client certificate and its key, for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// Don't verify AdGuard's certificate at all. Dangerous, as the connection can then be intercepted
+    pub insecure_skip_verify: Option<bool>,
+    /// How often to fetch new data, in seconds
+    pub refresh_interval: Option<u64>,
+    /// Most query log entries to keep in memory
+    pub query_log_size: Option<usize>,
+    /// Path to a database to save history to, so it's kept for longer, and between runs
+    pub history_db: Option<PathBuf>,
+    /// How many days of history to keep in the database
+    pub history_retention_days: Option<u64>,
+    #[serde(default)]
+    pub theme: Theme,
+    #[serde(default)]
+    pub layout: LayoutOptions,
+}
+
+/// Colour scheme for the dashboard
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// The full colour dashboard
+    #[default]
+    Default,
+    /// No colours, for terminals (or people) that don't get on with them
+    Monochrome,
+}
+
+/// When to show the filters and top lists along the bottom of the dashboard
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ListsVisibility {
+    /// Only when the terminal is taller than `lists_min_height`
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Options for how the dashboard is laid out
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutOptions {
+    pub lists: ListsVisibility,
+    /// Terminal height (in rows) above which the lists are shown, when set to `auto`
+    pub lists_min_height: u16,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        LayoutOptions { lists: ListsVisibility::Auto, lists_min_height: 42 }
+    }
+}
+
+impl LayoutOptions {
+    /// Whether the lists along the bottom should be shown, in a terminal of the given height
+    pub fn show_lists(&self, height: u16) -> bool {
+        match self.lists {
+            ListsVisibility::Auto => height > self.lists_min_height,
+            ListsVisibility::Always => true,
+            ListsVisibility::Never => false,
+        }
+    }
+}
+
+/// Where the config file lives, if not specified with `--config`. This follows the XDG spec everywhere
+/// but Windows (so on macOS too, rather than using `~/Library/Application Support`, as most CLI tools do)
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        dirs::config_dir()
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| dirs::home_dir().map(|home| home.join(".config")))
+    };
+    config_dir.map(|dir| dir.join("adguardian").join("config.toml"))
+}
+
+/// Reads and parses the config file at the given path
+pub fn load_config_file(path: &Path) -> anyhow::Result<ConfigFile> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read config file {}", path.display()))?;
+    toml::from_str(&contents)
+        .with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+/// Loads the config file from the given path, or the default location.
+/// A missing file at the default location isn't an error, as the file is optional
+pub fn load_config(path: Option<&Path>) -> anyhow::Result<(Option<PathBuf>, ConfigFile)> {
+    match path {
+        Some(path) => Ok((Some(path.to_path_buf()), load_config_file(path)?)),
+        None => match default_config_path().filter(|path| path.exists()) {
+            Some(path) => {
+                let config = load_config_file(&path)?;
+                Ok((Some(path), config))
+            }
+            None => Ok((None, ConfigFile::default())),
+        },
+    }
+}
+
+impl ConfigFile {
+    /// Picks the profile to use, returning its name and settings, or `None` if there are none
+    pub fn select_profile(&self, requested: Option<&str>) -> anyhow::Result<Option<(String, InstanceConfig)>> {
+        let name = match requested.or(self.default_profile.as_deref()) {
+            Some(name) => name.to_string(),
+            None if self.instances.len() == 1 => self.instances.keys().next().cloned().unwrap_or_default(),
+            None if self.instances.is_empty() => return Ok(None),
+            None => bail!(
+                "Multiple profiles are configured ({}), choose one with --profile, or set default_profile",
+                self.profile_names().join(", ")
+            ),
+        };
+        let instance = self.profile(&name)?;
+        Ok(Some((name, instance)))
+    }
+
+    /// Gets a profile by name, checking that its URL (if it has one) is valid
+    fn profile(&self, name: &str) -> anyhow::Result<InstanceConfig> {
+        let instance = self.instances.get(name).cloned().ok_or_else(|| {
+            anyhow!("No profile named '{}' in the config file (available: {})", name, self.profile_names().join(", "))
+        })?;
+        if let Some(url) = &instance.url {
+            Url::parse(url).with_context(|| format!("Invalid url '{}' in profile '{}'", url, name))?;
+        }
+        Ok(instance)
+    }
+
+    fn profile_names(&self) -> Vec<String> {
+        self.instances.keys().cloned().collect()
+    }
+}
+
+/// Where a setting's value came from, so we can tell the user
+#[derive(Clone, Copy)]
+pub enum Source {
+    Flag,
+    Env,
+    ConfigFile,
+    Default,
+    Prompt,
+}
+
+impl Source {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Source::Flag => "flag",
+            Source::Env => "environment variable",
+            Source::ConfigFile => "config file",
+            Source::Default => "default",
+            Source::Prompt => "prompt",
+        }
+    }
+}
+
+/// Looks up settings by their environment variable name (e.g. `ADGUARD_IP`),
+/// checking flags first, then environment variables, then the profile from the config file
+pub struct Resolver {
+    flags: HashMap<&'static str, String>,
+    /// Whether environment variables are checked
+    use_env: bool,
+    profile: Option<InstanceConfig>,
+    /// Name of the selected profile, and the file it came from
+    pub profile_source: Option<(String, PathBuf)>,
+}
+
+impl Resolver {
+    /// Loads the config file and selects a profile, as specified by the command line arguments
+    pub fn from_args(args: &GlobalArgs) -> anyhow::Result<Self> {
+        let (config_path, config) = load_config(args.config.as_deref())?;
+        let profile = config.select_profile(args.profile.as_deref())?;
+        let profile_source = match (&profile, config_path) {
+            (Some((name, _)), Some(path)) => Some((name.clone(), path)),
+            _ => None,
+        };
+        Ok(Resolver {
+            flags: args.flag_values().into_iter().collect(),
+            use_env: true,
+            profile: profile.map(|(_, instance)| instance),
+            profile_source,
+        })
+    }
+
+    /// One resolver for each instance to show on the dashboard. That's each of the profiles listed
+    /// with `--profiles` (or `dashboard` in the config file), if there's more than one,
+    /// otherwise just the one selected as usual
+    pub fn for_dashboard(args: &GlobalArgs) -> anyhow::Result<Vec<Self>> {
+        let (config_path, config) = load_config(args.config.as_deref())?;
+        let names = match (&args.profile, args.profiles.as_slice()) {
+            (None, []) => config.dashboard.as_slice(),
+            (None, names) => names,
+            (Some(_), _) => &[],
+        };
+        if names.len() < 2 {
+            return Ok(vec![Self::from_args(args)?]);
+        }
+        names.iter().map(|name| Self::from_profile(&config, config_path.as_deref(), name)).collect()
+    }
+
+    /// A resolver for the named profile, which (as there may be several in use at once)
+    /// is set entirely from the config file, ignoring flags and environment variables
+    pub fn for_profile(args: &GlobalArgs, name: &str) -> anyhow::Result<Self> {
+        let (config_path, config) = load_config(args.config.as_deref())?;
+        Self::from_profile(&config, config_path.as_deref(), name)
+    }
+
+    fn from_profile(config: &ConfigFile, config_path: Option<&Path>, name: &str) -> anyhow::Result<Self> {
+        Ok(Resolver {
+            flags: HashMap::new(),
+            use_env: false,
+            profile: Some(config.profile(name)?),
+            profile_source: config_path.map(|path| (name.to_string(), path.to_path_buf())),
+        })
+    }
+
+    /// Builds the final settings. `get` is called for each required connection setting
+    /// (with its default, if it has one), and can look it up, print it, or ask for it.
+    /// If AdGuard's certificate won't be verified, a warning is printed, whatever it's being used for
+    pub fn settings<F>(&self, mut get: F) -> anyhow::Result<Settings>
+    where
+        F: FnMut(&Resolver, &str, Option<&str>) -> anyhow::Result<String>,
+    {
+        let ip = get(self, "ADGUARD_IP", None)?;
+        let port = get(self, "ADGUARD_PORT", None)?;
+        let protocol = get(self, "ADGUARD_PROTOCOL", Some("http"))?;
+        let base_path = Settings::normalize_base_path(self.get("ADGUARD_BASE_PATH").map(|(value, _)| value).as_deref());
+        let auth_method = Settings::parse_auth_method(self.get("ADGUARD_AUTH").map(|(value, _)| value).as_deref())?;
+        // Only asked for when they're needed, as a proxy in front of AdGuard may handle auth instead
+        let (username, password) = if auth_method.needs_credentials() {
+            (get(self, "ADGUARD_USERNAME", None)?, get(self, "ADGUARD_PASSWORD", None)?)
+        } else {
+            let optional = |key| self.get(key).map(|(value, _)| value).unwrap_or_default();
+            (optional("ADGUARD_USERNAME"), optional("ADGUARD_PASSWORD"))
+        };
+        let token = match auth_method {
+            AuthMethod::Bearer => Some(get(self, "ADGUARD_TOKEN", None)?),
+            _ => None,
+        };
+        let settings = Settings {
+            name: self.profile_source.as_ref().map_or_else(|| ip.clone(), |(name, _)| name.clone()),
+            ip,
+            port,
+            protocol,
+            base_path,
+            username,
+            password,
+            auth_method,
+            token,
+            headers: self.headers()?,
+            tls: self.tls()?,
+            update_interval: Settings::parse_update_interval(
+                self.get("ADGUARD_UPDATE_INTERVAL").map(|(value, _)| value).as_deref(),
+            )?,
+            query_log_size: Settings::parse_query_log_size(
+                self.get("ADGUARD_QUERY_LOG_SIZE").map(|(value, _)| value).as_deref(),
+            )?,
+            history_db: self.get("ADGUARD_HISTORY_DB")
+                .map(|(value, _)| PathBuf::from(value))
+                .filter(|path| !path.as_os_str().is_empty()),
+            history_retention: Settings::parse_history_retention(
+                self.get("ADGUARD_HISTORY_RETENTION_DAYS").map(|(value, _)| value).as_deref(),
+            )?,
+            theme: self.theme(),
+            layout: self.layout(),
+        };
+        if settings.tls.insecure_skip_verify {
+            print_insecure_warning(&settings.name);
+        }
+        Ok(settings)
+    }
+
+    /// Builds the final settings without any user interaction, for scripting.
+    /// Fails if a required setting isn't set anywhere
+    pub fn settings_non_interactive(&self) -> anyhow::Result<Settings> {
+        self.settings(|resolver, key, default| {
+            resolver.get(key)
+                .map(|(value, _)| value)
+                .or_else(|| default.map(str::to_string))
+                .ok_or_else(|| anyhow!("{} is not set. Pass it as a flag, environment variable, or in the config file", key))
+        })
+    }
+
+    /// Gets the value of a setting, and where it came from, if it's set anywhere
+    pub fn get(&self, key: &str) -> Option<(String, Source)> {
+        if let Some(value) = self.flags.get(key) {
+            return Some((value.clone(), Source::Flag));
+        }
+        if let Some(value) = env::var(key).ok().filter(|_| self.use_env) {
+            return Some((value, Source::Env));
+        }
+        self.profile_value(key).map(|value| (value, Source::ConfigFile))
+    }
+
+    /// Gets a setting from the selected profile in the config file
+    fn profile_value(&self, key: &str) -> Option<String> {
+        let profile = self.profile.as_ref()?;
+        let url = profile.url.as_deref().and_then(|url| Url::parse(url).ok());
+        match key {
+            "ADGUARD_IP" => url?.host_str().map(str::to_string),
+            "ADGUARD_PORT" => url?.port_or_known_default().map(|port| port.to_string()),
+            "ADGUARD_PROTOCOL" => url.map(|url| url.scheme().to_string()),
+            "ADGUARD_BASE_PATH" => url.map(|url| url.path().to_string()),
+            "ADGUARD_USERNAME" => profile.username.clone(),
+            "ADGUARD_PASSWORD" => profile.password.clone(),
+            "ADGUARD_AUTH" => profile.auth.map(|auth| auth.as_str().to_string()),
+            "ADGUARD_TOKEN" => profile.token.clone(),
+            "ADGUARD_CA_CERT" => profile.ca_cert.as_ref().map(|path| path.display().to_string()),
+            "ADGUARD_CLIENT_CERT" => profile.client_cert.as_ref().map(|path| path.display().to_string()),
+            "ADGUARD_CLIENT_KEY" => profile.client_key.as_ref().map(|path| path.display().to_string()),
+            "ADGUARD_INSECURE_SKIP_VERIFY" => profile.insecure_skip_verify.map(|skip| skip.to_string()),
+            "ADGUARD_UPDATE_INTERVAL" => profile.refresh_interval.map(|secs| secs.to_string()),
+            "ADGUARD_QUERY_LOG_SIZE" => profile.query_log_size.map(|size| size.to_string()),
+            "ADGUARD_HISTORY_DB" => profile.history_db.as_ref().map(|path| path.display().to_string()),
+            "ADGUARD_HISTORY_RETENTION_DAYS" => profile.history_retention_days.map(|days| days.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Extra headers to send with every request, from `ADGUARD_HEADERS` if it's set,
+    /// otherwise from the `headers` table in the profile
+    fn headers(&self) -> anyhow::Result<HeaderMap> {
+        let headers = match self.get("ADGUARD_HEADERS") {
+            Some((value, _)) => Settings::parse_headers(&value)?,
+            None => self.profile.as_ref().map(|p| p.headers.clone().into_iter().collect()).unwrap_or_default(),
+        };
+        Settings::header_map(headers)
+    }
+
+    /// The TLS options, reading in any certificates and keys they point to
+    fn tls(&self) -> anyhow::Result<TlsOptions> {
+        let path = |key| self.get(key).map(|(value, _)| PathBuf::from(value)).filter(|path| !path.as_os_str().is_empty());
+        let ca_certs = match path("ADGUARD_CA_CERT") {
+            Some(path) => {
+                let pem = read_pem(&path, "CA certificate")?;
+                if !pem.contains("-----BEGIN CERTIFICATE-----") {
+                    bail!("No certificates found in CA certificate file {}", path.display());
+                }
+                Some(Certificate::from_pem(pem.as_bytes())?)
+            }
+            None => None,
+        };
+        let identity = match (path("ADGUARD_CLIENT_CERT"), path("ADGUARD_CLIENT_KEY")) {
+            (Some(cert_path), Some(key_path)) => {
+                let pem = format!("{}\n{}", read_pem(&cert_path, "client certificate")?, read_pem(&key_path, "client key")?);
+                let identity = Identity::from_pem(pem.as_bytes()).with_context(|| format!(
+                    "Invalid client certificate {} or key {}", cert_path.display(), key_path.display()
+                ))?;
+                Some(identity)
+            }
+            (None, None) => None,
+            _ => bail!("ADGUARD_CLIENT_CERT and ADGUARD_CLIENT_KEY must be set together, for mutual TLS"),
+        };
+        Ok(TlsOptions {
+            ca_certs,
+            identity,
+            insecure_skip_verify: Settings::parse_insecure_skip_verify(
+                self.get("ADGUARD_INSECURE_SKIP_VERIFY").map(|(value, _)| value).as_deref(),
+            )?,
+        })
+    }
+
+    /// The theme from the profile, or the default
+    fn theme(&self) -> Theme {
+        self.profile.as_ref().map(|p| p.theme).unwrap_or_default()
+    }
+
+    /// The layout options from the profile, or the defaults
+    fn layout(&self) -> LayoutOptions {
+        self.profile.as_ref().map(|p| p.layout).unwrap_or_default()
+    }
+}
+
+/// Warns loudly (on stderr, so it's not mixed into any output) that the instance's TLS certificate won't be verified
+fn print_insecure_warning(name: &str) {
+    eprintln!(
+        "{}\n{}",
+        format!("WARNING: TLS certificate verification is disabled for {}", name).red().bold(),
+        concat!(
+            "The connection can be intercepted, and your credentials read, by anyone on the network.\n",
+            "Use ADGUARD_CA_CERT to trust AdGuard's certificate instead, if it's signed by your own CA."
+        ).yellow(),
+    );
+}
+
+/// Reads a PEM file, for the TLS options, saying which it was if that fails
+fn read_pem(path: &Path, what: &str) -> anyhow::Result<String> {
+    fs::read_to_string(path).with_context(|| format!("Unable to read {} {}", what, path.display()))
+}
+
+/// The final settings, once everything has been resolved
+pub struct Settings {
+    /// Name of the profile, or the IP address if there isn't one
+    pub name: String,
+    pub ip: String,
+    pub port: String,
+    pub protocol: String,
+    /// Path that AdGuard is served under (e.g. `/adguard`, behind a reverse proxy), or empty if it's at the root
+    pub base_path: String,
+    pub username: String,
+    pub password: String,
+    pub auth_method: AuthMethod,
+    /// Token to send, with bearer auth
+    pub token: Option<String>,
+    /// Extra headers to send with every request
+    pub headers: HeaderMap,
+    pub tls: TlsOptions,
+    pub update_interval: Duration,
+    /// Most query log entries to keep in memory
+    pub query_log_size: usize,
+    /// Where to save history to, if anywhere
+    pub history_db: Option<PathBuf>,
+    /// How long to keep saved history for
+    pub history_retention: Duration,
+    pub theme: Theme,
+    pub layout: LayoutOptions,
+}
+
+impl Settings {
+    /// Base URL of the AdGuard instance, e.g. http://192.168.1.2:3000 or https://example.com:443/adguard
+    pub fn hostname(&self) -> String {
+        format!("{}://{}:{}{}", self.protocol, self.ip, self.port, self.base_path)
+    }
+
+    /// Makes sure the base path starts with a slash, and doesn't end with one, so it can go after the port
+    pub fn normalize_base_path(value: Option<&str>) -> String {
+        match value.map(|value| value.trim().trim_matches('/')) {
+            Some(path) if !path.is_empty() => format!("/{}", path),
+            _ => String::new(),
+        }
+    }
+
+    /// Creates a client for the instance, authenticating, sending extra headers and setting up TLS as configured
+    pub fn client(&self) -> Result<AdGuardClient, AdGuardError> {
+        let client = AdGuardClient::new(&self.hostname(), &self.username, &self.password)?
+            .with_auth_method(self.auth_method)
+            .with_headers(self.headers.clone())
+            .with_tls(self.tls.clone())?;
+        match &self.token {
+            Some(token) => client.with_bearer_token(token),
+            None => Ok(client),
+        }
+    }
+
+    /// Parses how to authenticate, falling back to Basic auth if it's not set
+    pub fn parse_auth_method(value: Option<&str>) -> anyhow::Result<AuthMethod> {
+        match value.map(|value| value.trim().to_lowercase()).as_deref() {
+            None | Some("basic") => Ok(AuthMethod::Basic),
+            Some("session") => Ok(AuthMethod::Session),
+            Some("bearer") => Ok(AuthMethod::Bearer),
+            Some("none") => Ok(AuthMethod::None),
+            Some(_) => bail!(
+                "Invalid auth method '{}', expected basic, session, bearer or none",
+                value.unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Parses whether to skip verifying AdGuard's certificate, which is off unless it's set
+    pub fn parse_insecure_skip_verify(value: Option<&str>) -> anyhow::Result<bool> {
+        match value.map(|value| value.trim().to_lowercase()).as_deref() {
+            None | Some("") | Some("false") | Some("0") | Some("no") => Ok(false),
+            Some("true") | Some("1") | Some("yes") => Ok(true),
+            Some(_) => bail!(
+                "Invalid value '{}' for ADGUARD_INSECURE_SKIP_VERIFY, expected true or false",
+                value.unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Parses a list of headers, separated by semicolons, each in the form `Name: value`
+    pub fn parse_headers(value: &str) -> anyhow::Result<Vec<(String, String)>> {
+        value.split(';')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .map(|header| match header.split_once(':') {
+                Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                None => bail!("Invalid header '{}', expected 'Name: value'", header),
+            })
+            .collect()
+    }
+
+    /// Checks each header's name and value are valid, collecting them into a map
+    pub fn header_map(headers: Vec<(String, String)>) -> anyhow::Result<HeaderMap> {
+        headers.into_iter()
+            .map(|(name, value)| {
+                let header_name = HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("Invalid header name '{}'", name))?;
+                let header_value = HeaderValue::from_str(&value)
+                    .with_context(|| format!("Invalid value for header '{}'", name))?;
+                Ok((header_name, header_value))
+            })
+            .collect()
+    }
+
+    /// Parses the update interval, falling back to the default if it's not set.
+    /// It must be at least a second, so AdGuard isn't polled in a tight loop
+    pub fn parse_update_interval(value: Option<&str>) -> anyhow::Result<Duration> {
+        let secs = match value {
+            Some(value) => value.trim().parse()
+                .with_context(|| format!("Invalid update interval '{}', expected a number of seconds", value))?,
+            None => DEFAULT_UPDATE_INTERVAL,
+        };
+        if secs == 0 {
+            bail!("Invalid update interval '0', it must be at least 1 second");
+        }
+        Ok(Duration::from_secs(secs))
+    }
+
+    /// Parses how many days of history to keep, falling back to the default if it's not set
+    pub fn parse_history_retention(value: Option<&str>) -> anyhow::Result<Duration> {
+        let days = match value {
+            Some(value) => value.trim().parse()
+                .with_context(|| format!("Invalid history retention '{}', expected a number of days", value))?,
+            None => DEFAULT_HISTORY_RETENTION_DAYS,
+        };
+        // The cutoff is worked out from a timestamp, so it has to fit in one of those too
+        let secs = days.checked_mul(24 * 60 * 60)
+            .filter(|secs| i64::try_from(*secs).is_ok())
+            .ok_or_else(|| anyhow!("History retention of {} days is too long", days))?;
+        Ok(Duration::from_secs(secs))
+    }
+
+    /// Parses the query log size, falling back to the default if it's not set
+    pub fn parse_query_log_size(value: Option<&str>) -> anyhow::Result<usize> {
+        match value {
+            Some(value) => value.trim().parse()
+                .with_context(|| format!("Invalid query log size '{}', expected a number of entries", value)),
+            None => Ok(query_log::DEFAULT_CAPACITY),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(url: &str) -> InstanceConfig {
+        InstanceConfig { url: Some(url.to_string()), ..Default::default() }
+    }
+
+    fn config(default_profile: Option<&str>, names: &[&str]) -> ConfigFile {
+        ConfigFile {
+            default_profile: default_profile.map(str::to_string),
+            instances: names.iter()
+                .map(|name| (name.to_string(), instance(&format!("http://{}.lan:3000", name))))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn selected(config: &ConfigFile, requested: Option<&str>) -> Option<String> {
+        config.select_profile(requested).unwrap().map(|(name, _)| name)
+    }
+
+    #[test]
+    fn settings_are_taken_from_flags_then_env_then_profile() {
+        let mut resolver = Resolver {
+            flags: HashMap::from([("ADGUARD_IP", "10.0.0.1".to_string())]),
+            use_env: true,
+            profile: Some(instance("http://10.0.0.3:3000")),
+            profile_source: None,
+        };
+        let get = |resolver: &Resolver| resolver.get("ADGUARD_IP").map(|(value, source)| (value, source.describe()));
+        // Only this test sets ADGUARD_IP, so it doesn't affect any others
+        env::set_var("ADGUARD_IP", "10.0.0.2");
+        assert_eq!(get(&resolver), Some(("10.0.0.1".to_string(), "flag")));
+        resolver.flags.clear();
+        assert_eq!(get(&resolver), Some(("10.0.0.2".to_string(), "environment variable")));
+        env::remove_var("ADGUARD_IP");
+        assert_eq!(get(&resolver), Some(("10.0.0.3".to_string(), "config file")));
+        resolver.profile = None;
+        assert_eq!(get(&resolver), None);
+    }
+
+    #[test]
+    fn profiles_shown_together_ignore_the_environment() {
+        let resolver = Resolver::from_profile(&config(None, &["home", "office"]), None, "office").unwrap();
+        assert!(!resolver.use_env);
+        assert_eq!(resolver.get("ADGUARD_PORT").map(|(value, _)| value).as_deref(), Some("3000"));
+    }
+
+    #[test]
+    fn unset_settings_fall_back_to_their_defaults() {
+        let resolver = Resolver {
+            flags: HashMap::from([("ADGUARD_USERNAME", "admin".to_string()), ("ADGUARD_PASSWORD", "secret".to_string())]),
+            use_env: false,
+            profile: Some(instance("http://10.0.0.3")),
+            profile_source: None,
+        };
+        let settings = resolver.settings_non_interactive().unwrap();
+        assert_eq!(settings.hostname(), "http://10.0.0.3:80");
+        assert_eq!(settings.update_interval, Duration::from_secs(DEFAULT_UPDATE_INTERVAL));
+        assert_eq!(settings.query_log_size, query_log::DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn profile_is_chosen_by_flag_then_default_then_if_its_the_only_one() {
+        let both = config(Some("home"), &["home", "office"]);
+        assert_eq!(selected(&both, Some("office")).as_deref(), Some("office"));
+        assert_eq!(selected(&both, None).as_deref(), Some("home"));
+        assert_eq!(selected(&config(None, &["home"]), None).as_deref(), Some("home"));
+        assert_eq!(selected(&config(None, &[]), None), None);
+    }
+
+    #[test]
+    fn profile_must_exist_and_be_unambiguous() {
+        let both = config(None, &["home", "office"]);
+        assert!(both.select_profile(None).is_err());
+        assert!(both.select_profile(Some("cabin")).is_err());
+    }
+
+    #[test]
+    fn update_interval_must_be_at_least_a_second() {
+        assert_eq!(Settings::parse_update_interval(Some("5")).unwrap(), Duration::from_secs(5));
+        assert_eq!(Settings::parse_update_interval(None).unwrap(), Duration::from_secs(DEFAULT_UPDATE_INTERVAL));
+        assert!(Settings::parse_update_interval(Some("0")).is_err());
+        assert!(Settings::parse_update_interval(Some("soon")).is_err());
+    }
+
+    #[test]
+    fn history_retention_that_overflows_is_rejected() {
+        assert_eq!(Settings::parse_history_retention(Some("2")).unwrap(), Duration::from_secs(2 * 24 * 60 * 60));
+        assert!(Settings::parse_history_retention(Some("106751991167301")).is_err());
+        assert!(Settings::parse_history_retention(Some(&u64::MAX.to_string())).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Tracks the health of the connection to AdGuard, so that the fetcher can
+//! back off and retry when the instance is unreachable, and the UI can tell
+//! the user what's going on, instead of exiting
+
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+
+/// How long to wait after the first failure, doubled for each one after
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest we'll ever wait between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The current state of the connection, as reported by the fetcher to the UI
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    /// No data has been fetched yet
+    Connecting,
+    /// The last round of fetches all succeeded
+    Connected {
+        last_update: DateTime<Local>,
+    },
+    /// The last round of fetches failed, and we're waiting to try again
+    Retrying {
+        attempt: u32,
+        retry_at: Instant,
+        last_success: Option<DateTime<Local>>,
+        error: String,
+    },
+}
+
+impl ConnectionState {
+    /// Whether the data currently on screen may be out of date
+    pub fn is_stale(&self) -> bool {
+        matches!(self, ConnectionState::Retrying { .. })
+    }
+}
+
+/// Exponential backoff, doubling the delay after each consecutive failure
+#[derive(Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Number of consecutive failures so far
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Records a failure, and returns how long to wait before retrying
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 2u32.saturating_pow(self.attempt.min(16));
+        self.attempt += 1;
+        INITIAL_BACKOFF.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+
+    /// Records a success, so the next failure starts from the base delay again
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_after_each_failure() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..4).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8]);
+        assert_eq!(backoff.attempt(), 4);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut backoff = Backoff::default();
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= MAX_BACKOFF);
+        }
+        assert_eq!(backoff.next_delay(), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_starts_again_after_a_success() {
+        let mut backoff = Backoff::default();
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(backoff.next_delay(), INITIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,371 @@
+//! A single client for talking to the AdGuard Home API.
+//! It owns the base URL, credentials and timeouts, and turns every failure into
+//! an `AdGuardError`, so each endpoint only needs to say where it lives, and what it returns.
+//! The methods for each endpoint live alongside their response types, in the other fetch modules.
+//!
+//! By default, every request carries HTTP Basic auth. With session auth, the client instead
+//! logs in once via `/control/login`, and sends the session cookie it gets back with each request.
+//! If the session expires (AdGuard responds with a 401), it logs in again, and retries the request.
+//! When AdGuard sits behind a proxy which handles auth itself, the client can instead send a bearer token,
+//! or nothing at all, along with any extra headers the proxy expects (e.g. `X-Api-Key`).
+//!
+//! Over HTTPS, AdGuard's certificate is checked against the usual trusted roots, plus any extra CA
+//! certificates given (e.g. for an internal CA). A client certificate can also be presented, for mutual TLS.
+//! Verification can be turned off entirely, but the dashboard makes a lot of noise about it if so.
+
+use std::{fmt, sync::Arc, time::Duration};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, COOKIE, SET_COOKIE},
+    Certificate, Client, Identity, RequestBuilder, Response, StatusCode,
+};
+use semver::Version;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+/// Oldest version of AdGuard Home that we know how to talk to
+pub const MIN_VERSION: &str = "0.107.29";
+
+/// How long to wait for any single request before giving up
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything that can go wrong when talking to AdGuard
+#[derive(Debug)]
+pub enum AdGuardError {
+    /// AdGuard rejected the username or password
+    Auth { endpoint: &'static str },
+    /// AdGuard couldn't be reached, or didn't respond in time
+    Unreachable { endpoint: &'static str, source: reqwest::Error },
+    /// AdGuard responded, but is running a version we don't support
+    UnsupportedVersion { found: Option<String> },
+    /// AdGuard responded with something we couldn't make sense of
+    Decode { endpoint: &'static str, source: reqwest::Error },
+    /// AdGuard responded with any other unsuccessful status code
+    Status { endpoint: &'static str, status: StatusCode },
+    /// The credentials (or token) couldn't be made into a valid header
+    InvalidCredentials,
+    /// Logging in succeeded, but AdGuard didn't set a session cookie
+    NoSession,
+    /// The HTTP client couldn't be set up with the given TLS options (e.g. an invalid certificate)
+    Tls { source: reqwest::Error },
+}
+
+impl fmt::Display for AdGuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdGuardError::Auth { endpoint } =>
+                write!(f, "Authentication failed when requesting {}", endpoint),
+            AdGuardError::Unreachable { endpoint, .. } =>
+                write!(f, "Unable to reach AdGuard when requesting {}", endpoint),
+            AdGuardError::UnsupportedVersion { found: Some(version) } =>
+                write!(f, "AdGuard Home {} is unsupported, v{} or later is required", version, MIN_VERSION),
+            AdGuardError::UnsupportedVersion { found: None } =>
+                write!(f, "Unable to determine the AdGuard Home version, v{} or later is required", MIN_VERSION),
+            AdGuardError::Decode { endpoint, .. } =>
+                write!(f, "Failed to decode the response from {}", endpoint),
+            AdGuardError::Status { endpoint, status } =>
+                write!(f, "Request to {} failed with status code {}", endpoint, status),
+            AdGuardError::InvalidCredentials =>
+                write!(f, "The username, password or token contains invalid characters"),
+            AdGuardError::NoSession =>
+                write!(f, "Logged in to AdGuard, but no session cookie was set"),
+            AdGuardError::Tls { .. } =>
+                write!(f, "Unable to set up TLS with the given certificates"),
+        }
+    }
+}
+
+impl std::error::Error for AdGuardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AdGuardError::Unreachable { source, .. }
+            | AdGuardError::Decode { source, .. }
+            | AdGuardError::Tls { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// How the client authenticates with AdGuard
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// HTTP Basic auth, sent with every request
+    #[default]
+    Basic,
+    /// Log in via `/control/login`, then send the session cookie with every request
+    Session,
+    /// A bearer token, sent with every request, for a proxy in front of AdGuard
+    Bearer,
+    /// No auth at all, for a proxy in front of AdGuard which is happy with just the extra headers
+    None,
+}
+
+impl AuthMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthMethod::Basic => "basic",
+            AuthMethod::Session => "session",
+            AuthMethod::Bearer => "bearer",
+            AuthMethod::None => "none",
+        }
+    }
+
+    /// Whether a username and password are needed to authenticate this way
+    pub fn needs_credentials(self) -> bool {
+        matches!(self, AuthMethod::Basic | AuthMethod::Session)
+    }
+}
+
+/// How to connect to AdGuard over HTTPS, beyond checking its certificate against the usual trusted roots
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    /// Extra CA certificates to trust, e.g. for an internal CA
+    pub ca_certs: Option<Certificate>,
+    /// Client certificate and key to present, for mutual TLS
+    pub identity: Option<Identity>,
+    /// Don't verify AdGuard's certificate at all. This makes the connection open to interception!
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Serialize)]
+struct LoginRequest<'a> {
+    name: &'a str,
+    password: &'a str,
+}
+
+/// Client for an AdGuard Home instance. Cheap to clone, as the underlying connection pool
+/// (and the session, if there is one) is shared
+#[derive(Clone)]
+pub struct AdGuardClient {
+    http: Client,
+    base_url: String,
+    username: String,
+    password: String,
+    /// Value of the Authorization header, for Basic or bearer auth
+    auth_header: HeaderValue,
+    auth_method: AuthMethod,
+    /// Sent with every request (including logging in), after the auth, so they can replace it
+    extra_headers: HeaderMap,
+    /// Cookie for the current session, once logged in
+    session: Arc<Mutex<Option<HeaderValue>>>,
+    timeout: Duration,
+    /// Whether AdGuard's certificate is left unverified
+    insecure: bool,
+}
+
+impl AdGuardClient {
+    /// Creates a client for the instance at `base_url` (e.g. http://192.168.1.2:3000),
+    /// which authenticates with the given username and password
+    pub fn new(base_url: &str, username: &str, password: &str) -> Result<Self, AdGuardError> {
+        let auth_string = format!("{}:{}", username, password);
+        let auth_header = format!("Basic {}", base64::encode(auth_string))
+            .parse()
+            .map_err(|_| AdGuardError::InvalidCredentials)?;
+
+        Ok(AdGuardClient {
+            http: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            auth_header,
+            auth_method: AuthMethod::Basic,
+            extra_headers: HeaderMap::new(),
+            session: Arc::new(Mutex::new(None)),
+            timeout: DEFAULT_TIMEOUT,
+            insecure: false,
+        })
+    }
+
+    /// Sets how long to wait for each request before treating AdGuard as unreachable
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how to authenticate with AdGuard. For bearer auth, use `with_bearer_token` instead
+    pub fn with_auth_method(mut self, auth_method: AuthMethod) -> Self {
+        self.auth_method = auth_method;
+        self
+    }
+
+    /// Authenticates by sending the given token in an `Authorization: Bearer` header, instead of Basic auth
+    pub fn with_bearer_token(mut self, token: &str) -> Result<Self, AdGuardError> {
+        self.auth_header = format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| AdGuardError::InvalidCredentials)?;
+        self.auth_method = AuthMethod::Bearer;
+        Ok(self)
+    }
+
+    /// Sets up the connection with the given TLS options
+    pub fn with_tls(mut self, tls: TlsOptions) -> Result<Self, AdGuardError> {
+        let mut builder = Client::builder().danger_accept_invalid_certs(tls.insecure_skip_verify);
+        if let Some(ca_certs) = tls.ca_certs {
+            builder = builder.add_root_certificate(ca_certs);
+        }
+        if let Some(identity) = tls.identity {
+            builder = builder.identity(identity);
+        }
+        self.http = builder.build().map_err(|source| AdGuardError::Tls { source })?;
+        self.insecure = tls.insecure_skip_verify;
+        Ok(self)
+    }
+
+    /// Whether AdGuard's certificate is left unverified, so the user can be warned
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    /// Sets extra headers to send with every request, e.g. for a proxy in front of AdGuard
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    /// Adds auth and timeout to a request, sends it, and checks the response was successful.
+    /// With session auth, if the session has expired, logs in again and retries once
+    async fn send(&self, endpoint: &'static str, request: RequestBuilder) -> Result<Response, AdGuardError> {
+        let response = match self.auth_method {
+            AuthMethod::Basic | AuthMethod::Bearer => {
+                self.send_once(endpoint, request.header(AUTHORIZATION, self.auth_header.clone())).await?
+            }
+            AuthMethod::None => self.send_once(endpoint, request).await?,
+            AuthMethod::Session => {
+                // Kept in case the request needs to be sent again, after logging in
+                let retry = request.try_clone();
+                let cookie = self.session_cookie(None).await?;
+                let response = self.send_once(endpoint, request.header(COOKIE, cookie.clone())).await?;
+                match (response.status(), retry) {
+                    (StatusCode::UNAUTHORIZED, Some(retry)) => {
+                        let cookie = self.session_cookie(Some(&cookie)).await?;
+                        self.send_once(endpoint, retry.header(COOKIE, cookie)).await?
+                    }
+                    _ => response,
+                }
+            }
+        };
+
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(AdGuardError::Auth { endpoint }),
+            status => Err(AdGuardError::Status { endpoint, status }),
+        }
+    }
+
+    /// Adds the extra headers and timeout to a request, and sends it, whatever the response
+    async fn send_once(&self, endpoint: &'static str, request: RequestBuilder) -> Result<Response, AdGuardError> {
+        request
+            .headers(self.extra_headers.clone())
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|source| AdGuardError::Unreachable { endpoint, source })
+    }
+
+    /// The cookie for the current session, logging in first if there isn't one yet,
+    /// or if it's the same as `expired` (so requests which fail together only log in once)
+    async fn session_cookie(&self, expired: Option<&HeaderValue>) -> Result<HeaderValue, AdGuardError> {
+        let mut session = self.session.lock().await;
+        match session.as_ref() {
+            Some(cookie) if Some(cookie) != expired => Ok(cookie.clone()),
+            _ => {
+                let cookie = self.login().await?;
+                *session = Some(cookie.clone());
+                Ok(cookie)
+            }
+        }
+    }
+
+    /// Logs in via `/control/login`, returning the session cookie AdGuard sets
+    async fn login(&self) -> Result<HeaderValue, AdGuardError> {
+        let endpoint = "/control/login";
+        let request = self.http.post(self.url(endpoint))
+            .json(&LoginRequest { name: &self.username, password: &self.password });
+        let response = self.send_once(endpoint, request).await?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::BAD_REQUEST => {
+                return Err(AdGuardError::Auth { endpoint });
+            }
+            status => return Err(AdGuardError::Status { endpoint, status }),
+        }
+        // Only the name=value part of the cookie is sent back, not its attributes
+        response.headers().get_all(SET_COOKIE).iter()
+            .filter_map(|header| header.to_str().ok())
+            .filter_map(|header| header.split(';').next())
+            .find(|cookie| cookie.contains('='))
+            .and_then(|cookie| HeaderValue::from_str(cookie.trim()).ok())
+            .ok_or(AdGuardError::NoSession)
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}{}", self.base_url, endpoint)
+    }
+
+    /// Makes a GET request to an endpoint, with optional query params, and decodes the JSON response
+    pub(crate) async fn get_json<T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        params: &[(&str, String)],
+    ) -> Result<T, AdGuardError> {
+        let request = self.http.get(self.url(endpoint)).query(params);
+        self.send(endpoint, request)
+            .await?
+            .json()
+            .await
+            .map_err(|source| AdGuardError::Decode { endpoint, source })
+    }
+
+    /// Makes a POST request to an endpoint, with a JSON body, ignoring any response body
+    pub(crate) async fn post_json<B: Serialize + ?Sized>(
+        &self,
+        endpoint: &'static str,
+        body: &B,
+    ) -> Result<(), AdGuardError> {
+        let request = self.http.post(self.url(endpoint)).json(body);
+        self.send(endpoint, request).await?;
+        Ok(())
+    }
+
+    /// Makes a PUT request to an endpoint, with a JSON body, ignoring any response body
+    pub(crate) async fn put_json<B: Serialize + ?Sized>(
+        &self,
+        endpoint: &'static str,
+        body: &B,
+    ) -> Result<(), AdGuardError> {
+        let request = self.http.put(self.url(endpoint)).json(body);
+        self.send(endpoint, request).await?;
+        Ok(())
+    }
+
+    /// Makes a POST request to an endpoint, with a JSON body, and decodes the JSON response
+    pub(crate) async fn post_json_returning<B: Serialize + ?Sized, T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        body: &B,
+    ) -> Result<T, AdGuardError> {
+        let request = self.http.post(self.url(endpoint)).json(body);
+        self.send(endpoint, request)
+            .await?
+            .json()
+            .await
+            .map_err(|source| AdGuardError::Decode { endpoint, source })
+    }
+
+    /// Checks we can connect and authenticate, and that the AdGuard version is supported.
+    /// The response is read loosely, as older versions may not include the fields we'd expect
+    pub async fn verify_connection(&self) -> Result<String, AdGuardError> {
+        let body: Value = self.get_json("/control/status", &[]).await?;
+        let version = body["version"].as_str().map(str::to_string);
+        let min_version = Version::parse(MIN_VERSION).expect("MIN_VERSION is valid semver");
+
+        let parsed = version
+            .as_deref()
+            .and_then(|v| Version::parse(v.strip_prefix('v').unwrap_or(v)).ok());
+        match (parsed, version) {
+            (Some(parsed), Some(version)) if parsed >= min_version => Ok(version),
+            (_, found) => Err(AdGuardError::UnsupportedVersion { found }),
+        }
+    }
+}
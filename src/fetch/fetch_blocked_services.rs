@@ -0,0 +1,43 @@
+//! Services (e.g. TikTok, or Steam) which are blocked for every client using the global settings
+
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+
+/// IDs of the blocked services, and when they're blocked.
+/// The schedule is passed through as-is, as it's only ever copied between instances
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
+pub struct BlockedServices {
+    #[serde(default)]
+    pub ids: Vec<String>,
+    /// Missing on versions older than v0.107.37, which always block them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Value>,
+}
+
+impl AdGuardClient {
+    /// Fetches the blocked services, from `/control/blocked_services/get`.
+    /// Versions before that existed only have `/control/blocked_services/list`, so that's used if it's not found
+    pub async fn fetch_blocked_services(&self) -> Result<BlockedServices, AdGuardError> {
+        match self.get_json("/control/blocked_services/get", &[]).await {
+            Err(AdGuardError::Status { status: StatusCode::NOT_FOUND, .. }) => {
+                let ids = self.get_json("/control/blocked_services/list", &[]).await?;
+                Ok(BlockedServices { ids, schedule: None })
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces the blocked services, via `/control/blocked_services/update`,
+    /// or `/control/blocked_services/set` on versions before that existed
+    pub async fn set_blocked_services(&self, services: &BlockedServices) -> Result<(), AdGuardError> {
+        match self.put_json("/control/blocked_services/update", services).await {
+            Err(AdGuardError::Status { status: StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED, .. }) => {
+                self.post_json("/control/blocked_services/set", &services.ids).await
+            }
+            result => result,
+        }
+    }
+}
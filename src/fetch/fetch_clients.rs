@@ -0,0 +1,57 @@
+//! Persistent clients, which have their own name, and optionally their own settings,
+//! rather than being known only by their IP address
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+
+/// A client that's been added in AdGuard's settings. Only the name is needed to identify it.
+/// Its settings are kept as they were returned, so that fields added in newer versions
+/// are still copied, when syncing between instances
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct PersistentClient {
+    pub name: String,
+    #[serde(flatten)]
+    pub settings: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct ClientsResponse {
+    #[serde(default)]
+    clients: Option<Vec<PersistentClient>>,
+}
+
+#[derive(Serialize)]
+struct UpdateClientRequest<'a> {
+    name: &'a str,
+    data: &'a PersistentClient,
+}
+
+#[derive(Serialize)]
+struct DeleteClientRequest<'a> {
+    name: &'a str,
+}
+
+impl AdGuardClient {
+    /// Fetches the persistent clients, from `/control/clients`
+    pub async fn fetch_clients(&self) -> Result<Vec<PersistentClient>, AdGuardError> {
+        let response: ClientsResponse = self.get_json("/control/clients", &[]).await?;
+        Ok(response.clients.unwrap_or_default())
+    }
+
+    /// Adds a persistent client, via `/control/clients/add`
+    pub async fn add_client(&self, client: &PersistentClient) -> Result<(), AdGuardError> {
+        self.post_json("/control/clients/add", client).await
+    }
+
+    /// Replaces the settings of the persistent client with the same name, via `/control/clients/update`
+    pub async fn update_client(&self, client: &PersistentClient) -> Result<(), AdGuardError> {
+        self.post_json("/control/clients/update", &UpdateClientRequest { name: &client.name, data: client }).await
+    }
+
+    /// Removes a persistent client, via `/control/clients/delete`
+    pub async fn remove_client(&self, name: &str) -> Result<(), AdGuardError> {
+        self.post_json("/control/clients/delete", &DeleteClientRequest { name }).await
+    }
+}
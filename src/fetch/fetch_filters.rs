@@ -1,34 +1,204 @@
-use reqwest::{Client, Response, header::HeaderMap};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize)]
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct AdGuardFilteringStatus {
+    /// Whether filtering is turned on at all
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// How often filter lists are updated, in hours (0 means never)
+    #[serde(default)]
+    pub interval: u32,
+    /// Blocklists
     pub filters: Option<Vec<Filter>>,
+    /// Allowlists, whose rules stop queries from being blocked
+    #[serde(default)]
+    pub whitelist_filters: Option<Vec<Filter>>,
+    /// Custom rules, written by the user, one per line
+    #[serde(default)]
+    pub user_rules: Vec<String>,
 }
 
-#[derive(Deserialize)]
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Filter {
+    /// Whether this is an allowlist, rather than a blocklist.
+    /// AdGuard keeps them in separate lists, so this is set once they've been fetched
+    #[serde(skip)]
+    pub allowlist: bool,
+    /// Referred to by the `filter_list_id` of rules matched in the query log
+    #[serde(default)]
+    pub id: i64,
     pub url: String,
     pub name: String,
     pub rules_count: u32,
     pub enabled: bool,
+    /// When the list was last downloaded, if it ever has been
+    #[serde(default)]
+    pub last_updated: Option<String>,
 }
 
-pub async fn fetch_adguard_filter_list(
-    client: &Client,
-    endpoint: &str,
-    username: &str,
-    password: &str,
-) -> Result<AdGuardFilteringStatus, reqwest::Error> {
-    let url = format!("{}/control/filtering/status", endpoint);
+/// ID given to rules matched from the custom user rules, rather than a filter list
+pub const USER_RULES_ID: i64 = 0;
+
+impl AdGuardFilteringStatus {
+    /// Every filter list, blocklists first, then allowlists
+    pub fn lists(&self) -> impl Iterator<Item = &Filter> {
+        let blocklists = self.filters.as_deref().unwrap_or_default();
+        let allowlists = self.whitelist_filters.as_deref().unwrap_or_default();
+        blocklists.iter().chain(allowlists)
+    }
+
+    /// Name of the filter list with the given ID, as referred to by rules matched in the query log
+    pub fn list_name(&self, id: i64) -> Option<&str> {
+        if id == USER_RULES_ID {
+            return Some("custom rules");
+        }
+        self.lists()
+            .find(|filter| filter.id == id)
+            .map(|filter| filter.name.as_str())
+    }
+}
+
+#[derive(Serialize)]
+struct SetRulesRequest<'a> {
+    rules: &'a [String],
+}
+
+/// Identifies a filter list by its URL, for adding or removing it.
+/// `whitelist` is whether it's an allowlist, rather than a blocklist
+#[derive(Serialize)]
+struct FilterUrlRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    url: &'a str,
+    whitelist: bool,
+}
+
+#[derive(Serialize)]
+struct SetFilterUrlRequest<'a> {
+    url: &'a str,
+    whitelist: bool,
+    data: FilterData<'a>,
+}
+
+#[derive(Serialize)]
+struct FilterData<'a> {
+    name: &'a str,
+    url: &'a str,
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct FilteringConfigRequest {
+    enabled: bool,
+    interval: u32,
+}
+
+#[derive(Serialize)]
+struct RefreshRequest {
+    whitelist: bool,
+}
+
+#[derive(Deserialize)]
+struct RefreshResponse {
+    #[serde(default)]
+    updated: u32,
+}
+
+impl AdGuardClient {
+    /// Fetches the filter lists configured in AdGuard, from `/control/filtering/status`
+    pub async fn fetch_filters(&self) -> Result<AdGuardFilteringStatus, AdGuardError> {
+        let mut status: AdGuardFilteringStatus = self.get_json("/control/filtering/status", &[]).await?;
+        for filter in status.whitelist_filters.iter_mut().flatten() {
+            filter.allowlist = true;
+        }
+        Ok(status)
+    }
+
+    /// Replaces the custom user rules, via `/control/filtering/set_rules`
+    pub async fn set_user_rules(&self, rules: &[String]) -> Result<(), AdGuardError> {
+        self.post_json("/control/filtering/set_rules", &SetRulesRequest { rules }).await
+    }
+
+    /// Enables or disables a filter list, via `/control/filtering/set_url`
+    pub async fn set_filter_enabled(&self, filter: &Filter, enabled: bool) -> Result<(), AdGuardError> {
+        let request = SetFilterUrlRequest {
+            url: &filter.url,
+            whitelist: filter.allowlist,
+            data: FilterData { name: &filter.name, url: &filter.url, enabled },
+        };
+        self.post_json("/control/filtering/set_url", &request).await
+    }
+
+    /// Turns filtering on or off, and sets how often lists are updated (in hours),
+    /// via `/control/filtering/config`
+    pub async fn set_filtering_config(&self, enabled: bool, interval: u32) -> Result<(), AdGuardError> {
+        self.post_json("/control/filtering/config", &FilteringConfigRequest { enabled, interval }).await
+    }
+
+    /// Has AdGuard download the latest version of every blocklist and allowlist,
+    /// via `/control/filtering/refresh`. Returns how many lists were updated
+    pub async fn refresh_filters(&self) -> Result<u32, AdGuardError> {
+        let mut updated = 0;
+        for whitelist in [false, true] {
+            let response: RefreshResponse = self
+                .post_json_returning("/control/filtering/refresh", &RefreshRequest { whitelist })
+                .await?;
+            updated += response.updated;
+        }
+        Ok(updated)
+    }
+
+    /// Adds a new blocklist (or allowlist), via `/control/filtering/add_url`.
+    /// AdGuard downloads it straight away, and rejects it if it can't be fetched
+    pub async fn add_filter(&self, name: &str, url: &str, allowlist: bool) -> Result<(), AdGuardError> {
+        let request = FilterUrlRequest { name: Some(name), url, whitelist: allowlist };
+        self.post_json("/control/filtering/add_url", &request).await
+    }
+
+    /// Removes a filter list, via `/control/filtering/remove_url`
+    pub async fn remove_filter(&self, filter: &Filter) -> Result<(), AdGuardError> {
+        let request = FilterUrlRequest { name: None, url: &filter.url, whitelist: filter.allowlist };
+        self.post_json("/control/filtering/remove_url", &request).await
+    }
+
+    /// Appends a rule to the custom user rules, unless it's already there.
+    /// Returns whether the rule was added
+    pub async fn add_user_rule(&self, rule: &str) -> Result<bool, AdGuardError> {
+        let mut rules = self.fetch_filters().await?.user_rules;
+        if rules.iter().any(|existing| existing.trim() == rule) {
+            return Ok(false);
+        }
+        rules.push(rule.to_string());
+        self.set_user_rules(&rules).await?;
+        Ok(true)
+    }
+}
 
-    let auth_string = format!("{}:{}", username, password);
-    let auth_header_value = format!("Basic {}", base64::encode(&auth_string));
-    let mut headers = HeaderMap::new();
-    headers.insert("Authorization", auth_header_value.parse().unwrap());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-    let res: Response = client.get(&url).headers(headers).send().await?;
-    let status: AdGuardFilteringStatus = res.json().await?;
+    #[test]
+    fn lists_are_blocklists_then_allowlists() {
+        let status: AdGuardFilteringStatus = serde_json::from_value(json!({
+            "filters": [{ "id": 1, "name": "Ads", "url": "https://a/ads.txt", "rules_count": 5, "enabled": true }],
+            "whitelist_filters": [{ "id": 2, "name": "Fixes", "url": "https://a/fixes.txt", "rules_count": 1, "enabled": false }],
+        })).unwrap();
+        // Filtering is on unless AdGuard says otherwise, and lists are never updated
+        assert!(status.enabled);
+        assert_eq!(status.interval, 0);
 
-    Ok(status)
+        let names: Vec<&str> = status.lists().map(|filter| filter.name.as_str()).collect();
+        assert_eq!(names, ["Ads", "Fixes"]);
+        assert_eq!(status.list_name(2), Some("Fixes"));
+        assert_eq!(status.list_name(USER_RULES_ID), Some("custom rules"));
+        assert_eq!(status.list_name(3), None);
+    }
 }
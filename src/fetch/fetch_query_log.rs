@@ -1,14 +1,35 @@
-use reqwest::{
-  header::{HeaderValue, CONTENT_LENGTH, AUTHORIZATION},
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    sync::Arc,
 };
-use serde::Deserialize;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+
+/// Number of entries to ask for in each page of the query log
+const PAGE_SIZE: usize = 500;
+
+/// Number of entries to ask for first when polling for new entries, as there are usually only a few
+const POLL_PAGE_SIZE: usize = 50;
+
+/// Most pages to fetch in one go, when catching up on everything since the last fetch
+const MAX_PAGES: usize = 20;
 
 #[derive(Deserialize)]
 pub struct QueryResponse {
+    /// Entries, newest first
     pub data: Vec<Query>,
+    /// Time of the oldest entry in this page, to pass as `older_than` to get the next page
+    #[serde(default)]
+    pub oldest: String,
 }
 
-#[derive(Deserialize)]
+/// A single entry from the query log.
+/// Fields which older AdGuard versions may omit are defaulted
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Query {
     pub cached: bool,
     pub client: String,
@@ -16,11 +37,40 @@ pub struct Query {
     #[serde(rename = "elapsedMs")]
     pub elapsed_ms: String,
     pub question: Question,
-    pub reason: String,
+    pub reason: QueryReason,
     pub time: String,
+    /// DNS response code, e.g. NOERROR or NXDOMAIN
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub answer: Vec<Answer>,
+    /// The answer from upstream, before AdGuard replaced it (if it did)
+    #[serde(default)]
+    pub original_answer: Vec<Answer>,
+    #[serde(default)]
+    pub answer_dnssec: bool,
+    /// Filtering rules which matched this query
+    #[serde(default)]
+    pub rules: Vec<MatchedRule>,
+    #[serde(default)]
+    pub client_info: Option<ClientInfo>,
+    /// Protocol the client used, e.g. dot, doh, or empty for plain DNS
+    #[serde(default)]
+    pub client_proto: String,
+    /// Name of the blocked service, if the query was blocked by one
+    #[serde(default)]
+    pub service_name: Option<String>,
+    /// Set when entries from just before this one were missed, as there were too many new entries
+    /// to catch up on in one fetch (e.g. after losing the connection for a while).
+    /// Not from AdGuard, but kept when saved, so the gap is still marked in history
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub gap_before: bool,
+    /// Name of the instance this came from, when the dashboard is showing several
+    #[serde(skip)]
+    pub instance: Option<Arc<str>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Question {
     pub class: String,
     pub name: String,
@@ -28,25 +78,414 @@ pub struct Question {
     pub question_type: String,
 }
 
-pub async fn fetch_adguard_query_log(
-  client: &reqwest::Client,
-  endpoint: &str,
-  username: &str,
-  password: &str,
-) -> Result<QueryResponse, anyhow::Error> {
-  let auth_string = format!("{}:{}", username, password);
-  let auth_header_value = format!("Basic {}", base64::encode(&auth_string));
-  let mut headers = reqwest::header::HeaderMap::new();
-  headers.insert(AUTHORIZATION, auth_header_value.parse()?);
-  headers.insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Answer {
+    #[serde(rename = "type")]
+    pub answer_type: String,
+    /// Usually a string, but can be structured for some record types
+    pub value: Value,
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MatchedRule {
+    /// ID of the filter list the rule came from, 0 being the custom user rules
+    #[serde(default)]
+    pub filter_list_id: i64,
+    pub text: String,
+}
+
+/// What AdGuard knows about the client that made a query
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ClientInfo {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub whois: HashMap<String, String>,
+    #[serde(default)]
+    pub disallowed: bool,
+}
+
+/// Identifies a query log entry, e.g. so it can stay selected when new entries push it down the log,
+/// or so entries which were already seen in a previous fetch can be skipped
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct QueryKey {
+    time: String,
+    client: String,
+    name: String,
+}
+
+/// Why AdGuard allowed, blocked or rewrote a query. Serialized as AdGuard's own name for it,
+/// so reasons added in newer versions are kept as `Other`, rather than failing to parse
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum QueryReason {
+    /// Didn't match any rules
+    NotFiltered,
+    /// Matched an allowlist rule
+    Allowlisted,
+    /// AdGuard couldn't process it
+    Error,
+    /// Matched a blocking rule, from a filter list or the user's rules
+    Blocked,
+    SafeBrowsing,
+    Parental,
+    /// Not a valid domain name, so blocked without being sent upstream
+    Invalid,
+    SafeSearch,
+    BlockedService,
+    /// Answered from a DNS rewrite
+    Rewritten,
+    /// Answered from the system's hosts file
+    RewrittenHosts,
+    /// Answered by a `$dnsrewrite` rule
+    RewrittenRule,
+    Other(String),
+}
+
+impl QueryReason {
+    /// AdGuard's name for the reason
+    pub fn as_str(&self) -> &str {
+        match self {
+            QueryReason::NotFiltered => "NotFilteredNotFound",
+            QueryReason::Allowlisted => "NotFilteredWhiteList",
+            QueryReason::Error => "NotFilteredError",
+            QueryReason::Blocked => "FilteredBlackList",
+            QueryReason::SafeBrowsing => "FilteredSafeBrowsing",
+            QueryReason::Parental => "FilteredParental",
+            QueryReason::Invalid => "FilteredInvalid",
+            QueryReason::SafeSearch => "FilteredSafeSearch",
+            QueryReason::BlockedService => "FilteredBlockedService",
+            QueryReason::Rewritten => "Rewrite",
+            QueryReason::RewrittenHosts => "RewriteEtcHosts",
+            QueryReason::RewrittenRule => "RewriteRule",
+            QueryReason::Other(reason) => reason,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            QueryReason::NotFiltered => "Allowed",
+            QueryReason::Allowlisted => "Allowlisted",
+            QueryReason::Error => "Error",
+            QueryReason::Blocked => "Blocked",
+            QueryReason::SafeBrowsing => "Safe Browsing",
+            QueryReason::Parental => "Parental",
+            QueryReason::Invalid => "Invalid",
+            QueryReason::SafeSearch => "Safe Search",
+            QueryReason::BlockedService => "Blocked Service",
+            QueryReason::Rewritten => "Rewritten",
+            QueryReason::RewrittenHosts => "Hosts File",
+            QueryReason::RewrittenRule => "Rewrite Rule",
+            QueryReason::Other(reason) => reason,
+        }
+    }
+
+    /// Whether the query was blocked, for any reason (filter list, blocked service, parental control, etc)
+    pub fn is_blocked(&self) -> bool {
+        matches!(
+            self,
+            QueryReason::Blocked | QueryReason::SafeBrowsing | QueryReason::Parental
+                | QueryReason::BlockedService | QueryReason::Invalid
+        )
+    }
+}
+
+impl From<String> for QueryReason {
+    fn from(reason: String) -> Self {
+        match reason.as_str() {
+            "NotFilteredNotFound" => QueryReason::NotFiltered,
+            "NotFilteredWhiteList" => QueryReason::Allowlisted,
+            "NotFilteredError" => QueryReason::Error,
+            "FilteredBlackList" => QueryReason::Blocked,
+            "FilteredSafeBrowsing" => QueryReason::SafeBrowsing,
+            "FilteredParental" => QueryReason::Parental,
+            "FilteredInvalid" => QueryReason::Invalid,
+            "FilteredSafeSearch" => QueryReason::SafeSearch,
+            "FilteredBlockedService" => QueryReason::BlockedService,
+            "Rewrite" => QueryReason::Rewritten,
+            "RewriteEtcHosts" => QueryReason::RewrittenHosts,
+            "RewriteRule" => QueryReason::RewrittenRule,
+            _ => QueryReason::Other(reason),
+        }
+    }
+}
+
+impl From<QueryReason> for String {
+    fn from(reason: QueryReason) -> Self {
+        match reason {
+            QueryReason::Other(reason) => reason,
+            known => known.as_str().to_string(),
+        }
+    }
+}
+
+/// Which responses to include from the query log, as understood by AdGuard's `response_status` parameter
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum ResponseStatus {
+    #[default]
+    All,
+    Blocked,
+    BlockedServices,
+    SafeSearch,
+    Rewritten,
+    Processed,
+    Whitelisted,
+}
+
+impl ResponseStatus {
+    pub const ALL: [ResponseStatus; 7] = [
+        ResponseStatus::All,
+        ResponseStatus::Blocked,
+        ResponseStatus::BlockedServices,
+        ResponseStatus::SafeSearch,
+        ResponseStatus::Rewritten,
+        ResponseStatus::Processed,
+        ResponseStatus::Whitelisted,
+    ];
+
+    /// Value of the `response_status` parameter
+    pub fn as_param(self) -> &'static str {
+        match self {
+            ResponseStatus::All => "all",
+            ResponseStatus::Blocked => "blocked",
+            ResponseStatus::BlockedServices => "blocked_services",
+            ResponseStatus::SafeSearch => "safe_search",
+            ResponseStatus::Rewritten => "rewritten",
+            ResponseStatus::Processed => "processed",
+            ResponseStatus::Whitelisted => "whitelisted",
+        }
+    }
 
-  let url = format!("{}/control/querylog", endpoint);
-  let response = client.get(&url).headers(headers).send().await?;
-  if !response.status().is_success() {
-      return Err(anyhow::anyhow!("Request failed with status code {}", response.status()));
-  }
+    pub fn label(self) -> &'static str {
+        match self {
+            ResponseStatus::All => "All",
+            ResponseStatus::Blocked => "Blocked",
+            ResponseStatus::BlockedServices => "Blocked services",
+            ResponseStatus::SafeSearch => "Safe search",
+            ResponseStatus::Rewritten => "Rewritten",
+            ResponseStatus::Processed => "Processed",
+            ResponseStatus::Whitelisted => "Allowlisted",
+        }
+    }
 
-  let data = response.json().await?;
-  Ok(data)
+    /// The next (or with a negative step, previous) status, wrapping around
+    pub fn cycle(self, step: isize) -> Self {
+        let len = Self::ALL.len() as isize;
+        let index = Self::ALL.iter().position(|&status| status == self).unwrap_or(0) as isize;
+        Self::ALL[(index + step).rem_euclid(len) as usize]
+    }
 }
 
+/// Narrows down which entries are fetched from the query log. Applied by AdGuard,
+/// so matches are found throughout its whole log, not just in what's been fetched
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct QueryFilter {
+    /// Matches part of the domain or client
+    pub search: String,
+    pub response_status: ResponseStatus,
+}
+
+impl QueryFilter {
+    /// Whether this filter lets every entry through
+    pub fn is_empty(&self) -> bool {
+        self.search.is_empty() && self.response_status == ResponseStatus::All
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        if !self.search.is_empty() {
+            params.push(("search", self.search.clone()));
+        }
+        if self.response_status != ResponseStatus::All {
+            params.push(("response_status", self.response_status.as_param().to_string()));
+        }
+        params
+    }
+}
+
+/// Marks how far through the query log we've fetched, so only newer entries are fetched next time
+#[derive(Clone, Default, PartialEq)]
+pub struct QueryCursor {
+    newest: Option<DateTime<FixedOffset>>,
+    /// Entries with exactly the newest time, as more may arrive with that same time
+    newest_keys: HashSet<QueryKey>,
+}
+
+impl QueryCursor {
+    /// Whether an entry is newer than everything that's been fetched so far
+    pub fn is_new(&self, query: &Query) -> bool {
+        match (self.newest, query.parsed_time()) {
+            (Some(newest), Some(time)) => time > newest || (time == newest && !self.newest_keys.contains(&query.key())),
+            _ => true,
+        }
+    }
+
+    /// Whether an entry is older than everything that's been fetched so far,
+    /// meaning every entry after it (in a page) has already been fetched
+    fn is_behind(&self, query: &Query) -> bool {
+        matches!((self.newest, query.parsed_time()), (Some(newest), Some(time)) if time < newest)
+    }
+
+    /// Moves past the given entries (newest first), which were fetched using this cursor
+    pub fn advance(&mut self, queries: &[Query]) {
+        let Some(newest) = queries.first().and_then(Query::parsed_time) else {
+            return;
+        };
+        if self.newest != Some(newest) {
+            self.newest = Some(newest);
+            self.newest_keys.clear();
+        }
+        let at_newest = queries.iter().take_while(|query| query.parsed_time() == Some(newest));
+        self.newest_keys.extend(at_newest.map(Query::key));
+    }
+}
+
+impl Query {
+    /// When the query was made, if AdGuard gave a valid time
+    pub fn parsed_time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.time).ok()
+    }
+
+    pub fn key(&self) -> QueryKey {
+        QueryKey {
+            time: self.time.clone(),
+            client: self.client.clone(),
+            name: self.question.name.clone(),
+        }
+    }
+
+    /// Whether AdGuard blocked this query, for any reason (filter list, blocked service, parental control, etc)
+    pub fn is_blocked(&self) -> bool {
+        self.reason.is_blocked()
+    }
+
+    /// Name of the blocked service, if it was blocked by one
+    pub fn blocked_service(&self) -> Option<&str> {
+        self.service_name.as_deref()
+            .filter(|name| !name.is_empty() && self.reason == QueryReason::BlockedService)
+    }
+}
+
+impl AdGuardClient {
+    /// Fetches a page of the query log, from `/control/querylog`, starting with the most recent
+    /// entries, or those older than the given time. Only entries matching the filter are included
+    pub async fn fetch_query_log_page(
+        &self,
+        filter: &QueryFilter,
+        older_than: Option<&str>,
+        limit: usize,
+    ) -> Result<QueryResponse, AdGuardError> {
+        let mut params = filter.params();
+        params.push(("limit", limit.to_string()));
+        if let Some(older_than) = older_than {
+            params.push(("older_than", older_than.to_string()));
+        }
+        self.get_json("/control/querylog", &params).await
+    }
+
+    /// Fetches every entry in the query log that matches the filter, and is newer than the cursor (newest first),
+    /// paging back until reaching entries that were already fetched.
+    /// If nothing's been fetched yet, only the most recent page is fetched.
+    /// If there were too many new entries to catch up on, the oldest one fetched is marked with `gap_before`
+    pub async fn fetch_new_queries(&self, filter: &QueryFilter, cursor: &QueryCursor) -> Result<Vec<Query>, AdGuardError> {
+        fetch_new_pages(cursor, |older_than, limit| async move {
+            self.fetch_query_log_page(filter, older_than.as_deref(), limit).await
+        }).await
+    }
+}
+
+/// Pages back through the query log with `fetch_page(older_than, limit)`, for `fetch_new_queries`.
+/// Polls start with a small page, and only fetch full pages if that doesn't reach the cursor
+async fn fetch_new_pages<F, Fut>(cursor: &QueryCursor, mut fetch_page: F) -> Result<Vec<Query>, AdGuardError>
+where
+    F: FnMut(Option<String>, usize) -> Fut,
+    Fut: Future<Output = Result<QueryResponse, AdGuardError>>,
+{
+    let mut new_queries = Vec::new();
+    let mut older_than: Option<String> = None;
+    let mut limit = if cursor.newest.is_some() { POLL_PAGE_SIZE } else { PAGE_SIZE };
+    for _ in 0..MAX_PAGES {
+        let page = fetch_page(older_than.take(), limit).await?;
+        let full_page = page.data.len() >= limit;
+        let caught_up = page.data.iter().any(|query| cursor.is_behind(query));
+        new_queries.extend(
+            page.data.into_iter()
+                .take_while(|query| !cursor.is_behind(query))
+                .filter(|query| cursor.is_new(query)),
+        );
+        if caught_up || !full_page || page.oldest.is_empty() || cursor.newest.is_none() {
+            return Ok(new_queries);
+        }
+        older_than = Some(page.oldest);
+        limit = PAGE_SIZE;
+    }
+    // Gave up before reaching what was already fetched, so the entries in between are never seen
+    if let Some(oldest) = new_queries.last_mut() {
+        oldest.gap_before = true;
+    }
+    Ok(new_queries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A query log of `count` entries, one a second, newest first
+    fn query_log(count: u32) -> Vec<Query> {
+        (0..count).rev()
+            .map(|i| serde_json::from_value(json!({
+                "cached": false,
+                "client": "10.0.0.2",
+                "upstream": "1.1.1.1",
+                "elapsedMs": "1.2",
+                "question": { "class": "IN", "name": format!("d{}.example.com", i), "type": "A" },
+                "reason": "NotFilteredNotFound",
+                "time": format!("2024-01-01T{:02}:{:02}:{:02}Z", i / 3600, i / 60 % 60, i % 60),
+            })).unwrap())
+            .collect()
+    }
+
+    /// Fetches new entries from the log as AdGuard would page through it, recording the `limit` of each request
+    async fn fetch_new(log: &[Query], cursor: &QueryCursor) -> (Vec<Query>, Vec<usize>) {
+        let mut limits = Vec::new();
+        let new_queries = fetch_new_pages(cursor, |older_than, limit| {
+            limits.push(limit);
+            let data: Vec<Query> = log.iter()
+                .filter(|query| older_than.as_ref().is_none_or(|older_than| &query.time < older_than))
+                .take(limit)
+                .cloned()
+                .collect();
+            let oldest = data.last().map(|query| query.time.clone()).unwrap_or_default();
+            async move { Ok(QueryResponse { data, oldest }) }
+        }).await.unwrap();
+        (new_queries, limits)
+    }
+
+    #[tokio::test]
+    async fn caught_up_poll_makes_one_small_request() {
+        let log = query_log(1000);
+        let mut cursor = QueryCursor::default();
+        let (first, limits) = fetch_new(&log, &cursor).await;
+        assert_eq!((first.len(), limits), (PAGE_SIZE, vec![PAGE_SIZE]));
+        cursor.advance(&first);
+
+        let (new_queries, limits) = fetch_new(&log, &cursor).await;
+        assert!(new_queries.is_empty());
+        assert_eq!(limits, vec![POLL_PAGE_SIZE]);
+    }
+
+    #[tokio::test]
+    async fn poll_pages_back_when_many_entries_are_new() {
+        let log = query_log(1000);
+        let mut cursor = QueryCursor::default();
+        cursor.advance(&log[600..]);
+
+        let (new_queries, limits) = fetch_new(&log, &cursor).await;
+        assert_eq!(new_queries.len(), 600);
+        assert!(new_queries.iter().all(|query| !query.gap_before));
+        assert_eq!(limits, vec![POLL_PAGE_SIZE, PAGE_SIZE, PAGE_SIZE]);
+    }
+}
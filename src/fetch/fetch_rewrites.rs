@@ -0,0 +1,29 @@
+//! DNS rewrites, which answer queries for a domain with a fixed IP address or another domain
+
+use serde::{Deserialize, Serialize};
+
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+
+/// A single rewrite. The domain may start with `*.` to match subdomains
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Rewrite {
+    pub domain: String,
+    pub answer: String,
+}
+
+impl AdGuardClient {
+    /// Fetches every rewrite, from `/control/rewrite/list`
+    pub async fn fetch_rewrites(&self) -> Result<Vec<Rewrite>, AdGuardError> {
+        self.get_json("/control/rewrite/list", &[]).await
+    }
+
+    /// Adds a rewrite, via `/control/rewrite/add`
+    pub async fn add_rewrite(&self, rewrite: &Rewrite) -> Result<(), AdGuardError> {
+        self.post_json("/control/rewrite/add", rewrite).await
+    }
+
+    /// Removes a rewrite, via `/control/rewrite/delete`. Both the domain and answer must match
+    pub async fn remove_rewrite(&self, rewrite: &Rewrite) -> Result<(), AdGuardError> {
+        self.post_json("/control/rewrite/delete", rewrite).await
+    }
+}
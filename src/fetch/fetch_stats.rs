@@ -1,21 +1,52 @@
-/// This module fetches data from AdGuard's stats API
-/// This includes total number of blocked / allowed queries in each category,
-/// and 30-day query count history
+//! This module fetches data from AdGuard's stats API
+//! This includes total number of blocked / allowed queries in each category,
+//! and query count history, which is either hourly or daily, depending on AdGuard's stats interval
 
-use reqwest::{
-  header::{HeaderValue, CONTENT_LENGTH, AUTHORIZATION},
-};
-use serde::Deserialize;
+use chrono::{Duration, Local, NaiveDateTime, NaiveTime, Timelike};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::fetch::client::{AdGuardClient, AdGuardError};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DomainData {
     pub name: String,
     pub count: i32,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// How long each point in the query count history covers.
+/// AdGuard reports hours when its stats interval is a day, otherwise days
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeUnit {
+    Hours,
+    #[default]
+    Days,
+}
+
+impl TimeUnit {
+    pub fn duration(self) -> Duration {
+        match self {
+            TimeUnit::Hours => Duration::hours(1),
+            TimeUnit::Days => Duration::days(1),
+        }
+    }
+
+    /// Start of the current hour or day, in local time. This is the last point in the history
+    pub fn current_start(self) -> NaiveDateTime {
+        let now = Local::now().naive_local();
+        match self {
+            TimeUnit::Hours => now.date().and_time(NaiveTime::from_hms_opt(now.hour(), 0, 0).unwrap_or(NaiveTime::MIN)),
+            TimeUnit::Days => now.date().and_time(NaiveTime::MIN),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StatsResponse {
+    /// Older versions of AdGuard don't include this, and only report days
+    #[serde(default)]
+    pub time_units: TimeUnit,
     pub num_dns_queries: u64,
     pub num_blocked_filtering: u64,
     pub num_replaced_safebrowsing: u64,
@@ -27,11 +58,13 @@ pub struct StatsResponse {
     pub replaced_safebrowsing: Vec<u64>,
     pub replaced_parental: Vec<u64>,
 
-    #[serde(default, skip_deserializing)]
+    #[serde(default, skip)]
     pub dns_queries_chart: Vec<(f64, f64)>,
-    #[serde(default, skip_deserializing)]
+    #[serde(default, skip)]
     pub blocked_filtering_chart: Vec<(f64, f64)>,
 
+    // Top lists are serialized as {"name": ..., "count": ...} objects, rather than AdGuard's
+    // single-key maps, so that they're easier to consume from other tools
     #[serde(rename = "top_queried_domains", deserialize_with = "deserialize_domains")]
     pub top_queried_domains: Vec<DomainData>,
     #[serde(rename = "top_blocked_domains", deserialize_with = "deserialize_domains")]
@@ -40,26 +73,11 @@ pub struct StatsResponse {
     pub top_clients: Vec<DomainData>,
 }
 
-pub async fn fetch_adguard_stats(
-    client: &reqwest::Client,
-    endpoint: &str,
-    username: &str,
-    password: &str,
-) -> Result<StatsResponse, anyhow::Error> {
-    let auth_string = format!("{}:{}", username, password);
-    let auth_header_value = format!("Basic {}", base64::encode(&auth_string));
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(AUTHORIZATION, auth_header_value.parse()?);
-    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
-
-    let url = format!("{}/control/stats", endpoint);
-    let response = client.get(&url).headers(headers).send().await?;
-    if !response.status().is_success() {
-        return Err(anyhow::anyhow!("Request failed with status code {}", response.status()));
+impl AdGuardClient {
+    /// Fetches query counts, history and top lists, from `/control/stats`
+    pub async fn fetch_stats(&self) -> Result<StatsResponse, AdGuardError> {
+        self.get_json("/control/stats", &[]).await
     }
-
-    let data = response.json().await?;
-    Ok(data)
 }
 
 /// Deserialize a list of domains from the JSON data
@@ -76,3 +94,38 @@ where
         .collect())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn top_lists_are_output_as_name_and_count() {
+        let stats: StatsResponse = serde_json::from_value(json!({
+            "num_dns_queries": 10,
+            "num_blocked_filtering": 2,
+            "num_replaced_safebrowsing": 0,
+            "num_replaced_safesearch": 0,
+            "num_replaced_parental": 0,
+            "avg_processing_time": 0.002,
+            "dns_queries": [4, 6],
+            "blocked_filtering": [1, 1],
+            "replaced_safebrowsing": [0, 0],
+            "replaced_parental": [0, 0],
+            "top_queried_domains": [{ "example.com": 6 }, { "example.org": 4 }],
+            "top_blocked_domains": [{ "ads.example.com": 2 }],
+            "top_clients": [],
+        })).unwrap();
+        // Older versions don't send the time units, and always report days
+        assert_eq!(stats.time_units, TimeUnit::Days);
+
+        let output = serde_json::to_value(&stats).unwrap();
+        assert_eq!(output["top_queried_domains"], json!([
+            { "name": "example.com", "count": 6 },
+            { "name": "example.org", "count": 4 },
+        ]));
+        assert_eq!(output["top_blocked_domains"], json!([{ "name": "ads.example.com", "count": 2 }]));
+        assert_eq!(output["top_clients"], json!([]));
+    }
+}
@@ -1,7 +1,7 @@
-use reqwest::{
-  header::{HeaderValue, CONTENT_LENGTH, AUTHORIZATION},
-};
-use serde::Deserialize;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::fetch::client::{AdGuardClient, AdGuardError};
 
 /// Represents the status response from the AdGuard Home API.
 ///
@@ -13,11 +13,8 @@ use serde::Deserialize;
 /// A `StatusResponse` is typically obtained like this:
 ///
 /// ```
-/// let client = reqwest::Client::new();
-/// let hostname = "http://localhost:3000";
-/// let username = "username";
-/// let password = "password";
-/// let status = fetch_adguard_status(&client, &hostname, &username, &password).await?;
+/// let client = AdGuardClient::new("http://localhost:3000", "username", "password")?;
+/// let status = client.fetch_status().await?;
 /// println!("AdGuard Status: {:?}", status);
 /// ```
 ///
@@ -28,11 +25,11 @@ use serde::Deserialize;
 /// * `dns_addresses` - The DNS addresses used by the AdGuard Home instance.
 /// * `dns_port` - The port number on which the DNS server is running.
 /// * `http_port` - The port number on which the HTTP server is running.
-/// * `protection_disabled_duration` - The duration for which protection is disabled (in seconds).
+/// * `protection_disabled_duration` - How much longer protection will stay disabled for (in milliseconds), or 0 if indefinitely.
 /// * `protection_enabled` - Whether or not protection is currently enabled.
 /// * `dhcp_available` - Whether or not DHCP is available.
 /// * `running` - Whether or not the AdGuard Home instance is currently running.
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StatusResponse {
     pub version: String,
     pub language: String,
@@ -46,51 +43,45 @@ pub struct StatusResponse {
 }
 
 
-/// Fetches the current status from the AdGuard Home instance.
-///
-/// This function sends a GET request to the `/control/status` endpoint of the 
-/// AdGuard Home API, then deserializes the JSON response into a `StatusResponse`.
-///
-/// # Arguments
-///
-/// * `client` - A reference to the `reqwest::Client`.
-/// * `hostname` - The hostname of the AdGuard Home instance.
-/// * `username` - The username for the AdGuard Home instance.
-/// * `password` - The password for the AdGuard Home instance.
-///
-/// # Returns
-///
-/// A `Result` which is `Ok` if the status was successfully fetched and `Err` otherwise.
-/// The `Ok` variant contains a `StatusResponse`.
-///
-/// # Example
-///
-/// ```
-/// let client = reqwest::Client::new();
-/// let hostname = "http://localhost:80";
-/// let username = "username";
-/// let password = "password";
-/// let status = fetch_adguard_status(&client, &hostname, &username, &password).await?;
-/// println!("AdGuard Status: {:?}", status);
-/// ```
-pub async fn fetch_adguard_status(
-    client: &reqwest::Client,
-    endpoint: &str,
-    username: &str,
-    password: &str,
-) -> Result<StatusResponse, anyhow::Error> {
-    let auth_string = format!("{}:{}", username, password);
-    let auth_header_value = format!("Basic {}", base64::encode(&auth_string));
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(AUTHORIZATION, auth_header_value.parse()?);
-    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+/// Body for the `/control/protection` endpoint.
+/// A duration (in milliseconds) can only be given when disabling protection,
+/// after which AdGuard will re-enable it automatically
+#[derive(Serialize)]
+struct ProtectionRequest {
+    enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<u64>,
+}
 
-    let url = format!("{}/control/status", endpoint);
-    let response = client.get(&url).headers(headers).send().await?;
-    if !response.status().is_success() {
-        return Err(anyhow::anyhow!("Request failed with status code {}", response.status()));
+impl AdGuardClient {
+    /// Fetches the current status from the AdGuard Home instance.
+    ///
+    /// This sends a GET request to the `/control/status` endpoint of the
+    /// AdGuard Home API, then deserializes the JSON response into a `StatusResponse`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` which is `Ok` if the status was successfully fetched and `Err` otherwise.
+    /// The `Ok` variant contains a `StatusResponse`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let client = AdGuardClient::new("http://localhost:80", "username", "password")?;
+    /// let status = client.fetch_status().await?;
+    /// println!("AdGuard Status: {:?}", status);
+    /// ```
+    pub async fn fetch_status(&self) -> Result<StatusResponse, AdGuardError> {
+        self.get_json("/control/status", &[]).await
     }
 
-    let data = response.json().await?;
-    Ok(data)
+    /// Enables or disables protection. If disabling, and a duration is given,
+    /// AdGuard will turn protection back on once that time has passed
+    pub async fn set_protection(&self, enabled: bool, duration: Option<Duration>) -> Result<(), AdGuardError> {
+        let request = ProtectionRequest {
+            enabled,
+            duration: duration.filter(|_| !enabled).map(|d| d.as_millis() as u64),
+        };
+        self.post_json("/control/protection", &request).await
+    }
 }
@@ -1,4 +1,8 @@
+pub mod client;
 pub mod fetch_query_log;
 pub mod fetch_status;
 pub mod fetch_stats;
 pub mod fetch_filters;
+pub mod fetch_rewrites;
+pub mod fetch_blocked_services;
+pub mod fetch_clients;
@@ -0,0 +1,351 @@
+//! Optional on-disk history, in an SQLite database, so the dashboard can show more than
+//! AdGuard itself keeps, and remembers it between runs.
+//!
+//! Two things are recorded by the fetch loop:
+//! - Every query log entry that's fetched, used for the top lists, and to fill the query log on startup
+//! - The counts from each bucket of AdGuard's stats (one per hour or day), used for the history chart.
+//!   Each bucket is overwritten with the latest counts, until it's fallen out of AdGuard's stats period
+//!
+//! Anything older than the retention period is deleted. Query log entries are fetched for history
+//! even while the dashboard is only showing those matching a search, so none are missed.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+use anyhow::Context;
+use chrono::{DateTime, Local, TimeZone};
+use rusqlite::{params, Connection};
+
+use crate::query_log::ListHits;
+use crate::fetch::{
+    fetch_query_log::{Query, QueryCursor},
+    fetch_stats::{DomainData, StatsResponse, TimeUnit},
+};
+
+/// How often old history is deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Number of entries in each of the top lists
+const TOP_LIST_SIZE: usize = 10;
+
+/// How often the top lists are recounted from saved queries. Counting them means
+/// scanning every saved query, so it's done much less often than stats are fetched
+const TOP_LISTS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS queries (
+        time TEXT NOT NULL,
+        unix INTEGER NOT NULL,
+        client TEXT NOT NULL,
+        domain TEXT NOT NULL,
+        blocked INTEGER NOT NULL,
+        json TEXT NOT NULL,
+        UNIQUE (time, client, domain)
+    );
+    CREATE INDEX IF NOT EXISTS queries_unix ON queries (unix);
+    CREATE TABLE IF NOT EXISTS stats_buckets (
+        bucket_size INTEGER NOT NULL,
+        start INTEGER NOT NULL,
+        dns_queries INTEGER NOT NULL,
+        blocked_filtering INTEGER NOT NULL,
+        replaced_safebrowsing INTEGER NOT NULL,
+        replaced_parental INTEGER NOT NULL,
+        PRIMARY KEY (bucket_size, start)
+    );
+";
+
+pub struct HistoryStore {
+    conn: Connection,
+    retention: Duration,
+    last_pruned: Instant,
+    /// Newest query log entries that have been saved, so the next can be fetched from there
+    cursor: QueryCursor,
+    /// Top lists from the last time they were counted
+    top_lists: Option<TopLists>,
+}
+
+/// Top lists counted from saved queries
+struct TopLists {
+    counted_at: Instant,
+    queried_domains: Vec<DomainData>,
+    blocked_domains: Vec<DomainData>,
+    clients: Vec<DomainData>,
+}
+
+impl HistoryStore {
+    /// Opens (or creates) the database at the given path, and deletes anything past retention
+    pub fn open(path: &Path, retention: Duration) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Unable to create directory {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Unable to open history database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("Unable to set up history database {}", path.display()))?;
+        let mut store = HistoryStore {
+            conn,
+            retention,
+            last_pruned: Instant::now(),
+            cursor: QueryCursor::default(),
+            top_lists: None,
+        };
+        store.prune()?;
+        store.load_cursor()?;
+        Ok(store)
+    }
+
+    /// Starts the cursor from the newest saved entries, so fetching picks up where the last run left off
+    fn load_cursor(&mut self) -> anyhow::Result<()> {
+        let mut select = self.conn.prepare("SELECT json FROM queries WHERE unix = (SELECT MAX(unix) FROM queries)")?;
+        let rows = select.query_map([], |row| row.get::<_, String>(0))?;
+        let mut newest: Vec<Query> = Vec::new();
+        for json in rows {
+            newest.push(serde_json::from_str(&json?)?);
+        }
+        // Entries within the same second are in no particular order
+        newest.sort_by_key(|query| Reverse(query.parsed_time()));
+        self.cursor.advance(&newest);
+        Ok(())
+    }
+
+    /// Where the next fetch for history should pick up from
+    pub fn cursor(&self) -> &QueryCursor {
+        &self.cursor
+    }
+
+    /// Oldest time (in seconds since the epoch) that's kept
+    fn cutoff(&self) -> i64 {
+        Local::now().timestamp() - self.retention.as_secs() as i64
+    }
+
+    /// Deletes anything older than the retention period
+    fn prune(&mut self) -> anyhow::Result<()> {
+        let cutoff = self.cutoff();
+        self.conn.execute("DELETE FROM queries WHERE unix < ?1", [cutoff])?;
+        self.conn.execute("DELETE FROM stats_buckets WHERE start + bucket_size < ?1", [cutoff])?;
+        self.last_pruned = Instant::now();
+        Ok(())
+    }
+
+    fn prune_if_due(&mut self) -> anyhow::Result<()> {
+        if self.last_pruned.elapsed() >= PRUNE_INTERVAL {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Saves newly fetched query log entries (newest first). Any that were already saved are skipped
+    pub fn record_queries(&mut self, queries: &[Query]) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR IGNORE INTO queries (time, unix, client, domain, blocked, json)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for query in queries {
+                let Ok(time) = chrono::DateTime::parse_from_rfc3339(&query.time) else {
+                    continue;
+                };
+                insert.execute(params![
+                    query.time,
+                    time.timestamp(),
+                    query.client,
+                    query.question.name,
+                    query.is_blocked(),
+                    serde_json::to_string(query)?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        self.cursor.advance(queries);
+        self.prune_if_due()
+    }
+
+    /// The most recent saved query log entries, newest first
+    pub fn recent_queries(&self, limit: usize) -> anyhow::Result<Vec<Query>> {
+        let mut select = self.conn.prepare("SELECT json FROM queries ORDER BY unix DESC, rowid DESC LIMIT ?1")?;
+        let rows = select.query_map([limit as i64], |row| row.get::<_, String>(0))?;
+        let mut queries = Vec::new();
+        for json in rows {
+            queries.push(serde_json::from_str(&json?)?);
+        }
+        Ok(queries)
+    }
+
+    /// Time of the oldest saved query, if there are any
+    pub fn oldest_query(&self) -> anyhow::Result<Option<DateTime<Local>>> {
+        let oldest: Option<i64> = self.conn.query_row("SELECT MIN(unix) FROM queries", [], |row| row.get(0))?;
+        Ok(oldest.and_then(|oldest| Local.timestamp_opt(oldest, 0).single()))
+    }
+
+    /// How many saved queries matched a rule from each filter list (and how many were blocked), keyed by list ID
+    pub fn list_hits(&self) -> anyhow::Result<HashMap<i64, ListHits>> {
+        let mut select = self.conn.prepare(
+            "SELECT json_extract(rule.value, '$.filter_list_id') AS list,
+                    COUNT(DISTINCT queries.rowid),
+                    COUNT(DISTINCT CASE WHEN queries.blocked THEN queries.rowid END)
+             FROM queries, json_each(queries.json, '$.rules') AS rule
+             GROUP BY list",
+        )?;
+        let rows = select.query_map([], |row| {
+            let list: Option<i64> = row.get(0)?;
+            let hits = ListHits { matched: row.get::<_, i64>(1)? as u64, blocked: row.get::<_, i64>(2)? as u64 };
+            Ok((list.unwrap_or_default(), hits))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Saves the counts from each bucket of AdGuard's stats. The last bucket is the current hour or day.
+    /// Hourly and daily buckets are kept separately, in case AdGuard's stats interval changes
+    pub fn record_stats(&mut self, stats: &StatsResponse) -> anyhow::Result<()> {
+        let (bucket_size, current) = bucket_bounds(stats.time_units);
+        let tx = self.conn.transaction()?;
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO stats_buckets
+                 (bucket_size, start, dns_queries, blocked_filtering, replaced_safebrowsing, replaced_parental)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            let buckets = stats.dns_queries.len();
+            for i in 0..buckets {
+                let start = current - (buckets - 1 - i) as i64 * bucket_size;
+                let value = |series: &[u64]| series.get(i).copied().unwrap_or_default() as i64;
+                upsert.execute(params![
+                    bucket_size,
+                    start,
+                    value(&stats.dns_queries),
+                    value(&stats.blocked_filtering),
+                    value(&stats.replaced_safebrowsing),
+                    value(&stats.replaced_parental),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Extends AdGuard's stats with what's been saved: the history chart gets every saved bucket
+    /// (with any gaps as zero), and the top lists include counts from saved query log entries
+    pub fn apply_history(&mut self, stats: &mut StatsResponse) -> anyhow::Result<()> {
+        let (bucket_size, current) = bucket_bounds(stats.time_units);
+        let oldest: Option<i64> = self.conn.query_row(
+            "SELECT MIN(start) FROM stats_buckets WHERE bucket_size = ?1",
+            [bucket_size],
+            |row| row.get(0),
+        )?;
+        if let Some(oldest) = oldest.filter(|&oldest| oldest <= current) {
+            let buckets = ((current - oldest) / bucket_size + 1) as usize;
+            let mut series = [vec![0; buckets], vec![0; buckets], vec![0; buckets], vec![0; buckets]];
+            let mut select = self.conn.prepare(
+                "SELECT start, dns_queries, blocked_filtering, replaced_safebrowsing, replaced_parental
+                 FROM stats_buckets WHERE bucket_size = ?1 AND start <= ?2",
+            )?;
+            let mut rows = select.query(params![bucket_size, current])?;
+            while let Some(row) = rows.next()? {
+                let index = ((row.get::<_, i64>(0)? - oldest) / bucket_size) as usize;
+                for (column, values) in series.iter_mut().enumerate() {
+                    values[index] = row.get::<_, i64>(column + 1)?.max(0) as u64;
+                }
+            }
+            let [dns_queries, blocked_filtering, replaced_safebrowsing, replaced_parental] = series;
+            // Only replace AdGuard's own series if the saved history covers more
+            if dns_queries.len() > stats.dns_queries.len() {
+                stats.dns_queries = dns_queries;
+                stats.blocked_filtering = blocked_filtering;
+                stats.replaced_safebrowsing = replaced_safebrowsing;
+                stats.replaced_parental = replaced_parental;
+            }
+        }
+
+        let top_lists = self.top_lists()?;
+        merge_top_list(&mut stats.top_queried_domains, &top_lists.queried_domains);
+        merge_top_list(&mut stats.top_blocked_domains, &top_lists.blocked_domains);
+        merge_top_list(&mut stats.top_clients, &top_lists.clients);
+        Ok(())
+    }
+
+    /// The top lists from saved queries, recounted if they're more than `TOP_LISTS_INTERVAL` old
+    fn top_lists(&mut self) -> anyhow::Result<&TopLists> {
+        if self.top_lists.as_ref().is_none_or(|top_lists| top_lists.counted_at.elapsed() >= TOP_LISTS_INTERVAL) {
+            let top = |column: &str, blocked_only: bool| -> anyhow::Result<Vec<DomainData>> {
+                let sql = format!(
+                    "SELECT {column}, COUNT(*) FROM queries {} GROUP BY {column} ORDER BY 2 DESC LIMIT ?1",
+                    if blocked_only { "WHERE blocked" } else { "" },
+                );
+                let mut select = self.conn.prepare(&sql)?;
+                let rows = select.query_map([TOP_LIST_SIZE as i64], |row| {
+                    Ok(DomainData { name: row.get(0)?, count: row.get(1)? })
+                })?;
+                Ok(rows.collect::<Result<_, _>>()?)
+            };
+            self.top_lists = Some(TopLists {
+                counted_at: Instant::now(),
+                queried_domains: top("domain", false)?,
+                blocked_domains: top("domain", true)?,
+                clients: top("client", false)?,
+            });
+        }
+        Ok(self.top_lists.as_ref().expect("top lists were just counted"))
+    }
+}
+
+/// Combines one of AdGuard's top lists with one counted from saved history.
+/// Each covers a different period, so the higher of the two counts is used for each entry
+fn merge_top_list(list: &mut Vec<DomainData>, saved: &[DomainData]) {
+    let size = list.len().max(TOP_LIST_SIZE);
+    let mut counts: HashMap<String, i32> = list.drain(..).map(|item| (item.name, item.count)).collect();
+    for item in saved {
+        let count = counts.entry(item.name.clone()).or_default();
+        *count = (*count).max(item.count);
+    }
+    let mut merged: Vec<DomainData> = counts.into_iter().map(|(name, count)| DomainData { name, count }).collect();
+    merged.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    merged.truncate(size);
+    *list = merged;
+}
+
+/// Size of each bucket in seconds, and the start of the current one, as seconds since the epoch
+/// (treating local time as if it were UTC, so every bucket is exactly the same length, even when the clocks change)
+fn bucket_bounds(unit: TimeUnit) -> (i64, i64) {
+    (unit.duration().num_seconds(), unit.current_start().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn query(name: &str, seconds_ago: i64) -> Query {
+        serde_json::from_value(json!({
+            "cached": false,
+            "client": "10.0.0.1",
+            "upstream": "",
+            "elapsedMs": "1.0",
+            "question": { "class": "IN", "name": name, "type": "A" },
+            "reason": "NotFilteredNotFound",
+            "time": (Local::now() - chrono::Duration::seconds(seconds_ago)).to_rfc3339(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cursor_picks_up_from_saved_queries() {
+        let path = std::env::temp_dir().join(format!("adguardian-history-{}.db", std::process::id()));
+        let retention = Duration::from_secs(60 * 60);
+        let mut store = HistoryStore::open(&path, retention).unwrap();
+        assert!(*store.cursor() == QueryCursor::default());
+
+        store.record_queries(&[query("b.example.com", 10), query("a.example.com", 20)]).unwrap();
+        assert!(*store.cursor() != QueryCursor::default());
+
+        let reopened = HistoryStore::open(&path, retention).unwrap();
+        let matches = *reopened.cursor() == *store.cursor();
+        fs::remove_file(&path).unwrap();
+        assert!(matches);
+    }
+}
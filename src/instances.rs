@@ -0,0 +1,348 @@
+//! Showing several AdGuard instances (e.g. a primary and its secondary) on one dashboard.
+//!
+//! Each instance has its own fetcher, publishing to its own set of channels, exactly as when
+//! there's only one. These are then combined into the set of channels the UI reads from,
+//! according to the selected `View`: either everything added together, or a single instance.
+//! When combined, stats are summed, and query logs are merged by time (each entry tagged with
+//! the instance it came from), with only new entries merged each time. Filters and status come from
+//! the first instance, though protection only shows as on if it's on for all of them.
+
+use std::{collections::HashMap, sync::Arc};
+use futures::future::select_all;
+use tokio::sync::{watch, Notify};
+
+use crate::Senders;
+use crate::connection::ConnectionState;
+use crate::fetch::{
+    client::AdGuardClient,
+    fetch_filters::{AdGuardFilteringStatus, USER_RULES_ID},
+    fetch_query_log::Query,
+    fetch_stats::{DomainData, StatsResponse},
+    fetch_status::StatusResponse,
+};
+use crate::query_log::{merge_newest_first, ListHits, LogVersion};
+use crate::ui::Receivers;
+
+/// An instance being shown on the dashboard, which actions can be run against
+#[derive(Clone)]
+pub struct Instance {
+    pub name: String,
+    pub client: AdGuardClient,
+    /// Wakes the instance's fetcher, to fetch again straight away
+    pub refresh: Arc<Notify>,
+}
+
+/// Which instances the dashboard is showing
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum View {
+    /// Every instance, combined
+    #[default]
+    All,
+    /// Just the instance at this index
+    Single(usize),
+}
+
+impl View {
+    /// The next view along, out of the given number of instances, going back to `All` after the last
+    pub fn next(self, count: usize) -> Self {
+        match self {
+            View::All if count > 0 => View::Single(0),
+            View::Single(index) if index + 1 < count => View::Single(index + 1),
+            _ => View::All,
+        }
+    }
+
+    /// Whether the instance at the given index is part of this view
+    pub fn includes(self, index: usize) -> bool {
+        self == View::All || self == View::Single(index)
+    }
+}
+
+/// The latest state of an instance, for its row in the instances bar
+#[derive(Clone)]
+pub struct InstanceSummary {
+    pub name: String,
+    pub connection: ConnectionState,
+    pub status: Option<StatusResponse>,
+}
+
+/// What's been merged from the query logs, so next time, only what's been added since needs merging
+struct MergedLogs {
+    view: View,
+    /// Version of each log in view, when they were merged
+    versions: Vec<LogVersion>,
+}
+
+/// Waits until any of an instance's channels changes, failing if its fetcher has gone away
+async fn any_changed(rx: &mut Receivers) -> Result<(), watch::error::RecvError> {
+    tokio::select! {
+        changed = rx.queries.changed() => changed,
+        changed = rx.stats.changed() => changed,
+        changed = rx.status.changed() => changed,
+        changed = rx.filters.changed() => changed,
+        changed = rx.connection.changed() => changed,
+    }
+}
+
+/// Combines each instance's data into `senders` (which the UI reads from), whenever any of it,
+/// or the selected view, changes. Runs until the UI or any of the fetchers go away
+pub async fn combine(
+    mut instances: Vec<(String, Receivers)>,
+    mut view: watch::Receiver<View>,
+    senders: Senders,
+    summaries: watch::Sender<Vec<InstanceSummary>>,
+) {
+    let names: Vec<Arc<str>> = instances.iter().map(|(name, _)| Arc::from(name.as_str())).collect();
+    let mut merged: Option<MergedLogs> = None;
+    loop {
+        let selected = *view.borrow_and_update();
+        let in_view = || instances.iter().enumerate()
+            .filter(move |(index, _)| selected.includes(*index))
+            .map(|(_, (_, rx))| rx);
+
+        if merge_query_logs(&instances, &names, selected, &senders, &mut merged) {
+            senders.queries.send_modify(|generation| *generation += 1);
+        }
+
+        let stats: Vec<StatsResponse> = in_view().filter_map(|rx| rx.stats.borrow().clone()).collect();
+        senders.stats.send_replace(sum_stats(&stats));
+        let statuses: Vec<StatusResponse> = in_view().filter_map(|rx| rx.status.borrow().clone()).collect();
+        senders.status.send_replace(combine_status(&statuses));
+        let filters: Option<AdGuardFilteringStatus> = in_view().find_map(|rx| rx.filters.borrow().clone());
+        senders.filters.send_replace(filters);
+        let connections: Vec<(&str, ConnectionState)> = instances.iter().enumerate()
+            .filter(|(index, _)| selected.includes(*index))
+            .map(|(_, (name, rx))| (name.as_str(), rx.connection.borrow().clone()))
+            .collect();
+        senders.connection.send_replace(combine_connections(&connections));
+        summaries.send_replace(instances.iter().map(|(name, rx)| InstanceSummary {
+            name: name.clone(),
+            connection: rx.connection.borrow().clone(),
+            status: rx.status.borrow().clone(),
+        }).collect());
+
+        let changed = tokio::select! {
+            changed = view.changed() => changed,
+            (changed, _, _) = select_all(instances.iter_mut().map(|(_, rx)| Box::pin(any_changed(rx)))) => changed,
+        };
+        if changed.is_err() {
+            break;
+        }
+    }
+}
+
+/// Merges the query logs of every instance in view, newest first, tagging each entry with its instance.
+/// Only entries added since the last merge are merged in, unless the view has changed, or a log has been
+/// replaced (e.g. by a new search), in which case they're merged again from scratch.
+/// Hits per filter list are added together, counted since whichever instance started counting first.
+/// Returns whether anything changed
+fn merge_query_logs(
+    instances: &[(String, Receivers)],
+    names: &[Arc<str>],
+    view: View,
+    senders: &Senders,
+    merged: &mut Option<MergedLogs>,
+) -> bool {
+    let in_view: Vec<(usize, &Receivers)> = instances.iter().enumerate()
+        .filter(|(index, _)| view.includes(*index))
+        .map(|(index, (_, rx))| (index, rx))
+        .collect();
+    let logs: Vec<_> = in_view.iter().map(|(index, rx)| (*index, rx.query_log.read().unwrap())).collect();
+    let versions: Vec<LogVersion> = logs.iter().map(|(_, log)| log.version()).collect();
+    let previous = merged.as_ref().filter(|merged| merged.view == view);
+    if previous.is_some_and(|merged| merged.versions == versions) {
+        return false;
+    }
+
+    let added: Option<Vec<usize>> = previous.and_then(|merged| {
+        versions.iter().zip(&merged.versions).map(|(now, then)| now.added_since(*then)).collect()
+    });
+    let mut combined = senders.query_log.write().unwrap();
+    match added {
+        Some(added) => {
+            let new_entries = logs.iter().zip(added)
+                .map(|((index, log), added)| tagged(&names[*index], &log.as_slice()[..added.min(log.as_slice().len())]))
+                .collect();
+            combined.insert_entries(merge_newest_first(new_entries, usize::MAX));
+        }
+        None => {
+            let loaded: Vec<_> = logs.iter()
+                .filter_map(|(index, log)| Some(tagged(&names[*index], log.loaded()?)))
+                .collect();
+            if !loaded.is_empty() {
+                let capacity = combined.capacity();
+                combined.set_entries(merge_newest_first(loaded, capacity));
+            }
+        }
+    }
+
+    let filters: Vec<_> = in_view.iter().map(|(_, rx)| rx.filters.borrow()).collect();
+    let shown = filters.iter().find_map(|filters| filters.as_ref());
+    let hits: Vec<_> = logs.iter().zip(&filters)
+        .map(|((_, log), filters)| (log.rule_hits(), filters.as_ref()))
+        .collect();
+    let since = logs.iter().map(|(_, log)| log.hits_since()).min();
+    if let Some(since) = since {
+        combined.set_rule_hits(sum_rule_hits(&hits, shown), since);
+    }
+    *merged = Some(MergedLogs { view, versions });
+    true
+}
+
+/// Copies entries from an instance's query log, tagged with the instance's name
+fn tagged<'a>(name: &Arc<str>, queries: &'a [Query]) -> impl Iterator<Item = Query> + 'a {
+    let name = Arc::clone(name);
+    queries.iter().map(move |query| {
+        let mut query = query.clone();
+        query.instance = Some(Arc::clone(&name));
+        query
+    })
+}
+
+/// Adds up the hits per filter list from several instances. Each instance numbers its lists differently,
+/// so they're matched up by URL, and counted under the ID that `shown` (the lists on the dashboard) gives them.
+/// Hits on lists that aren't shown are left out
+fn sum_rule_hits(
+    all: &[(&HashMap<i64, ListHits>, Option<&AdGuardFilteringStatus>)],
+    shown: Option<&AdGuardFilteringStatus>,
+) -> HashMap<i64, ListHits> {
+    let shown_ids: HashMap<&str, i64> = shown.into_iter()
+        .flat_map(AdGuardFilteringStatus::lists)
+        .map(|filter| (filter.url.as_str(), filter.id))
+        .collect();
+    let mut total: HashMap<i64, ListHits> = HashMap::new();
+    for (hits, filters) in all {
+        for (&id, list_hits) in hits.iter() {
+            let shown_id = if id == USER_RULES_ID {
+                Some(USER_RULES_ID)
+            } else {
+                filters.and_then(|filters| filters.lists().find(|filter| filter.id == id))
+                    .and_then(|filter| shown_ids.get(filter.url.as_str()).copied())
+            };
+            if let Some(shown_id) = shown_id {
+                let sum = total.entry(shown_id).or_default();
+                sum.matched += list_hits.matched;
+                sum.blocked += list_hits.blocked;
+            }
+        }
+    }
+    total
+}
+
+/// Adds up the stats from several instances. The history is lined up from the most recent point,
+/// and the average processing time is weighted by each instance's number of queries
+fn sum_stats(all: &[StatsResponse]) -> Option<StatsResponse> {
+    let (first, rest) = all.split_first()?;
+    let mut total = first.clone();
+    let mut weighted_time = first.avg_processing_time * first.num_dns_queries as f64;
+    for stats in rest {
+        total.num_dns_queries += stats.num_dns_queries;
+        total.num_blocked_filtering += stats.num_blocked_filtering;
+        total.num_replaced_safebrowsing += stats.num_replaced_safebrowsing;
+        total.num_replaced_safesearch += stats.num_replaced_safesearch;
+        total.num_replaced_parental += stats.num_replaced_parental;
+        weighted_time += stats.avg_processing_time * stats.num_dns_queries as f64;
+        sum_series(&mut total.dns_queries, &stats.dns_queries);
+        sum_series(&mut total.blocked_filtering, &stats.blocked_filtering);
+        sum_series(&mut total.replaced_safebrowsing, &stats.replaced_safebrowsing);
+        sum_series(&mut total.replaced_parental, &stats.replaced_parental);
+        sum_top_list(&mut total.top_queried_domains, &stats.top_queried_domains);
+        sum_top_list(&mut total.top_blocked_domains, &stats.top_blocked_domains);
+        sum_top_list(&mut total.top_clients, &stats.top_clients);
+    }
+    if total.num_dns_queries > 0 {
+        total.avg_processing_time = weighted_time / total.num_dns_queries as f64;
+    }
+    Some(total)
+}
+
+/// Adds one series onto another, lining them up from the end (the current hour or day)
+fn sum_series(total: &mut Vec<u64>, series: &[u64]) {
+    if series.len() > total.len() {
+        let missing = series.len() - total.len();
+        total.splice(0..0, std::iter::repeat_n(0, missing));
+    }
+    let offset = total.len() - series.len();
+    for (sum, value) in total[offset..].iter_mut().zip(series) {
+        *sum += value;
+    }
+}
+
+/// Adds the counts from one top list onto another, keeping it in order, and no longer than the longest
+fn sum_top_list(total: &mut Vec<DomainData>, list: &[DomainData]) {
+    let size = total.len().max(list.len());
+    let mut counts: HashMap<String, i32> = total.drain(..).map(|item| (item.name, item.count)).collect();
+    for item in list {
+        *counts.entry(item.name.clone()).or_default() += item.count;
+    }
+    let mut summed: Vec<DomainData> = counts.into_iter().map(|(name, count)| DomainData { name, count }).collect();
+    summed.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    summed.truncate(size);
+    *total = summed;
+}
+
+/// The first instance's status, except that it's only shown as running,
+/// or with protection on, if that's the case for every instance
+fn combine_status(all: &[StatusResponse]) -> Option<StatusResponse> {
+    let mut status = all.first()?.clone();
+    status.running = all.iter().all(|status| status.running);
+    status.protection_enabled = all.iter().all(|status| status.protection_enabled);
+    Some(status)
+}
+
+/// Connected only once every instance is. Otherwise, the first which is having trouble,
+/// with its name added to the error, so it's clear which it is
+fn combine_connections(all: &[(&str, ConnectionState)]) -> ConnectionState {
+    let retrying = all.iter().find(|(_, connection)| connection.is_stale());
+    if let Some((name, ConnectionState::Retrying { attempt, retry_at, last_success, error })) = retrying {
+        return ConnectionState::Retrying {
+            attempt: *attempt,
+            retry_at: *retry_at,
+            last_success: *last_success,
+            error: format!("{}: {}", name, error),
+        };
+    }
+    let oldest_update = all.iter()
+        .map(|(_, connection)| match connection {
+            ConnectionState::Connected { last_update } => Some(*last_update),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .and_then(|updates| updates.into_iter().min());
+    match oldest_update {
+        Some(last_update) => ConnectionState::Connected { last_update },
+        None => ConnectionState::Connecting,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filters(lists: &[(i64, &str)]) -> AdGuardFilteringStatus {
+        let lists: Vec<_> = lists.iter()
+            .map(|(id, url)| json!({ "id": id, "url": url, "name": url, "rules_count": 1, "enabled": true }))
+            .collect();
+        serde_json::from_value(json!({ "filters": lists })).unwrap()
+    }
+
+    fn hits(counts: &[(i64, u64)]) -> HashMap<i64, ListHits> {
+        counts.iter().map(|&(id, matched)| (id, ListHits { matched, blocked: matched })).collect()
+    }
+
+    #[test]
+    fn rule_hits_are_matched_up_by_url() {
+        let primary = filters(&[(1, "https://a/list.txt"), (2, "https://b/list.txt")]);
+        let secondary = filters(&[(7, "https://b/list.txt"), (8, "https://a/list.txt"), (9, "https://c/list.txt")]);
+        let primary_hits = hits(&[(USER_RULES_ID, 1), (1, 10), (2, 20)]);
+        let secondary_hits = hits(&[(USER_RULES_ID, 2), (7, 5), (8, 3), (9, 100)]);
+
+        let total = sum_rule_hits(&[(&primary_hits, Some(&primary)), (&secondary_hits, Some(&secondary))], Some(&primary));
+        let matched = |id| total.get(&id).map(|hits| hits.matched);
+        assert_eq!(matched(USER_RULES_ID), Some(3));
+        assert_eq!(matched(1), Some(13));
+        assert_eq!(matched(2), Some(25));
+        assert_eq!(total.len(), 3);
+    }
+}
@@ -1,101 +1,343 @@
+mod app;
+mod cli;
+mod commands;
+mod config;
+mod connection;
 mod fetch;
+mod history;
+mod instances;
+mod metrics;
+mod query_log;
+mod rules;
+mod sync;
 mod ui;
 mod widgets;
 mod welcome;
 
-use std::{env, sync::Arc, time::Duration};
-use reqwest::Client;
-use tokio::time::interval;
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use anyhow::Context;
+use chrono::Local;
+use clap::Parser;
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+    time::sleep,
+};
 
-use ui::draw_ui;
+use app::{App, Flash};
+use ui::{draw_ui, Receivers};
+use cli::{Cli, Command};
+use config::{Resolver, Settings};
+use connection::{Backoff, ConnectionState};
+use history::HistoryStore;
+use instances::{Instance, View};
+use query_log::{QueryLog, SharedQueryLog};
 
 use fetch::{
-    fetch_query_log::fetch_adguard_query_log, 
-    fetch_stats::fetch_adguard_stats, 
-    fetch_status::fetch_adguard_status,
-    fetch_filters::fetch_adguard_filter_list
+    client::AdGuardClient,
+    fetch_stats::StatsResponse,
+    fetch_status::StatusResponse,
+    fetch_filters::AdGuardFilteringStatus,
+    fetch_query_log::QueryFilter,
 };
 
-async fn run() -> anyhow::Result<()> {
-
-    // Create a reqwest client
-    let client = Client::new();
-
-    // AdGuard instance details, from env vars (verified in welcome.rs)
-    let ip = env::var("ADGUARD_IP")?;
-    let port = env::var("ADGUARD_PORT")?;
-    let protocol = env::var("ADGUARD_PROTOCOL").unwrap_or("http".to_string());
-    let hostname = format!("{}://{}:{}", protocol, ip, port);
-    let username = env::var("ADGUARD_USERNAME")?;
-    let password = env::var("ADGUARD_PASSWORD")?;
-    
-
-    // Fetch data that doesn't require updates
-    let filters = fetch_adguard_filter_list(&client, &hostname, &username, &password).await?;
-
-    // Open channels for data fetching where updates are required
-    let (queries_tx, queries_rx) = tokio::sync::mpsc::channel(1);
-    let (stats_tx, stats_rx) = tokio::sync::mpsc::channel(1);
-    let (status_tx, status_rx) = tokio::sync::mpsc::channel(1);
-
-    // Create a channel for the UI to notify the fetcher to shutdown
-    let shutdown = Arc::new(tokio::sync::Notify::new());
-
-    // Spawn the UI task, pass data and update channels
-    let draw_ui_task = tokio::spawn(
-        draw_ui(queries_rx, stats_rx, status_rx, filters, Arc::clone(&shutdown))
-    );
-
-    // Get update interval (in seconds)
-    let interval_secs: u64 = env::var("ADGUARD_UPDATE_INTERVAL")
-        .unwrap_or_else(|_| "2".into()).parse()?;
-    let mut interval = interval(Duration::from_secs(interval_secs));
-    
-    // Open loop for fetching data at the specified interval
+/// Senders for each piece of data that the UI displays, plus the state of the connection
+struct Senders {
+    query_log: SharedQueryLog,
+    /// Bumped each time the query log is updated
+    queries: watch::Sender<u64>,
+    stats: watch::Sender<Option<StatsResponse>>,
+    status: watch::Sender<Option<StatusResponse>>,
+    filters: watch::Sender<Option<AdGuardFilteringStatus>>,
+    connection: watch::Sender<ConnectionState>,
+}
+
+/// Opens a channel for each piece of data, starting with the given query log.
+/// These only hold the latest value, so the UI never waits on a backlog
+/// The query log is too big to copy each time, so it's shared, with the channel signalling updates
+fn channels(query_log: QueryLog) -> (Senders, Receivers) {
+    let query_log = Arc::new(RwLock::new(query_log));
+    let (queries_tx, queries_rx) = watch::channel(0);
+    let (stats_tx, stats_rx) = watch::channel(None);
+    let (status_tx, status_rx) = watch::channel(None);
+    let (filters_tx, filters_rx) = watch::channel(None);
+    let (connection_tx, connection_rx) = watch::channel(ConnectionState::Connecting);
+    let senders = Senders {
+        query_log: Arc::clone(&query_log),
+        queries: queries_tx,
+        stats: stats_tx,
+        status: status_tx,
+        filters: filters_tx,
+        connection: connection_tx,
+    };
+    let receivers = Receivers {
+        query_log,
+        queries: queries_rx,
+        stats: stats_rx,
+        status: status_rx,
+        filters: filters_rx,
+        connection: connection_rx,
+    };
+    (senders, receivers)
+}
+
+/// How often filter lists are re-fetched, so changes made elsewhere show up.
+/// They rarely change, so this is much less often than everything else
+const FILTERS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Fetches a fresh copy of all data from AdGuard, and publishes it to the UI.
+/// Only new entries are fetched from the query log, which are added to what's already held.
+/// Filters are only fetched if `fetch_filters` is set, or they've not been received yet.
+/// If there's a history store, new queries and stats are saved to it, and stats are extended from it.
+/// Problems with history are shown in `flash`, rather than failing the fetch, as AdGuard was still reached
+async fn fetch_all(
+    client: &AdGuardClient,
+    senders: &Senders,
+    query_filter: &watch::Receiver<QueryFilter>,
+    history: &mut Option<HistoryStore>,
+    flash: &mpsc::UnboundedSender<Flash>,
+    fetch_filters: bool,
+) -> anyhow::Result<()> {
+    if fetch_filters || senders.filters.borrow().is_none() {
+        let filters = client.fetch_filters().await?;
+        senders.filters.send_replace(Some(filters));
+    }
+
+    // Apply the latest search from the UI, then fetch anything new that matches.
+    // The lock isn't held while fetching, so the UI can keep drawing from the log meanwhile
+    let (filter, cursor) = {
+        let mut query_log = senders.query_log.write().unwrap();
+        query_log.set_filter(query_filter.borrow().clone());
+        (query_log.filter().clone(), query_log.cursor().clone())
+    };
+    let new_queries = client.fetch_new_queries(&filter, &cursor).await?;
+    if let Some(history) = history.as_mut() {
+        // History needs every entry, so while there's a search, it's fetched separately, without one
+        let unfiltered = if filter.is_empty() {
+            None
+        } else {
+            Some(client.fetch_new_queries(&QueryFilter::default(), history.cursor()).await?)
+        };
+        let saved = tokio::task::block_in_place(|| history.record_queries(unfiltered.as_ref().unwrap_or(&new_queries)));
+        report_history_error(flash, saved.context("Unable to save query log to history"));
+    }
+    senders.query_log.write().unwrap().merge(new_queries);
+    senders.queries.send_modify(|generation| *generation += 1);
+
+    let mut stats = client.fetch_stats().await?;
+    if let Some(history) = history.as_mut() {
+        let saved = tokio::task::block_in_place(|| {
+            history.record_stats(&stats)?;
+            history.apply_history(&mut stats)
+        });
+        report_history_error(flash, saved.context("Unable to save stats to history"));
+    }
+    senders.stats.send_replace(Some(stats));
+
+    let status = client.fetch_status().await?;
+    senders.status.send_replace(Some(status));
+
+    Ok(())
+}
+
+/// Shows an error from saving or reading history, if there was one
+fn report_history_error(flash: &mpsc::UnboundedSender<Flash>, result: anyhow::Result<()>) {
+    if let Err(e) = result {
+        let _ = flash.send(Flash::new(format!("{:#}", e), true));
+    }
+}
+
+/// Fetches data at the specified interval, until the task is aborted.
+/// If AdGuard can't be reached, keep the last good data, and back off before retrying
+/// Filters are also re-fetched after a refresh is requested, as it may have been one of them that changed
+async fn fetch_loop(
+    client: AdGuardClient,
+    senders: Senders,
+    query_filter: watch::Receiver<QueryFilter>,
+    mut history: Option<HistoryStore>,
+    flash: mpsc::UnboundedSender<Flash>,
+    update_interval: Duration,
+    refresh: Arc<Notify>,
+) {
+    let mut backoff = Backoff::default();
+    let mut last_success = None;
+    let mut filters_fetched_at: Option<Instant> = None;
+    let mut refreshed = false;
     loop {
-        tokio::select! {
-            _ = interval.tick() => {
-                let queries = fetch_adguard_query_log(&client, &hostname, &username, &password).await?;
-                if queries_tx.send(queries.data).await.is_err() {
-                    return Err(anyhow::anyhow!("Failed to send query data"));
-                }
-                
-                let stats = fetch_adguard_stats(&client, &hostname, &username, &password).await?;
-                if stats_tx.send(stats).await.is_err() {
-                    return Err(anyhow::anyhow!("Failed to send stats data"));
+        let fetch_filters = refreshed || filters_fetched_at.is_none_or(|at| at.elapsed() >= FILTERS_INTERVAL);
+        let delay = match fetch_all(&client, &senders, &query_filter, &mut history, &flash, fetch_filters).await {
+            Ok(()) => {
+                if fetch_filters {
+                    filters_fetched_at = Some(Instant::now());
                 }
+                backoff.reset();
+                let now = Local::now();
+                last_success = Some(now);
+                senders.connection.send_replace(ConnectionState::Connected { last_update: now });
+                update_interval
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                senders.connection.send_replace(ConnectionState::Retrying {
+                    attempt: backoff.attempt(),
+                    retry_at: Instant::now() + delay,
+                    last_success,
+                    error: format!("{:#}", e),
+                });
+                delay
+            }
+        };
 
-                let status = fetch_adguard_status(&client, &hostname, &username, &password).await?;
-                if status_tx.send(status).await.is_err() {
-                    return Err(anyhow::anyhow!("Failed to send status data"));
-                }
+        refreshed = tokio::select! {
+            _ = sleep(delay) => false,
+            _ = refresh.notified() => true,
+        };
+    }
+}
+
+/// Creates a client for an instance, and starts fetching from it in the background.
+/// Any problems which don't stop it fetching are sent to `flash`
+fn spawn_fetcher(
+    settings: &Settings,
+    query_filter: watch::Receiver<QueryFilter>,
+    flash: mpsc::UnboundedSender<Flash>,
+) -> anyhow::Result<(Instance, Receivers, JoinHandle<()>)> {
+    // Details were verified in welcome.rs
+    let client = settings.client()?;
+    let mut query_log = QueryLog::new(settings.query_log_size);
+
+    // If history is being saved, start with the query log from last time.
+    // New entries are then fetched from where that left off
+    let history = match &settings.history_db {
+        Some(path) => {
+            let store = HistoryStore::open(path, settings.history_retention)?;
+            let saved = store.recent_queries(settings.query_log_size)?;
+            if !saved.is_empty() {
+                query_log.merge(saved);
             }
-            _ = shutdown.notified() => {
-                break;
+            // Hits per filter list are counted over all saved history, rather than just this session
+            if let Some(since) = store.oldest_query()? {
+                query_log.set_rule_hits(store.list_hits()?, since);
             }
+            Some(store)
         }
+        None => None,
+    };
+
+    let (senders, receivers) = channels(query_log);
+    // The UI can ask for a refresh straight away, after changing something
+    let refresh = Arc::new(Notify::new());
+    let task = tokio::spawn(fetch_loop(
+        client.clone(), senders, query_filter, history, flash, settings.update_interval, Arc::clone(&refresh),
+    ));
+    let instance = Instance { name: settings.name.clone(), client, refresh };
+    Ok((instance, receivers, task))
+}
+
+/// Runs the dashboard for one or more instances. The layout and theme come from the first.
+/// With several, their data is combined, and the UI can switch to viewing any one of them
+async fn run(all_settings: Vec<Settings>) -> anyhow::Result<()> {
+    let Some(first) = all_settings.first() else {
+        anyhow::bail!("No instances to show");
+    };
+
+    // The UI sends the search it wants applied to the query log back to the fetchers
+    let (query_filter_tx, query_filter_rx) = watch::channel(QueryFilter::default());
+    // Messages to show in the UI come from both the fetchers and the UI's own actions
+    let (flash_tx, flash_rx) = mpsc::unbounded_channel();
+    let mut tasks = Vec::new();
+    let mut instances = Vec::new();
+    let mut named_receivers = Vec::new();
+    for settings in &all_settings {
+        let (instance, receivers, task) = spawn_fetcher(settings, query_filter_rx.clone(), flash_tx.clone())?;
+        named_receivers.push((instance.name.clone(), receivers));
+        instances.push(instance);
+        tasks.push(task);
     }
 
-    draw_ui_task.await??;
+    // With one instance, the UI reads straight from its fetcher,
+    // otherwise from where they're combined, which also tracks the view to show
+    let (view_tx, view_rx) = watch::channel(View::All);
+    let (summaries_tx, summaries_rx) = watch::channel(Vec::new());
+    let receivers = if named_receivers.len() == 1 {
+        named_receivers.remove(0).1
+    } else {
+        let (senders, receivers) = channels(QueryLog::new(first.query_log_size));
+        tasks.push(tokio::spawn(instances::combine(named_receivers, view_rx, senders, summaries_tx)));
+        receivers
+    };
 
-    Ok(())
+    let app = App::new(instances, view_tx, query_filter_tx, flash_tx, first.theme, first.layout);
+    let result = draw_ui(app, flash_rx, receivers, summaries_rx).await;
+
+    // Once the UI has closed, nothing else needs fetching
+    for task in tasks {
+        task.abort();
+    }
+    result
+}
+
+/// Parses the command line. A leading `--` is skipped, as older versions expected flags after one
+fn parse_cli() -> Cli {
+    let args = std::env::args().enumerate()
+        .filter(|(index, arg)| !(*index == 1 && arg == "--"))
+        .map(|(_, arg)| arg);
+    Cli::parse_from(args)
+}
+
+/// Prints an error (and its causes) then exits, for when running unattended
+fn exit_with_error(e: anyhow::Error) -> ! {
+    eprintln!("Error: {}", welcome::error_chain(e.as_ref()));
+    std::process::exit(1);
 }
 
 fn main() {
+    let cli = parse_cli();
     let rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async {
-        welcome::welcome().await.map_err(|e| {
-            eprintln!("Failed to initialize: {}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, "Failed to initialize")
-        }).unwrap();
-
-        run().await.map_err(|e| {
-            eprintln!("Failed to run: {}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to run: {}", e))
-        }).unwrap_or_else(|e| {
-            eprintln!("Error: {}", e);
-            std::process::exit(1);
-        });        
+        // The exporter and one-shot subcommands don't print the welcome screen or prompt for anything,
+        // so they can be used from scripts and services
+        let unattended = || Resolver::from_args(&cli.global)
+            .and_then(|resolver| resolver.settings_non_interactive())
+            .unwrap_or_else(|e| exit_with_error(e));
+
+        match (cli.command, cli.serve_metrics) {
+            (Some(_), Some(_)) => {
+                eprintln!("Error: --serve-metrics can't be used with a subcommand");
+                std::process::exit(2);
+            }
+            (None, Some(addr)) => {
+                if let Err(e) = metrics::serve_metrics(&unattended(), addr).await {
+                    exit_with_error(e);
+                }
+            }
+            (Some(Command::Sync(args)), None) => {
+                if let Err(e) = sync::run_sync(&cli.global, &args).await {
+                    exit_with_error(e);
+                }
+            }
+            (Some(command), None) if !matches!(command, Command::Dash) => {
+                if let Err(e) = commands::run_command(command, &unattended()).await {
+                    exit_with_error(e);
+                }
+            }
+            _ => {
+                let settings = welcome::welcome(&cli.global).await.map_err(|e| {
+                    eprintln!("Failed to initialize: {}", e);
+                    std::io::Error::other("Failed to initialize")
+                }).unwrap();
+
+                run(settings).await.map_err(|e| {
+                    eprintln!("Failed to run: {}", e);
+                    std::io::Error::other(format!("Failed to run: {}", e))
+                }).unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                });
+            }
+        }
     });
 }
-
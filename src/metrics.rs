@@ -0,0 +1,322 @@
+//! Prometheus exporter mode (`--serve-metrics`). Rather than drawing the dashboard,
+//! AdGuard is polled in the background, and the latest values are served at `/metrics`,
+//! in Prometheus' text exposition format.
+//!
+//! Stats and status are exposed as gauges, as AdGuard only reports totals over its stats period.
+//! Per-upstream and per-client counts are counted from the query log, from when the exporter
+//! started, so are exposed as counters.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use anyhow::Context;
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    server::conn::Http,
+    service::service_fn,
+    Body, Method, Request, Response, StatusCode,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+use crate::config::Settings;
+use crate::connection::Backoff;
+use crate::query_log::QueryLog;
+use crate::fetch::{
+    client::AdGuardClient,
+    fetch_filters::AdGuardFilteringStatus,
+    fetch_query_log::Query,
+    fetch_stats::StatsResponse,
+    fetch_status::StatusResponse,
+};
+
+/// Everything that's exposed at `/metrics`, updated after each poll
+#[derive(Default)]
+struct MetricsState {
+    /// Whether the last poll of AdGuard succeeded
+    up: bool,
+    poll_errors: u64,
+    stats: Option<StatsResponse>,
+    status: Option<StatusResponse>,
+    filters: Option<AdGuardFilteringStatus>,
+    upstream_queries: HashMap<String, u64>,
+    client_queries: HashMap<String, u64>,
+    client_blocked: HashMap<String, u64>,
+    reason_queries: HashMap<String, u64>,
+}
+
+impl MetricsState {
+    /// Counts query log entries that have been added since the previous poll
+    fn count_queries(&mut self, queries: &[Query]) {
+        for query in queries {
+            *self.upstream_queries.entry(query.upstream.clone()).or_default() += 1;
+            *self.client_queries.entry(query.client.clone()).or_default() += 1;
+            *self.reason_queries.entry(query.reason.as_str().to_string()).or_default() += 1;
+            if query.is_blocked() {
+                *self.client_blocked.entry(query.client.clone()).or_default() += 1;
+            }
+        }
+    }
+}
+
+/// Fetches everything from AdGuard, only updating the state once all of it has been received.
+/// The first page of the query log is only used to prime it, as those queries were made before the exporter started
+async fn poll(client: &AdGuardClient, log: &mut QueryLog, state: &Mutex<MetricsState>) -> anyhow::Result<()> {
+    let stats = client.fetch_stats().await?;
+    let status = client.fetch_status().await?;
+    let filters = client.fetch_filters().await?;
+    let primed = log.loaded().is_some();
+    let added = log.fetch(client).await?;
+
+    let mut state = state.lock().unwrap();
+    state.stats = Some(stats);
+    state.status = Some(status);
+    state.filters = Some(filters);
+    if primed {
+        state.count_queries(&log.as_slice()[..added]);
+    }
+    Ok(())
+}
+
+/// Builds the text exposition format, one metric family at a time
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, metric_type);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let labels: Vec<String> = labels.iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect();
+        let _ = if labels.is_empty() {
+            writeln!(self.out, "{} {}", name, value)
+        } else {
+            writeln!(self.out, "{}{{{}}} {}", name, labels.join(","), value)
+        };
+    }
+
+    /// A metric family with a single, unlabelled sample
+    fn single(&mut self, name: &str, metric_type: &str, help: &str, value: f64) {
+        self.family(name, metric_type, help);
+        self.sample(name, &[], value);
+    }
+
+    /// A metric family with one sample per key, e.g. per client
+    fn per_key(&mut self, name: &str, label: &str, help: &str, counts: &HashMap<String, u64>) {
+        self.family(name, "counter", help);
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort();
+        for (key, count) in counts {
+            self.sample(name, &[(label, key)], *count as f64);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn as_gauge(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+/// Renders the current state as Prometheus metrics
+fn render_metrics(state: &MetricsState) -> String {
+    let mut w = MetricsWriter { out: String::new() };
+    w.single("adguard_up", "gauge", "Whether the last poll of AdGuard succeeded", as_gauge(state.up));
+    w.single("adguard_exporter_poll_errors_total", "counter", "Failed polls of AdGuard", state.poll_errors as f64);
+
+    if let Some(status) = &state.status {
+        w.family("adguard_build_info", "gauge", "AdGuard Home version");
+        w.sample("adguard_build_info", &[("version", &status.version)], 1.0);
+        w.single("adguard_running", "gauge", "Whether the DNS server is running", as_gauge(status.running));
+        w.single("adguard_protection_enabled", "gauge", "Whether protection is enabled", as_gauge(status.protection_enabled));
+        w.single(
+            "adguard_protection_disabled_seconds", "gauge",
+            "Seconds until protection is re-enabled, if it was paused",
+            status.protection_disabled_duration as f64 / 1000.0,
+        );
+    }
+
+    if let Some(stats) = &state.stats {
+        let totals = [
+            ("adguard_stats_dns_queries", "DNS queries over the stats period", stats.num_dns_queries),
+            ("adguard_stats_blocked_filtering", "Queries blocked by filter lists over the stats period", stats.num_blocked_filtering),
+            ("adguard_stats_replaced_safebrowsing", "Queries blocked by safe browsing over the stats period", stats.num_replaced_safebrowsing),
+            ("adguard_stats_replaced_safesearch", "Queries rewritten by safe search over the stats period", stats.num_replaced_safesearch),
+            ("adguard_stats_replaced_parental", "Queries blocked by parental control over the stats period", stats.num_replaced_parental),
+        ];
+        for (name, help, value) in totals {
+            w.single(name, "gauge", help, value as f64);
+        }
+        w.single(
+            "adguard_stats_avg_processing_time_seconds", "gauge",
+            "Average time taken to process a query", stats.avg_processing_time,
+        );
+    }
+
+    if let Some(filtering) = &state.filters {
+        let filters = filtering.filters.as_deref().unwrap_or_default();
+        w.family("adguard_filter_rules", "gauge", "Number of rules in each filter list");
+        for filter in filters {
+            w.sample("adguard_filter_rules", &[("name", &filter.name), ("url", &filter.url)], filter.rules_count as f64);
+        }
+        w.family("adguard_filter_enabled", "gauge", "Whether each filter list is enabled");
+        for filter in filters {
+            w.sample("adguard_filter_enabled", &[("name", &filter.name), ("url", &filter.url)], as_gauge(filter.enabled));
+        }
+        let user_rules = filtering.user_rules.iter().filter(|rule| !rule.trim().is_empty()).count();
+        w.single("adguard_user_rules", "gauge", "Number of custom user rules", user_rules as f64);
+    }
+
+    w.per_key("adguard_upstream_queries_total", "upstream", "Queries answered by each upstream, seen in the query log", &state.upstream_queries);
+    w.per_key("adguard_client_queries_total", "client", "Queries made by each client, seen in the query log", &state.client_queries);
+    w.per_key("adguard_client_blocked_total", "client", "Blocked queries made by each client, seen in the query log", &state.client_blocked);
+    w.per_key("adguard_reason_queries_total", "reason", "Queries for each filtering reason (e.g. FilteredSafeBrowsing), seen in the query log", &state.reason_queries);
+    w.out
+}
+
+/// How long a client has to send its request and read the response before it's disconnected,
+/// so clients that connect and then go quiet can't hold connections open
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting connections again, after failing to accept one
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Answers a single HTTP request. Only `GET /metrics` is supported
+fn handle_request(request: &Request<Body>, state: &Mutex<MetricsState>) -> Response<Body> {
+    let (status, content_type, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let body = render_metrics(&state.lock().unwrap());
+            (StatusCode::OK, "text/plain; version=0.0.4; charset=utf-8", body)
+        }
+        (&Method::GET, _) => (StatusCode::NOT_FOUND, "text/plain", "Not found, try /metrics\n".to_string()),
+        _ => (StatusCode::METHOD_NOT_ALLOWED, "text/plain", "Method not allowed\n".to_string()),
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// Serves one request on a connection, then closes it. Connections that haven't
+/// finished within `CONNECTION_TIMEOUT` are dropped
+async fn handle_connection(stream: TcpStream, state: Arc<Mutex<MetricsState>>) -> hyper::Result<()> {
+    let service = service_fn(move |request| {
+        let response = handle_request(&request, &state);
+        async move { Ok::<_, Infallible>(response) }
+    });
+    let connection = Http::new()
+        .http1_only(true)
+        .http1_keep_alive(false)
+        .serve_connection(stream, service);
+    // A client that timed out isn't worth reporting, its connection is just dropped
+    timeout(CONNECTION_TIMEOUT, connection).await.unwrap_or(Ok(()))
+}
+
+/// Polls AdGuard at the update interval, backing off if it can't be reached
+async fn poll_loop(client: AdGuardClient, mut log: QueryLog, state: Arc<Mutex<MetricsState>>, interval: Duration) {
+    let mut backoff = Backoff::default();
+    loop {
+        let delay = match poll(&client, &mut log, &state).await {
+            Ok(()) => {
+                backoff.reset();
+                state.lock().unwrap().up = true;
+                interval
+            }
+            Err(e) => {
+                eprintln!("Failed to poll AdGuard: {:#}", e);
+                let mut state = state.lock().unwrap();
+                state.up = false;
+                state.poll_errors += 1;
+                backoff.next_delay()
+            }
+        };
+        sleep(delay).await;
+    }
+}
+
+/// Runs the exporter until interrupted, serving metrics on the given address
+pub async fn serve_metrics(settings: &Settings, addr: SocketAddr) -> anyhow::Result<()> {
+    let client = settings.client()?;
+    let state = Arc::new(Mutex::new(MetricsState::default()));
+
+    let listener = TcpListener::bind(addr).await
+        .with_context(|| format!("Unable to listen on {}", addr))?;
+    eprintln!("Serving metrics for {} at http://{}/metrics", settings.hostname(), addr);
+
+    let log = QueryLog::new(settings.query_log_size);
+    tokio::spawn(poll_loop(client, log, Arc::clone(&state), settings.update_interval));
+    loop {
+        // Running out of file descriptors, or a client giving up mid-handshake, shouldn't stop the exporter
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, state).await {
+                eprintln!("Failed to serve metrics: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn metrics_are_in_the_text_exposition_format() {
+        let mut state = MetricsState {
+            up: true,
+            status: Some(serde_json::from_value(json!({
+                "version": "v0.107.40",
+                "language": "en",
+                "dns_addresses": [],
+                "dns_port": 53,
+                "http_port": 80,
+                "protection_disabled_duration": 90000,
+                "protection_enabled": false,
+                "dhcp_available": false,
+                "running": true,
+            })).unwrap()),
+            ..Default::default()
+        };
+        state.client_queries.insert("laptop \"work\"\\desk\n".to_string(), 3);
+        state.client_queries.insert("10.0.0.2".to_string(), 5);
+
+        let metrics = render_metrics(&state);
+        let expected = [
+            "# HELP adguard_up Whether the last poll of AdGuard succeeded\n# TYPE adguard_up gauge\nadguard_up 1\n",
+            "adguard_build_info{version=\"v0.107.40\"} 1\n",
+            "adguard_protection_enabled 0\n",
+            "adguard_protection_disabled_seconds 90\n",
+            "# TYPE adguard_client_queries_total counter\n",
+            "adguard_client_queries_total{client=\"10.0.0.2\"} 5\n",
+            "adguard_client_queries_total{client=\"laptop \\\"work\\\"\\\\desk\\n\"} 3\n",
+        ];
+        for lines in expected {
+            assert!(metrics.contains(lines), "{:?} is missing from:\n{}", lines, metrics);
+        }
+        // Nothing's been fetched yet, so there are no stats or filters
+        assert!(!metrics.contains("adguard_stats_dns_queries"));
+    }
+}
@@ -0,0 +1,319 @@
+//! A bounded, in-memory copy of AdGuard's query log. Each fetch only asks AdGuard for entries
+//! newer than those already held, which are added to the front, and once it's full,
+//! the oldest entries are dropped. The dashboard's table, `tail` and the metrics exporter
+//! all read from one of these, rather than from a single page of the log.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    sync::{Arc, RwLock},
+};
+use chrono::{DateTime, FixedOffset, Local};
+
+use crate::fetch::{
+    client::{AdGuardClient, AdGuardError},
+    fetch_query_log::{Query, QueryCursor, QueryFilter},
+};
+
+/// How many entries are kept, if not otherwise specified
+pub const DEFAULT_CAPACITY: usize = 50_000;
+
+/// A query log that's written by the fetcher, and read by the UI
+pub type SharedQueryLog = Arc<RwLock<QueryLog>>;
+
+/// How many queries matched a rule from a filter list, and how many of those were blocked
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ListHits {
+    pub matched: u64,
+    pub blocked: u64,
+}
+
+/// How many entries have been added to a query log, so whatever reads it can tell which entries are new
+/// since it last looked, without going through them all
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct LogVersion {
+    /// Changes whenever entries are replaced, or dropped other than by falling off the end
+    epoch: u64,
+    /// How many entries have been added to the front since the epoch changed
+    added: u64,
+}
+
+impl LogVersion {
+    /// How many entries have been added to the front since an earlier version,
+    /// or `None` if the entries have been replaced since, so should all be read again
+    pub fn added_since(self, earlier: LogVersion) -> Option<usize> {
+        (self.epoch == earlier.epoch).then(|| (self.added - earlier.added) as usize)
+    }
+}
+
+pub struct QueryLog {
+    /// Entries, newest first. Kept contiguous, so they can be borrowed as a slice
+    entries: VecDeque<Query>,
+    capacity: usize,
+    /// Only entries matching this are fetched
+    filter: QueryFilter,
+    cursor: QueryCursor,
+    /// Whether anything has been fetched yet (even if the log was empty)
+    loaded: bool,
+    version: LogVersion,
+    /// How many entries have matched a rule from each filter list, keyed by list ID.
+    /// Counted from every entry fetched without a filter, including those since dropped
+    rule_hits: HashMap<i64, ListHits>,
+    /// Newest entries counted in `rule_hits`. Unlike `cursor`, this isn't reset by a search,
+    /// so entries fetched again once it's cleared aren't counted twice
+    hits_cursor: QueryCursor,
+    /// When counting hits started
+    hits_since: DateTime<Local>,
+}
+
+impl QueryLog {
+    pub fn new(capacity: usize) -> Self {
+        QueryLog {
+            entries: VecDeque::new(),
+            capacity: capacity.max(1),
+            filter: QueryFilter::default(),
+            cursor: QueryCursor::default(),
+            loaded: false,
+            version: LogVersion::default(),
+            rule_hits: HashMap::new(),
+            hits_cursor: QueryCursor::default(),
+            hits_since: Local::now(),
+        }
+    }
+
+    /// Every entry held, newest first
+    pub fn as_slice(&self) -> &[Query] {
+        let (entries, rest) = self.entries.as_slices();
+        debug_assert!(rest.is_empty(), "entries are made contiguous after each merge");
+        entries
+    }
+
+    /// Every entry held (newest first), or `None` if nothing's been fetched yet
+    pub fn loaded(&self) -> Option<&[Query]> {
+        self.loaded.then(|| self.as_slice())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn version(&self) -> LogVersion {
+        self.version
+    }
+
+    /// Where the next fetch should pick up from
+    pub fn cursor(&self) -> &QueryCursor {
+        &self.cursor
+    }
+
+    pub fn filter(&self) -> &QueryFilter {
+        &self.filter
+    }
+
+    pub fn rule_hits(&self) -> &HashMap<i64, ListHits> {
+        &self.rule_hits
+    }
+
+    pub fn hits_since(&self) -> DateTime<Local> {
+        self.hits_since
+    }
+
+    /// Replaces the hit counts, e.g. with those counted from saved history
+    pub fn set_rule_hits(&mut self, hits: HashMap<i64, ListHits>, since: DateTime<Local>) {
+        self.rule_hits = hits;
+        self.hits_since = since;
+    }
+
+    /// Replaces every entry, e.g. with those combined from several instances' logs (newest first).
+    /// Anything past capacity is dropped
+    pub fn set_entries(&mut self, mut entries: Vec<Query>) {
+        entries.truncate(self.capacity);
+        self.entries = entries.into();
+        self.loaded = true;
+        self.new_epoch();
+    }
+
+    /// Adds entries (newest first), e.g. those newly added to several instances' logs, among those already held,
+    /// in order of time. They're usually newer than anything held, so only the front of the log is looked through
+    pub fn insert_entries(&mut self, entries: Vec<Query>) {
+        if entries.is_empty() {
+            return;
+        }
+        let oldest = entries.last().and_then(Query::parsed_time);
+        let mut newer = Vec::new();
+        while self.entries.front().is_some_and(|query| matches!((query.parsed_time(), oldest), (Some(time), Some(oldest)) if time > oldest)) {
+            newer.extend(self.entries.pop_front());
+        }
+        let merged = merge_newest_first(vec![newer.into_iter(), entries.into_iter()], usize::MAX);
+        for query in merged.into_iter().rev() {
+            self.entries.push_front(query);
+        }
+        self.entries.truncate(self.capacity);
+        self.entries.make_contiguous();
+        self.loaded = true;
+        self.new_epoch();
+    }
+
+    /// Marks every entry as replaced, for `version`
+    fn new_epoch(&mut self) {
+        self.version = LogVersion { epoch: self.version.epoch + 1, added: 0 };
+    }
+
+    /// Changes which entries are fetched. As the entries already held may not match,
+    /// they're dropped, and the next fetch starts again from the most recent
+    pub fn set_filter(&mut self, filter: QueryFilter) {
+        if filter == self.filter {
+            return;
+        }
+        self.filter = filter;
+        self.entries.clear();
+        self.cursor = QueryCursor::default();
+        self.loaded = false;
+        self.new_epoch();
+    }
+
+    /// Adds newly fetched entries (newest first) to the front, dropping the oldest entries if it's full.
+    /// Returns how many were added, which will be the first entries of `as_slice`
+    pub fn merge(&mut self, new_queries: Vec<Query>) -> usize {
+        // Having nothing loaded is different from being empty, so readers need to start again
+        if !self.loaded {
+            self.new_epoch();
+        }
+        self.loaded = true;
+        self.cursor.advance(&new_queries);
+        // Searches only fetch some entries, which would skew the counts
+        if self.filter.is_empty() {
+            for query in new_queries.iter().filter(|query| self.hits_cursor.is_new(query)) {
+                let lists: HashSet<i64> = query.rules.iter().map(|rule| rule.filter_list_id).collect();
+                for list in lists {
+                    let hits = self.rule_hits.entry(list).or_default();
+                    hits.matched += 1;
+                    hits.blocked += query.is_blocked() as u64;
+                }
+            }
+            self.hits_cursor.advance(&new_queries);
+        }
+        let added = new_queries.len().min(self.capacity);
+        for query in new_queries.into_iter().take(added).rev() {
+            self.entries.push_front(query);
+        }
+        self.entries.truncate(self.capacity);
+        self.entries.make_contiguous();
+        self.version.added += added as u64;
+        added
+    }
+
+    /// Fetches any new entries from AdGuard and adds them, returning how many were added
+    pub async fn fetch(&mut self, client: &AdGuardClient) -> Result<usize, AdGuardError> {
+        let new_queries = client.fetch_new_queries(&self.filter, &self.cursor).await?;
+        Ok(self.merge(new_queries))
+    }
+}
+
+/// Merges several lists of entries which are each newest first, into one list that's newest first,
+/// stopping after `limit` entries. Entries at the same time are kept in the order of the lists they came from
+pub fn merge_newest_first<I: Iterator<Item = Query>>(lists: Vec<I>, limit: usize) -> Vec<Query> {
+    let mut lists: Vec<_> = lists.into_iter().map(Iterator::peekable).collect();
+    // The newest entry from each list not yet merged, by time, then earliest list first
+    let mut heads: BinaryHeap<(Option<DateTime<FixedOffset>>, Reverse<usize>)> = lists.iter_mut().enumerate()
+        .filter_map(|(index, list)| list.peek().map(|query| (query.parsed_time(), Reverse(index))))
+        .collect();
+    let mut merged = Vec::new();
+    while merged.len() < limit {
+        let Some((_, Reverse(index))) = heads.pop() else {
+            break;
+        };
+        merged.extend(lists[index].next());
+        if let Some(query) = lists[index].peek() {
+            heads.push((query.parsed_time(), Reverse(index)));
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn query(name: &str, time: &str, list: i64) -> Query {
+        serde_json::from_value(json!({
+            "cached": false,
+            "client": "10.0.0.1",
+            "upstream": "",
+            "elapsedMs": "1.0",
+            "question": { "class": "IN", "name": name, "type": "A" },
+            "reason": "FilteredBlackList",
+            "time": time,
+            "rules": [{ "filter_list_id": list, "text": format!("||{}^", name) }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn hits_are_not_recounted_after_a_search() {
+        let mut log = QueryLog::new(100);
+        log.merge(vec![
+            query("b.example.com", "2024-05-01T12:00:02Z", 1),
+            query("a.example.com", "2024-05-01T12:00:01Z", 1),
+        ]);
+
+        // Searching drops the entries, then clearing it fetches the most recent ones again
+        log.set_filter(QueryFilter { search: "a.example".to_string(), ..QueryFilter::default() });
+        log.merge(vec![query("a.example.com", "2024-05-01T12:00:01Z", 1)]);
+        log.set_filter(QueryFilter::default());
+        log.merge(vec![
+            query("c.example.com", "2024-05-01T12:00:03Z", 2),
+            query("b.example.com", "2024-05-01T12:00:02Z", 1),
+            query("a.example.com", "2024-05-01T12:00:01Z", 1),
+        ]);
+
+        let hits = log.rule_hits();
+        assert_eq!((hits[&1].matched, hits[&1].blocked), (2, 2));
+        assert_eq!(hits[&2].matched, 1);
+        assert_eq!(log.as_slice().len(), 3);
+    }
+
+    fn names(queries: &[Query]) -> Vec<&str> {
+        queries.iter().map(|query| query.question.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merging_lists_newest_first() {
+        let first = vec![query("a3", "2024-05-01T12:00:03Z", 1), query("a1", "2024-05-01T12:00:01Z", 1)];
+        let second = vec![query("b3", "2024-05-01T12:00:03Z", 1), query("b2", "2024-05-01T12:00:02Z", 1)];
+        let merged = merge_newest_first(vec![first.clone().into_iter(), second.clone().into_iter()], usize::MAX);
+        assert_eq!(names(&merged), ["a3", "b3", "b2", "a1"]);
+
+        let merged = merge_newest_first(vec![first.into_iter(), second.into_iter()], 2);
+        assert_eq!(names(&merged), ["a3", "b3"]);
+    }
+
+    #[test]
+    fn inserted_entries_are_kept_in_order() {
+        let mut log = QueryLog::new(4);
+        log.set_entries(vec![query("a3", "2024-05-01T12:00:03Z", 1), query("a1", "2024-05-01T12:00:01Z", 1)]);
+        log.insert_entries(vec![
+            query("b4", "2024-05-01T12:00:04Z", 1),
+            query("b2", "2024-05-01T12:00:02Z", 1),
+            query("b0", "2024-05-01T12:00:00Z", 1),
+        ]);
+        assert_eq!(names(log.as_slice()), ["b4", "a3", "b2", "a1"]);
+    }
+
+    #[test]
+    fn version_counts_entries_added() {
+        let mut log = QueryLog::new(100);
+        let empty = log.version();
+        log.merge(vec![query("a1", "2024-05-01T12:00:01Z", 1)]);
+        // Loading the log for the first time replaces having nothing loaded
+        assert_eq!(log.version().added_since(empty), None);
+
+        let loaded = log.version();
+        log.merge(vec![query("a3", "2024-05-01T12:00:03Z", 1), query("a2", "2024-05-01T12:00:02Z", 1)]);
+        assert_eq!(log.version().added_since(loaded), Some(2));
+
+        log.set_filter(QueryFilter { search: "a".to_string(), ..QueryFilter::default() });
+        assert_eq!(log.version().added_since(loaded), None);
+    }
+}
@@ -0,0 +1,292 @@
+//! Helpers for writing AdGuard (Adblock-style) filtering rules for a domain
+
+use std::sync::OnceLock;
+use publicsuffix::{List, Psl};
+
+/// The public suffix list, from https://publicsuffix.org, used to find a hostname's registrable domain.
+/// It includes private suffixes (e.g. github.io), so one site's rules don't cover everyone else's on the same host
+const PUBLIC_SUFFIX_LIST: &str = include_str!("../data/public_suffix_list.dat");
+
+/// The parsed public suffix list, parsed the first time it's needed
+fn public_suffixes() -> &'static List {
+    static LIST: OnceLock<List> = OnceLock::new();
+    LIST.get_or_init(|| PUBLIC_SUFFIX_LIST.parse().expect("the bundled public suffix list is valid"))
+}
+
+/// Which domain a new rule should apply to
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RuleScope {
+    /// Only the exact hostname that was queried
+    ExactHost,
+    /// The registrable parent domain, which covers all of its subdomains
+    ParentDomain,
+}
+
+/// Whether a new rule should block or allow a domain
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Block,
+    Allow,
+}
+
+/// Returns the registrable domain (e.g. example.co.uk) for a hostname (e.g. ads.cdn.example.co.uk).
+/// If the hostname is itself a public suffix, there's no parent domain, so the hostname is returned as is
+pub fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_lowercase();
+    match public_suffixes().domain(host.as_bytes()) {
+        Some(domain) => String::from_utf8_lossy(domain.as_bytes()).into_owned(),
+        None => host,
+    }
+}
+
+/// The domain a rule will target, for the given hostname and scope
+pub fn rule_target(host: &str, scope: RuleScope) -> String {
+    match scope {
+        RuleScope::ExactHost => host.trim_end_matches('.').to_lowercase(),
+        RuleScope::ParentDomain => registrable_domain(host),
+    }
+}
+
+/// Builds the rule to block or allow a domain, and all of its subdomains
+pub fn make_rule(domain: &str, kind: RuleKind) -> String {
+    match kind {
+        RuleKind::Block => format!("||{}^", domain),
+        RuleKind::Allow => format!("@@||{}^", domain),
+    }
+}
+
+/// Whether a rule modifier takes a value (e.g. `$client=192.168.1.2`)
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ModifierValue {
+    None,
+    Required,
+    Optional,
+}
+
+/// Modifiers that AdGuard Home understands in DNS filtering rules (e.g. `$important`)
+const MODIFIERS: &[(&str, ModifierValue)] = &[
+    ("important", ModifierValue::None),
+    ("badfilter", ModifierValue::None),
+    ("client", ModifierValue::Required),
+    ("ctag", ModifierValue::Required),
+    ("denyallow", ModifierValue::Required),
+    // Without a value, in an exception rule, it turns off other rewrites
+    ("dnsrewrite", ModifierValue::Optional),
+    ("dnstype", ModifierValue::Required),
+];
+
+/// Whether a character can appear in a hostname, in hosts file syntax
+fn is_host_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '.' | '-' | '_')
+}
+
+/// Whether a character can appear in the pattern of an Adblock-style rule
+fn is_pattern_char(c: char) -> bool {
+    is_host_char(c) || matches!(c, '*' | '|' | '^' | ':' | '/' | '[' | ']')
+}
+
+/// Splits a rule's modifiers on commas, except those inside a quoted value
+/// (e.g. `client='Frank, laptop'`) or escaped with `\`. Spaces are only allowed inside quotes
+fn split_modifiers(modifiers: &str) -> Result<Vec<&str>, String> {
+    let mut split = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in modifiers.char_indices() {
+        match (c, quote) {
+            _ if escaped => escaped = false,
+            ('\\', _) => escaped = true,
+            (c, Some(open)) if c == open => quote = None,
+            (_, Some(_)) => {}
+            ('\'' | '"', None) => quote = Some(c),
+            (',', None) => {
+                split.push(&modifiers[start..index]);
+                start = index + 1;
+            }
+            (c, None) if c.is_whitespace() => return Err("rules can't contain spaces, except in quoted values".to_string()),
+            _ => {}
+        }
+    }
+    if quote.is_some() {
+        return Err("quoted value isn't closed".to_string());
+    }
+    split.push(&modifiers[start..]);
+    Ok(split)
+}
+
+/// Checks that a custom rule is written in a syntax AdGuard understands: Adblock-style
+/// (`||example.org^$important`), a regular expression (`/ads[0-9]+/`), hosts file syntax
+/// (`0.0.0.0 example.org`), or just a domain. Blank lines and comments are always valid.
+/// This only catches common mistakes, so problems are shown as warnings, rather than stopping rules being saved
+pub fn validate_rule(rule: &str) -> Result<(), String> {
+    let rule = rule.trim();
+    if rule.is_empty() || rule.starts_with('!') || rule.starts_with('#') {
+        return Ok(());
+    }
+
+    let mut words = rule.split_whitespace();
+    if words.next().is_some_and(|first| first.parse::<std::net::IpAddr>().is_ok()) {
+        let hosts: Vec<&str> = words.take_while(|word| !word.starts_with('#')).collect();
+        if hosts.is_empty() {
+            return Err("hosts rule has no hostname after the IP address".to_string());
+        }
+        return match hosts.iter().find(|host| !host.chars().all(is_host_char)) {
+            Some(host) => Err(format!("invalid hostname '{}'", host)),
+            None => Ok(()),
+        };
+    }
+
+    let body = rule.strip_prefix("@@").unwrap_or(rule);
+    let (pattern, modifiers) = if let Some(regex) = body.strip_prefix('/') {
+        // The expression runs up to the last `/`, which may be followed by modifiers
+        let end = regex.rfind("/$").or_else(|| regex.strip_suffix('/').map(str::len));
+        match end {
+            Some(end) if end > 0 && regex[..end].contains(char::is_whitespace) => {
+                return Err("rules can't contain spaces, except in quoted values".to_string());
+            }
+            Some(end) if end > 0 => (None, regex[end + 1..].strip_prefix('$')),
+            _ => return Err("regular expression isn't closed with /".to_string()),
+        }
+    } else {
+        match body.split_once('$') {
+            Some((pattern, modifiers)) => (Some(pattern), Some(modifiers)),
+            None => (Some(body), None),
+        }
+    };
+
+    if let Some(pattern) = pattern {
+        if pattern.is_empty() && modifiers.is_none() {
+            return Err("rule has no pattern".to_string());
+        }
+        if pattern.contains(char::is_whitespace) {
+            return Err("rules can't contain spaces, except in quoted values".to_string());
+        }
+        if let Some(c) = pattern.chars().find(|&c| !is_pattern_char(c)) {
+            return Err(format!("unexpected character '{}' in pattern", c));
+        }
+        let inner = pattern.strip_prefix("||").or_else(|| pattern.strip_prefix('|')).unwrap_or(pattern);
+        if inner.strip_suffix('|').unwrap_or(inner).contains('|') {
+            return Err("| can only be used at the start or end of a pattern".to_string());
+        }
+    }
+
+    for modifier in modifiers.map(split_modifiers).transpose()?.unwrap_or_default() {
+        let (name, value) = match modifier.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (modifier, None),
+        };
+        match MODIFIERS.iter().find(|(known, _)| *known == name) {
+            None => return Err(format!("unknown modifier ${}", name)),
+            Some((_, ModifierValue::Required)) if value.is_none_or(str::is_empty) => {
+                return Err(format!("${} needs a value, e.g. ${}=...", name, name));
+            }
+            Some((_, ModifierValue::None)) if value.is_some() => return Err(format!("${} doesn't take a value", name)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrable_domain_of_a_simple_host() {
+        assert_eq!(registrable_domain("ads.example.com"), "example.com");
+        assert_eq!(registrable_domain("example.com"), "example.com");
+    }
+
+    #[test]
+    fn registrable_domain_with_a_multi_label_suffix() {
+        assert_eq!(registrable_domain("ads.cdn.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("www.example.com.au"), "example.com.au");
+    }
+
+    #[test]
+    fn registrable_domain_with_a_private_suffix() {
+        assert_eq!(registrable_domain("my-site.pages.dev"), "my-site.pages.dev");
+        assert_eq!(registrable_domain("assets.someone.github.io"), "someone.github.io");
+    }
+
+    #[test]
+    fn registrable_domain_ignores_case_and_trailing_dot() {
+        assert_eq!(registrable_domain("Tracker.Example.ORG."), "example.org");
+    }
+
+    #[test]
+    fn registrable_domain_of_a_public_suffix_is_itself() {
+        assert_eq!(registrable_domain("co.uk"), "co.uk");
+        assert_eq!(registrable_domain("github.io"), "github.io");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn registrable_domain_with_an_unlisted_tld() {
+        assert_eq!(registrable_domain("printer.office.lan"), "office.lan");
+    }
+
+    #[test]
+    fn validate_blank_lines_and_comments() {
+        assert!(validate_rule("").is_ok());
+        assert!(validate_rule("! Blocks ads everywhere").is_ok());
+        assert!(validate_rule("# also a comment").is_ok());
+    }
+
+    #[test]
+    fn validate_adblock_rules() {
+        assert!(validate_rule("||example.org^").is_ok());
+        assert!(validate_rule("example.org").is_ok());
+        assert!(validate_rule("|ads.example.org|").is_ok());
+        assert!(validate_rule("||*.example.org^").is_ok());
+        assert!(validate_rule("@@||example.org^").is_ok());
+        assert!(validate_rule("||exa mple.org^").is_err());
+        assert!(validate_rule("||example|org^").is_err());
+        assert!(validate_rule("||example.org^!").is_err());
+        assert!(validate_rule("@@").is_err());
+    }
+
+    #[test]
+    fn validate_hosts_rules() {
+        assert!(validate_rule("0.0.0.0 example.org").is_ok());
+        assert!(validate_rule("127.0.0.1 example.org www.example.org # trackers").is_ok());
+        assert!(validate_rule(":: example.org").is_ok());
+        assert!(validate_rule("0.0.0.0").is_err());
+        assert!(validate_rule("0.0.0.0 exa$mple.org").is_err());
+    }
+
+    #[test]
+    fn validate_regex_rules() {
+        assert!(validate_rule("/ads[0-9]+\\.example\\.org/").is_ok());
+        assert!(validate_rule("@@/^cdn[0-9]+/$important").is_ok());
+        assert!(validate_rule("/ads[0-9]+").is_err());
+        assert!(validate_rule("/ads [0-9]+/").is_err());
+        assert!(validate_rule("//").is_err());
+    }
+
+    #[test]
+    fn validate_modifiers() {
+        assert!(validate_rule("||example.org^$important").is_ok());
+        assert!(validate_rule("||example.org^$badfilter").is_ok());
+        assert!(validate_rule("||example.org^$client=192.168.0.2").is_ok());
+        assert!(validate_rule("||example.org^$ctag=device_phone|device_tablet").is_ok());
+        assert!(validate_rule("*$denyallow=example.org|example.net").is_ok());
+        assert!(validate_rule("||example.org^$dnsrewrite=1.2.3.4").is_ok());
+        assert!(validate_rule("@@||example.org^$dnsrewrite").is_ok());
+        assert!(validate_rule("||example.org^$dnstype=AAAA,important").is_ok());
+        assert!(validate_rule("||example.org^$important=yes").is_err());
+        assert!(validate_rule("||example.org^$client").is_err());
+        assert!(validate_rule("||example.org^$dnstype=").is_err());
+        assert!(validate_rule("||example.org^$third-party").is_err());
+    }
+
+    #[test]
+    fn validate_quoted_client_names() {
+        assert!(validate_rule("||example.org^$client='Frank laptop'").is_ok());
+        assert!(validate_rule("||example.org^$client=\"Frank's laptop\",important").is_ok());
+        assert!(validate_rule("||example.org^$client='Frank, laptop'|'Mary phone'").is_ok());
+        assert!(validate_rule("||example.org^$client=Frank\\,laptop").is_ok());
+        assert!(validate_rule("||example.org^$client=Frank laptop").is_err());
+        assert!(validate_rule("||example.org^$client='Frank laptop").is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! Copying settings from one AdGuard instance to others (e.g. from a primary to its secondary),
+//! so they filter in the same way. Each of these is compared, then changed to match, in turn:
+//! - Filtering settings (whether it's on, and how often lists are updated)
+//! - Blocklists and allowlists, identified by their URL
+//! - Custom rules
+//! - DNS rewrites
+//! - Blocked services, and their schedule
+//! - Persistent clients, identified by their name
+//!
+//! Anything on the target that isn't on the source is removed. With `--dry-run`, the differences
+//! are only printed. If one endpoint fails, the rest are still synced, and the failure is reported at the end,
+//! along with any changes to that section which were made before it failed
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use anyhow::{bail, Context};
+use colored::*;
+
+use crate::cli::{GlobalArgs, SyncArgs};
+use crate::config::Resolver;
+use crate::fetch::{
+    client::{AdGuardClient, AdGuardError},
+    fetch_blocked_services::BlockedServices,
+    fetch_clients::PersistentClient,
+    fetch_filters::{AdGuardFilteringStatus, Filter},
+    fetch_rewrites::Rewrite,
+};
+use crate::welcome::error_chain;
+
+/// A single difference on the target, which syncing resolves
+#[derive(PartialEq, Debug)]
+enum Change {
+    Add(String),
+    Remove(String),
+    Update(String),
+}
+
+/// Changes made to a section, and the error that stopped the rest being made, if there was one.
+/// Changes made before the error have still happened, so are reported along with it
+type SectionResult = (Vec<Change>, Option<AdGuardError>);
+
+impl Change {
+    fn format(&self) -> String {
+        match self {
+            Change::Add(what) => format!("  {} {}", "+".green().bold(), what),
+            Change::Remove(what) => format!("  {} {}", "-".red().bold(), what),
+            Change::Update(what) => format!("  {} {}", "~".yellow().bold(), what),
+        }
+    }
+}
+
+/// A call to the target's API, which resolves one or more changes
+enum Action<'a> {
+    SetFilteringConfig { enabled: bool, interval: u32 },
+    AddFilter(&'a Filter),
+    RemoveFilter(&'a Filter),
+    /// Also renames the list, to the name given
+    SetFilterEnabled(&'a Filter, bool),
+    SetUserRules(&'a [String]),
+    AddRewrite(&'a Rewrite),
+    RemoveRewrite(&'a Rewrite),
+    SetBlockedServices(&'a BlockedServices),
+    AddClient(&'a PersistentClient),
+    UpdateClient(&'a PersistentClient),
+    RemoveClient(&'a str),
+}
+
+impl Action<'_> {
+    async fn run(&self, client: &AdGuardClient) -> Result<(), AdGuardError> {
+        match *self {
+            Action::SetFilteringConfig { enabled, interval } => client.set_filtering_config(enabled, interval).await,
+            Action::AddFilter(filter) => client.add_filter(&filter.name, &filter.url, filter.allowlist).await,
+            Action::RemoveFilter(filter) => client.remove_filter(filter).await,
+            Action::SetFilterEnabled(filter, enabled) => client.set_filter_enabled(filter, enabled).await,
+            Action::SetUserRules(rules) => client.set_user_rules(rules).await,
+            Action::AddRewrite(rewrite) => client.add_rewrite(rewrite).await,
+            Action::RemoveRewrite(rewrite) => client.remove_rewrite(rewrite).await,
+            Action::SetBlockedServices(services) => client.set_blocked_services(services).await,
+            Action::AddClient(wanted) => client.add_client(wanted).await,
+            Action::UpdateClient(wanted) => client.update_client(wanted).await,
+            Action::RemoveClient(name) => client.remove_client(name).await,
+        }
+    }
+}
+
+/// One call to make to the target, and the changes it makes, which are only reported once it's succeeded
+struct Step<'a> {
+    action: Action<'a>,
+    changes: Vec<Change>,
+}
+
+impl<'a> Step<'a> {
+    fn new(action: Action<'a>, change: Change) -> Self {
+        Step { action, changes: vec![change] }
+    }
+}
+
+/// Everything that's synced, as read from one instance
+struct SyncedSettings {
+    filtering: AdGuardFilteringStatus,
+    rewrites: Vec<Rewrite>,
+    blocked_services: BlockedServices,
+    clients: Vec<PersistentClient>,
+}
+
+impl SyncedSettings {
+    async fn fetch(client: &AdGuardClient) -> Result<Self, AdGuardError> {
+        Ok(SyncedSettings {
+            filtering: client.fetch_filters().await?,
+            rewrites: client.fetch_rewrites().await?,
+            blocked_services: client.fetch_blocked_services().await?,
+            clients: client.fetch_clients().await?,
+        })
+    }
+}
+
+/// Creates a client for the instance with the given profile, returning it with the profile's name
+fn connect(args: &GlobalArgs, profile: &str) -> anyhow::Result<(String, AdGuardClient)> {
+    let settings = Resolver::for_profile(args, profile)
+        .and_then(|resolver| resolver.settings_non_interactive())
+        .with_context(|| format!("Unable to load profile '{}'", profile))?;
+    let client = settings.client()?;
+    Ok((settings.name, client))
+}
+
+/// Copies settings from the `--from` instance to each of the `--to` instances
+pub async fn run_sync(args: &GlobalArgs, sync: &SyncArgs) -> anyhow::Result<()> {
+    if sync.to.contains(&sync.from) {
+        bail!("'{}' can't be synced to itself, remove it from --to", sync.from);
+    }
+    let (source_name, source_client) = connect(args, &sync.from)?;
+    // Every profile is loaded first, so a mistake in one doesn't leave the others half synced.
+    // Each is only synced once, even if it's listed more than once
+    let mut seen = HashSet::new();
+    let targets = sync.to.iter()
+        .filter(|profile| seen.insert(profile.as_str()))
+        .map(|profile| connect(args, profile))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let source = SyncedSettings::fetch(&source_client).await
+        .with_context(|| format!("Unable to read settings from {}", source_name))?;
+
+    let mut failed = Vec::new();
+    for (name, client) in targets {
+        let heading = format!("{} → {}", source_name, name);
+        println!("{}{}", heading.bold(), if sync.dry_run { " (dry run)" } else { "" });
+        let target = match SyncedSettings::fetch(&client).await {
+            Ok(target) => target,
+            Err(e) => {
+                println!("  {}\n", format!("Unable to read settings: {}", error_chain(&e)).red());
+                failed.push(name);
+                continue;
+            }
+        };
+        if !sync_instance(&source, &target, &client, sync.dry_run).await {
+            failed.push(name);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Some settings couldn't be synced to {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Syncs every section to one target instance, printing what changed in each.
+/// Returns whether they all succeeded
+async fn sync_instance(source: &SyncedSettings, target: &SyncedSettings, client: &AdGuardClient, dry_run: bool) -> bool {
+    let mut results = Vec::new();
+    for (title, steps) in plan(source, target) {
+        results.push((title, apply(steps, client, dry_run).await));
+    }
+    print!("{}", report(&results, dry_run));
+    results.iter().all(|(_, (_, error))| error.is_none())
+}
+
+/// Works out what needs to change on the target to match the source, for each section in turn
+fn plan<'a>(source: &'a SyncedSettings, target: &'a SyncedSettings) -> Vec<(&'static str, Vec<Step<'a>>)> {
+    vec![
+        ("Filtering settings", plan_filtering(&source.filtering, &target.filtering)),
+        ("Filter lists", plan_filter_lists(&source.filtering, &target.filtering)),
+        ("Custom rules", plan_user_rules(&source.filtering, &target.filtering)),
+        ("Rewrites", plan_rewrites(&source.rewrites, &target.rewrites)),
+        ("Blocked services", plan_blocked_services(&source.blocked_services, &target.blocked_services)),
+        ("Clients", plan_clients(&source.clients, &target.clients)),
+    ]
+}
+
+/// Makes each step's call in turn (unless it's a dry run), stopping at the first that fails
+async fn apply(steps: Vec<Step<'_>>, client: &AdGuardClient, dry_run: bool) -> SectionResult {
+    let mut changes = Vec::new();
+    for step in steps {
+        if !dry_run {
+            if let Err(e) = step.action.run(client).await {
+                return (changes, Some(e));
+            }
+        }
+        changes.extend(step.changes);
+    }
+    (changes, None)
+}
+
+/// Describes what changed (or would change) in each section, then how many changes there were in total
+fn report(results: &[(&str, SectionResult)], dry_run: bool) -> String {
+    let mut lines = Vec::new();
+    for (title, (changes, error)) in results {
+        match (changes.len(), error) {
+            (0, None) => lines.push(format!("{} {}", title.bold(), "(no changes)".dimmed())),
+            (0, Some(e)) => lines.push(format!("{} {}", title.bold(), format!("Failed: {}", error_chain(e)).red())),
+            (count, _) => lines.push(format!("{} {}", title.bold(), format!("({})", count_changes(count)).yellow())),
+        }
+        lines.extend(changes.iter().map(Change::format));
+        if let (false, Some(e)) = (changes.is_empty(), error) {
+            lines.push(format!("  {}", format!("Failed: {}", error_chain(e)).red()));
+        }
+    }
+    let total: usize = results.iter().map(|(_, (changes, _))| changes.len()).sum();
+    let summary = match (total, dry_run) {
+        (0, _) => "Already in sync".green(),
+        (total, true) => format!("{} would be made", count_changes(total)).yellow(),
+        (total, false) => format!("{} made", count_changes(total)).green(),
+    };
+    lines.push(format!("{}\n\n", summary));
+    lines.join("\n")
+}
+
+fn count_changes(count: usize) -> String {
+    format!("{} change{}", count, if count == 1 { "" } else { "s" })
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+fn plan_filtering<'a>(source: &AdGuardFilteringStatus, target: &AdGuardFilteringStatus) -> Vec<Step<'a>> {
+    let mut changes = Vec::new();
+    if source.enabled != target.enabled {
+        changes.push(Change::Update(format!("filtering {} → {}", on_off(target.enabled), on_off(source.enabled))));
+    }
+    if source.interval != target.interval {
+        changes.push(Change::Update(format!("update interval {}h → {}h", target.interval, source.interval)));
+    }
+    if changes.is_empty() {
+        return Vec::new();
+    }
+    vec![Step { action: Action::SetFilteringConfig { enabled: source.enabled, interval: source.interval }, changes }]
+}
+
+/// Describes a filter list, e.g. "blocklist AdGuard DNS filter (https://...)"
+fn describe_list(filter: &Filter) -> String {
+    let kind = if filter.allowlist { "allowlist" } else { "blocklist" };
+    format!("{} {} ({})", kind, filter.name, filter.url)
+}
+
+fn plan_filter_lists<'a>(source: &'a AdGuardFilteringStatus, target: &'a AdGuardFilteringStatus) -> Vec<Step<'a>> {
+    // The same URL can be both a blocklist and an allowlist, so they're matched by both
+    let target_lists: HashMap<(bool, &str), &Filter> = target.lists()
+        .map(|filter| ((filter.allowlist, filter.url.as_str()), filter))
+        .collect();
+    let source_lists: HashMap<(bool, &str), &Filter> = source.lists()
+        .map(|filter| ((filter.allowlist, filter.url.as_str()), filter))
+        .collect();
+
+    let mut steps: Vec<Step> = target.lists()
+        .filter(|filter| !source_lists.contains_key(&(filter.allowlist, filter.url.as_str())))
+        .map(|filter| Step::new(Action::RemoveFilter(filter), Change::Remove(describe_list(filter))))
+        .collect();
+    for filter in source.lists() {
+        match target_lists.get(&(filter.allowlist, filter.url.as_str())) {
+            None => {
+                steps.push(Step::new(Action::AddFilter(filter), Change::Add(describe_list(filter))));
+                // New lists are always enabled, so are disabled afterwards if need be
+                if !filter.enabled {
+                    steps.push(Step { action: Action::SetFilterEnabled(filter, false), changes: Vec::new() });
+                }
+            }
+            Some(existing) if existing.name != filter.name || existing.enabled != filter.enabled => {
+                let mut differences = Vec::new();
+                if existing.name != filter.name {
+                    differences.push(format!("renamed \"{}\" → \"{}\"", existing.name, filter.name));
+                }
+                if existing.enabled != filter.enabled {
+                    differences.push(format!("{} → {}", on_off(existing.enabled), on_off(filter.enabled)));
+                }
+                let change = Change::Update(format!("{}, {}", describe_list(filter), differences.join(", ")));
+                steps.push(Step::new(Action::SetFilterEnabled(filter, filter.enabled), change));
+            }
+            Some(_) => {}
+        }
+    }
+    steps
+}
+
+fn plan_user_rules<'a>(source: &'a AdGuardFilteringStatus, target: &AdGuardFilteringStatus) -> Vec<Step<'a>> {
+    let rules = |filtering: &AdGuardFilteringStatus| -> Vec<String> {
+        filtering.user_rules.iter().map(|rule| rule.trim().to_string()).filter(|rule| !rule.is_empty()).collect()
+    };
+    let (source_rules, target_rules) = (rules(source), rules(target));
+    if source_rules == target_rules {
+        return Vec::new();
+    }
+    let source_set: BTreeSet<&String> = source_rules.iter().collect();
+    let target_set: BTreeSet<&String> = target_rules.iter().collect();
+    let mut changes: Vec<Change> = target_set.difference(&source_set).map(|rule| Change::Remove(rule.to_string()))
+        .chain(source_set.difference(&target_set).map(|rule| Change::Add(rule.to_string())))
+        .collect();
+    // The same rules in a different order still need syncing, as they're applied in order
+    if changes.is_empty() {
+        changes.push(Change::Update("order of rules".to_string()));
+    }
+    vec![Step { action: Action::SetUserRules(&source.user_rules), changes }]
+}
+
+fn plan_rewrites<'a>(source: &'a [Rewrite], target: &'a [Rewrite]) -> Vec<Step<'a>> {
+    let describe = |rewrite: &Rewrite| format!("{} → {}", rewrite.domain, rewrite.answer);
+    let removed = target.iter().filter(|rewrite| !source.contains(rewrite))
+        .map(|rewrite| Step::new(Action::RemoveRewrite(rewrite), Change::Remove(describe(rewrite))));
+    let added = source.iter().filter(|rewrite| !target.contains(rewrite))
+        .map(|rewrite| Step::new(Action::AddRewrite(rewrite), Change::Add(describe(rewrite))));
+    removed.chain(added).collect()
+}
+
+fn plan_blocked_services<'a>(source: &'a BlockedServices, target: &BlockedServices) -> Vec<Step<'a>> {
+    let source_ids: BTreeSet<&String> = source.ids.iter().collect();
+    let target_ids: BTreeSet<&String> = target.ids.iter().collect();
+    let mut changes: Vec<Change> = target_ids.difference(&source_ids).map(|id| Change::Remove(id.to_string()))
+        .chain(source_ids.difference(&target_ids).map(|id| Change::Add(id.to_string())))
+        .collect();
+    // Older versions don't have schedules, so there's nothing to compare
+    if source.schedule.is_some() && target.schedule.is_some() && source.schedule != target.schedule {
+        changes.push(Change::Update("schedule".to_string()));
+    }
+    if changes.is_empty() {
+        return Vec::new();
+    }
+    vec![Step { action: Action::SetBlockedServices(source), changes }]
+}
+
+fn plan_clients<'a>(source: &'a [PersistentClient], target: &'a [PersistentClient]) -> Vec<Step<'a>> {
+    let mut steps: Vec<Step> = target.iter()
+        .filter(|existing| !source.iter().any(|c| c.name == existing.name))
+        .map(|existing| Step::new(Action::RemoveClient(&existing.name), Change::Remove(existing.name.clone())))
+        .collect();
+    for wanted in source {
+        match target.iter().find(|existing| existing.name == wanted.name) {
+            None => steps.push(Step::new(Action::AddClient(wanted), Change::Add(wanted.name.clone()))),
+            Some(existing) if existing != wanted => {
+                // Name which settings differ, so the diff is readable without printing every field
+                let differing: BTreeSet<&String> = wanted.settings.keys().chain(existing.settings.keys())
+                    .filter(|key| wanted.settings.get(*key) != existing.settings.get(*key))
+                    .collect();
+                let differing: Vec<&str> = differing.into_iter().map(String::as_str).collect();
+                let change = Change::Update(format!("{} ({})", wanted.name, differing.join(", ")));
+                steps.push(Step::new(Action::UpdateClient(wanted), change));
+            }
+            Some(_) => {}
+        }
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+
+    fn settings(filtering: Value, rewrites: Value) -> SyncedSettings {
+        SyncedSettings {
+            filtering: serde_json::from_value(filtering).unwrap(),
+            rewrites: serde_json::from_value(rewrites).unwrap(),
+            blocked_services: BlockedServices::default(),
+            clients: Vec::new(),
+        }
+    }
+
+    fn list(name: &str, url: &str, enabled: bool) -> Value {
+        json!({ "id": 1, "name": name, "url": url, "rules_count": 10, "enabled": enabled })
+    }
+
+    fn changes<'a>(steps: &'a [Step]) -> Vec<&'a Change> {
+        steps.iter().flat_map(|step| &step.changes).collect()
+    }
+
+    #[test]
+    fn renamed_list_is_described_as_a_rename() {
+        let source = settings(json!({ "filters": [list("New name", "https://a/list.txt", true)] }), json!([]));
+        let target = settings(json!({ "filters": [list("Old name", "https://a/list.txt", true)] }), json!([]));
+        let steps = plan_filter_lists(&source.filtering, &target.filtering);
+        assert_eq!(changes(&steps), [&Change::Update(
+            "blocklist New name (https://a/list.txt), renamed \"Old name\" → \"New name\"".to_string(),
+        )]);
+
+        let disabled = settings(json!({ "filters": [list("Old name", "https://a/list.txt", false)] }), json!([]));
+        let steps = plan_filter_lists(&source.filtering, &disabled.filtering);
+        assert_eq!(changes(&steps), [&Change::Update(
+            "blocklist New name (https://a/list.txt), renamed \"Old name\" → \"New name\", off → on".to_string(),
+        )]);
+    }
+
+    #[test]
+    fn lists_are_matched_by_url() {
+        let source = settings(json!({ "filters": [
+            list("Kept", "https://a/kept.txt", true),
+            list("Added", "https://a/added.txt", false),
+        ] }), json!([]));
+        let target = settings(json!({ "filters": [
+            list("Kept", "https://a/kept.txt", true),
+            list("Removed", "https://a/removed.txt", true),
+        ] }), json!([]));
+        let steps = plan_filter_lists(&source.filtering, &target.filtering);
+        assert_eq!(changes(&steps), [
+            &Change::Remove("blocklist Removed (https://a/removed.txt)".to_string()),
+            &Change::Add("blocklist Added (https://a/added.txt)".to_string()),
+        ]);
+        // The new list is disabled after it's added, which isn't a change of its own
+        assert!(matches!(steps.last().map(|step| &step.action), Some(Action::SetFilterEnabled(_, false))));
+    }
+
+    #[test]
+    fn reordered_rules_are_still_synced() {
+        let source = settings(json!({ "filters": [], "user_rules": ["||a.com^", "||b.com^"] }), json!([]));
+        let target = settings(json!({ "filters": [], "user_rules": ["||b.com^", "", "||a.com^"] }), json!([]));
+        let steps = plan_user_rules(&source.filtering, &target.filtering);
+        assert_eq!(changes(&steps), [&Change::Update("order of rules".to_string())]);
+        assert!(plan_user_rules(&source.filtering, &source.filtering).is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_reports_every_change_without_making_them() {
+        colored::control::set_override(false);
+        let source = settings(
+            json!({ "enabled": true, "interval": 24, "filters": [], "user_rules": ["||ads.com^"] }),
+            json!([{ "domain": "nas.lan", "answer": "10.0.0.5" }]),
+        );
+        let target = settings(
+            json!({ "enabled": true, "interval": 12, "filters": [], "user_rules": [] }),
+            json!([{ "domain": "old.lan", "answer": "10.0.0.9" }]),
+        );
+        // Nothing's sent in a dry run, so this client is never used
+        let client = AdGuardClient::new("http://127.0.0.1:9", "", "").unwrap();
+        let mut results = Vec::new();
+        for (title, steps) in plan(&source, &target) {
+            results.push((title, apply(steps, &client, true).await));
+        }
+        assert_eq!(report(&results, true), [
+            "Filtering settings (1 change)",
+            "  ~ update interval 12h → 24h",
+            "Filter lists (no changes)",
+            "Custom rules (1 change)",
+            "  + ||ads.com^",
+            "Rewrites (2 changes)",
+            "  - old.lan → 10.0.0.9",
+            "  + nas.lan → 10.0.0.5",
+            "Blocked services (no changes)",
+            "Clients (no changes)",
+            "4 changes would be made\n\n",
+        ].join("\n"));
+        assert_eq!(report(&[("Rewrites", (Vec::new(), None))], true), "Rewrites (no changes)\nAlready in sync\n\n");
+    }
+
+    #[test]
+    fn changes_made_before_a_failure_are_reported() {
+        colored::control::set_override(false);
+        let error = AdGuardError::Status { endpoint: "/control/rewrite/add", status: StatusCode::BAD_REQUEST };
+        let results = [("Rewrites", (vec![Change::Remove("old.lan → 10.0.0.9".to_string())], Some(error)))];
+        let report = report(&results, false);
+        assert!(report.starts_with("Rewrites (1 change)\n  - old.lan → 10.0.0.9\n  Failed: "), "{}", report);
+        assert!(report.ends_with("1 change made\n\n"));
+    }
+}
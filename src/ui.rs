@@ -1,38 +1,77 @@
 use std::{
+  collections::HashMap,
   io::stdout,
-  sync::Arc,
   time::Duration,
 };
+use chrono::{DateTime, Local};
 use crossterm::{
-  event::{poll, read, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers},
+  event::{DisableMouseCapture, EnableMouseCapture, EventStream},
   execute,
   terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use futures::StreamExt;
+use tokio::{
+  sync::{mpsc, watch},
+  time::interval,
+};
 use tui::{
-  backend::CrosstermBackend,
-  layout::{Constraint, Direction, Layout},
-  style::Color,
+  backend::{Backend, CrosstermBackend},
+  layout::{Constraint, Direction, Layout, Rect},
+  style::{Color, Modifier, Style},
+  text::{Span, Spans},
+  widgets::{Block, Borders, Clear, ListState, Paragraph, Wrap},
+  Frame,
   Terminal,
 };
 
+use crate::app::{App, Flash, Focus, Popup, Snapshot, PAUSE_OPTIONS};
+use crate::config::Theme;
+use crate::connection::ConnectionState;
+use crate::instances::InstanceSummary;
 use crate::fetch::fetch_stats::StatsResponse;
 use crate::fetch::fetch_query_log::Query;
+use crate::query_log::{ListHits, SharedQueryLog};
 use crate::fetch::fetch_status::StatusResponse;
-use crate::fetch::fetch_filters::{AdGuardFilteringStatus, Filter};
+use crate::fetch::fetch_filters::AdGuardFilteringStatus;
 
 use crate::widgets::gauge::make_gauge;
+use crate::widgets::instances::make_instances_bar;
 use crate::widgets::table::make_query_table;
 use crate::widgets::chart::{make_history_chart, prepare_chart_data};
-use crate::widgets::status::render_status_paragraph;
-use crate::widgets::filters::make_filters_list;
+use crate::widgets::status::{make_insecure_warning_line, render_status_paragraph};
+use crate::widgets::filters::{filter_effectiveness_lines, make_filter_effectiveness, make_filters_list};
 use crate::widgets::list::make_list;
+use crate::widgets::connection::{make_connection_banner, make_flash_message, Dimmed};
+use crate::widgets::popup::{
+  centered_rect, make_add_filter_form, make_pause_picker, make_remove_filter_confirmation, make_rule_confirmation,
+  make_search_prompt, scroll_limit,
+};
+use crate::widgets::query_detail::{make_query_detail, query_detail_lines};
+use crate::widgets::rules_editor::make_rules_editor;
+use crate::widgets::theme::Monochrome;
+
+/// How often the UI is redrawn when nothing else has happened,
+/// so that relative times (e.g. "5 sec ago") stay current
+const RENDER_TICK: Duration = Duration::from_millis(250);
 
+/// Receivers for each piece of data that the UI displays, plus the state of the connection
+pub struct Receivers {
+    pub query_log: SharedQueryLog,
+    /// Changes each time the query log is updated
+    pub queries: watch::Receiver<u64>,
+    pub stats: watch::Receiver<Option<StatsResponse>>,
+    pub status: watch::Receiver<Option<StatusResponse>>,
+    pub filters: watch::Receiver<Option<AdGuardFilteringStatus>>,
+    pub connection: watch::Receiver<ConnectionState>,
+}
+
+/// Draws the dashboard until it's closed. `summaries` has the state of each instance, when there are several.
+/// Messages received on `flash_rx` are shown briefly, whether from the app's own actions or elsewhere
 pub async fn draw_ui(
-    mut data_rx: tokio::sync::mpsc::Receiver<Vec<Query>>,
-    mut stats_rx: tokio::sync::mpsc::Receiver<StatsResponse>,
-    mut status_rx: tokio::sync::mpsc::Receiver<StatusResponse>,
-    filters: AdGuardFilteringStatus,
-    shutdown: Arc<tokio::sync::Notify>
+    app: App,
+    flash_rx: mpsc::UnboundedReceiver<Flash>,
+    receivers: Receivers,
+    summaries: watch::Receiver<Vec<InstanceSummary>>,
 ) -> Result<(), anyhow::Error> {
     enable_raw_mode()?;
     let mut stdout = stdout();
@@ -41,149 +80,373 @@ pub async fn draw_ui(
     let mut terminal = Terminal::new(backend)?;
     terminal.clear()?;
 
-    loop {
-        // Receive query log and stats data from the fetcher
-        let data = match data_rx.recv().await {
-            Some(data) => data,
-            None => break, // Channel has been closed, so we break the loop
-        };
-        let mut stats = match stats_rx.recv().await {
-            Some(stats) => stats,
-            None => break,
-        };
-        let status = match status_rx.recv().await {
-            Some(status) => status,
-            None => break,
-        };
-
-        // Prepare the data for the chart
-        prepare_chart_data(&mut stats);
+    let result = run_ui_loop(&mut terminal, (app, flash_rx), receivers, summaries).await;
 
-        terminal.draw(|f| {
-            let size = f.size();
-
-            // Make the charts
-            let gauge = make_gauge(&stats);
-            let table = make_query_table(&data, size.width);
-            let graph = make_history_chart(&stats);
-            let paragraph = render_status_paragraph(&status, &stats);
-            let filter_items: &[Filter] = filters
-                .filters
-                .as_deref()
-                .unwrap_or(&[]);
-            let filters_list = make_filters_list(filter_items, size.width);
-            let top_queried_domains = make_list("Top Queried Domains", &stats.top_queried_domains, Color::Green, size.width);
-            let top_blocked_domains = make_list("Top Blocked Domains", &stats.top_blocked_domains, Color::Red, size.width);
-            let top_clients = make_list("Top Clients", &stats.top_clients, Color::Cyan, size.width);
-
-            let constraints = if size.height > 42 {
-                vec![
-                    Constraint::Percentage(30),
-                    Constraint::Min(1),
-                    Constraint::Percentage(20)
-                ]
-            } else {
-                vec![
-                    Constraint::Percentage(30),
-                    Constraint::Min(1),
-                    Constraint::Percentage(0)
-                ]
-            };
+    // Restore the terminal, even if the loop failed
+    terminal.show_cursor()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    disable_raw_mode()?;
+    result
+}
 
-            let chunks = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints(&*constraints)
-            .split(size);
-
-            // Split the top part (charts + gauge) into left (gauge + block) and right (line chart)
-            let top_chunks = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints(
-                [
-                    Constraint::Percentage(30), 
-                    Constraint::Percentage(70), 
-                ]
-                .as_ref(),
-            )
-            .split(chunks[0]);
-
-            // Split the left part of top (gauge + block) into top (gauge) and bottom (block)
-            let left_chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints(
-                    [
-                        Constraint::Min(0),
-                        Constraint::Length(3),
-                    ]
-                    .as_ref(),
-                )
-                .split(top_chunks[0]);
-
-            let bottom_chunks = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints(
-                    [
-                        Constraint::Percentage(25), 
-                        Constraint::Percentage(25), 
-                        Constraint::Percentage(25), 
-                        Constraint::Percentage(25), 
-                    ]
-                    .as_ref(),
-                )
-                .split(chunks[2]);
-
-            // Render the widgets to the UI
-            f.render_widget(paragraph, left_chunks[0]);
-            f.render_widget(gauge, left_chunks[1]);
-            f.render_widget(graph, top_chunks[1]);
-            f.render_widget(table, chunks[1]);
-            if size.height > 42 {
-                f.render_widget(filters_list, bottom_chunks[0]);
-                f.render_widget(top_queried_domains, bottom_chunks[1]);
-                f.render_widget(top_blocked_domains, bottom_chunks[2]);
-                f.render_widget(top_clients, bottom_chunks[3]);
-            }
-        })?;
+/// Main event loop. Waits on whichever happens first out of: a terminal event,
+/// the render tick, new data from any of the fetchers, or the result of an action, then redraws.
+/// Each channel only holds its latest value, so every panel shows the newest data it has.
+async fn run_ui_loop<B: Backend>(
+    terminal: &mut Terminal<B>,
+    (mut app, mut flash_rx): (App, mpsc::UnboundedReceiver<Flash>),
+    mut rx: Receivers,
+    mut summaries: watch::Receiver<Vec<InstanceSummary>>,
+) -> Result<(), anyhow::Error> {
+    let mut events = EventStream::new();
+    let mut render_tick = interval(RENDER_TICK);
 
-        // Check for user input events
-        if poll(Duration::from_millis(100))? {
-            match read()? {
-                Event::Key(KeyEvent {
-                    code: KeyCode::Char('q'),
-                    ..
-                }) => {
-                    // std::process::exit(0);
-                    shutdown.notify_waiters();
-                    break;
-                }
-                Event::Key(KeyEvent {
-                    code: KeyCode::Char('Q'),
-                    ..
-                }) => {
-                    shutdown.notify_waiters();
-                    break;
-                }
-                Event::Key(KeyEvent {
-                    code: KeyCode::Char('c'),
-                    modifiers: KeyModifiers::CONTROL,
-                }) => {
-                    shutdown.notify_waiters();
-                    break;
+    // Stats need preparing for the chart, so keep our own copy rather than borrowing
+    let mut stats: Option<StatsResponse> = None;
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                match event {
+                    Some(Ok(event)) => {
+                        // Resize events need no handling, the redraw below takes care of them
+                        let query_log = rx.query_log.read().unwrap();
+                        let status = rx.status.borrow();
+                        let filters = rx.filters.borrow();
+                        let snapshot = Snapshot {
+                            queries: query_log.as_slice(),
+                            stats: stats.as_ref(),
+                            status: status.as_ref(),
+                            filters: filters.as_ref(),
+                        };
+                        let quit = app.handle_event(&event, &snapshot);
+                        if quit {
+                            break;
+                        }
+                    }
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break, // Event stream has ended
                 }
-                Event::Resize(_, _) => {}, // Handle resize event, loop will redraw the UI
-                _ => {}
+            }
+            _ = render_tick.tick() => {}
+            changed = rx.queries.changed() => {
+                if changed.is_err() { break; } // Fetcher has gone away
+                app.queries_received(rx.query_log.read().unwrap().as_slice());
+            }
+            changed = rx.stats.changed() => {
+                if changed.is_err() { break; }
+                stats = rx.stats.borrow().clone().map(|mut stats| {
+                    prepare_chart_data(&mut stats);
+                    stats
+                });
+            }
+            changed = rx.status.changed() => {
+                if changed.is_err() { break; }
+                app.status_received();
+            }
+            changed = rx.filters.changed() => {
+                if changed.is_err() { break; }
+                let count = rx.filters.borrow().as_ref().map_or(0, |filters| filters.lists().count());
+                app.filters_received(count);
+            }
+            changed = rx.connection.changed() => {
+                if changed.is_err() { break; }
+            }
+            // With a single instance, nothing sends these, so the channel closing is expected
+            Ok(()) = summaries.changed() => {}
+            Some(flash) = flash_rx.recv() => {
+                app.show_flash(flash);
             }
         }
 
+        let query_log = rx.query_log.read().unwrap();
+        let status = rx.status.borrow();
+        let filters = rx.filters.borrow();
+        let connection = rx.connection.borrow();
+        let instances = summaries.borrow();
+        terminal.draw(|f| {
+            let data = Data {
+                queries: query_log.loaded(),
+                rule_hits: query_log.rule_hits(),
+                hits_since: query_log.hits_since(),
+                stats: stats.as_ref(),
+                status: status.as_ref(),
+                filters: filters.as_ref(),
+                instances: &instances,
+            };
+            render(f, &mut app, &data, &connection)
+        })?;
     }
 
-    terminal.show_cursor()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    disable_raw_mode()?;
     Ok(())
 }
 
+/// Placeholder for panels whose data hasn't arrived yet
+fn make_loading_block(title: &str) -> Paragraph<'_> {
+    Paragraph::new(Span::styled("Loading...", Style::default().fg(Color::DarkGray)))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD))),
+        )
+}
+
+/// Placeholder for the status panel, which still warns if TLS isn't verified, so it's seen before anything loads
+fn make_loading_status(insecure_warning: Option<String>) -> Paragraph<'static> {
+    let Some(warning) = insecure_warning else {
+        return make_loading_block("Status");
+    };
+    Paragraph::new(vec![
+        make_insecure_warning_line(&warning),
+        Spans::from(Span::styled("Loading...", Style::default().fg(Color::DarkGray))),
+    ])
+    .wrap(Wrap { trim: true })
+    .block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))
+            .title(Span::styled("Status", Style::default().add_modifier(Modifier::BOLD))),
+    )
+}
+
+/// Everything that's drawn, each of which is `None` until it's first been received
+struct Data<'a> {
+    queries: Option<&'a [Query]>,
+    /// Queries matching a rule from each filter list, by list ID
+    rule_hits: &'a HashMap<i64, ListHits>,
+    hits_since: DateTime<Local>,
+    stats: Option<&'a StatsResponse>,
+    status: Option<&'a StatusResponse>,
+    filters: Option<&'a AdGuardFilteringStatus>,
+    /// The state of each instance, if there are several
+    instances: &'a [InstanceSummary],
+}
+
+/// Lays out and renders every panel, using whatever data is currently available
+fn render<B: Backend>(
+    f: &mut Frame<B>,
+    app: &mut App,
+    data: &Data,
+    connection: &ConnectionState,
+) {
+    let Data { stats, status, filters, .. } = *data;
+    // Reserve the top line for the connection banner, and the outcome of any action,
+    // then the next for the instances bar, if there are several instances
+    let instances_height = if data.instances.len() > 1 { 1 } else { 0 };
+    let outer_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Length(instances_height), Constraint::Min(0)].as_ref())
+        .split(f.size());
+    f.render_widget(make_connection_banner(connection), outer_chunks[0]);
+    if let Some(flash) = app.flash() {
+        f.render_widget(make_flash_message(&flash.message, flash.is_error), outer_chunks[0]);
+    }
+    if instances_height > 0 {
+        f.render_widget(make_instances_bar(data.instances, app.view()), outer_chunks[1]);
+    }
+    let size = outer_chunks[2];
+
+    let show_lists = app.layout.show_lists(size.height);
+    let constraints = if show_lists {
+        vec![
+            Constraint::Percentage(30),
+            Constraint::Min(1),
+            Constraint::Percentage(20)
+        ]
+    } else {
+        vec![
+            Constraint::Percentage(30),
+            Constraint::Min(1),
+            Constraint::Percentage(0)
+        ]
+    };
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(&*constraints)
+        .split(size);
+
+    // Split the top part (charts + gauge) into left (gauge + block) and right (line chart)
+    let top_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage(30),
+                Constraint::Percentage(70),
+            ]
+            .as_ref(),
+        )
+        .split(chunks[0]);
+
+    // Split the left part of top (gauge + block) into top (gauge) and bottom (block)
+    let left_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Min(0),
+                Constraint::Length(3),
+            ]
+            .as_ref(),
+        )
+        .split(top_chunks[0]);
+
+    let bottom_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+            ]
+            .as_ref(),
+        )
+        .split(chunks[2]);
+
+    // Render the widgets to the UI, or a placeholder if their data isn't here yet
+    match (status, stats) {
+        (Some(status), Some(stats)) => {
+            let paragraph = render_status_paragraph(
+                status,
+                stats,
+                app.protection_resumes_in(status),
+                app.insecure_warning(),
+            );
+            f.render_widget(paragraph, left_chunks[0]);
+        }
+        _ => f.render_widget(make_loading_status(app.insecure_warning()), left_chunks[0]),
+    }
+    match stats {
+        Some(stats) => {
+            f.render_widget(make_gauge(stats), left_chunks[1]);
+            f.render_widget(make_history_chart(stats), top_chunks[1]);
+        }
+        None => {
+            f.render_widget(make_loading_block("Block Percentage"), left_chunks[1]);
+            f.render_widget(make_loading_block("History"), top_chunks[1]);
+        }
+    }
+    match data.queries {
+        Some(queries) => {
+            // Rows that fit, once the borders and header are taken away
+            // Only the rows on screen are drawn, as the log can hold many thousands
+            app.set_query_page_size(chunks[1].height.saturating_sub(3) as usize);
+            let (window, mut table_state) = app.query_window(queries.len());
+            let filter = app.query_filter();
+            let table = make_query_table(
+                &queries[window], queries.len(), &filter, filters, size.width, app.focus == Focus::QueryLog,
+            );
+            f.render_stateful_widget(table, chunks[1], &mut table_state);
+        }
+        None => f.render_widget(make_loading_block("Query Log"), chunks[1]),
+    }
+    app.set_lists_visible(show_lists);
+    if show_lists {
+        render_bottom_lists(f, app, &bottom_chunks, data, size.width);
+    }
+
+    // If we've lost connection, the data on screen is out of date, so dim it
+    if connection.is_stale() {
+        f.render_widget(Dimmed, size);
+    }
+
+    let scroll_limit = render_popup(f, app, data);
+    app.set_popup_scroll_limit(scroll_limit);
+
+    if app.theme == Theme::Monochrome {
+        f.render_widget(Monochrome, f.size());
+    }
+}
+
+/// Draws the open popup (if any) over the top of everything else,
+/// returning how far it can be scrolled (which is 0 for those that don't scroll)
+fn render_popup<B: Backend>(f: &mut Frame<B>, app: &App, data: &Data) -> u16 {
+    match &app.popup {
+        Some(Popup::PauseProtection { selected }) => {
+            let area = centered_rect(32, PAUSE_OPTIONS.len() as u16 + 2, f.size());
+            let mut state = ListState::default();
+            state.select(Some(*selected));
+            f.render_widget(Clear, area);
+            f.render_stateful_widget(make_pause_picker(), area, &mut state);
+        }
+        Some(Popup::QueryDetails { query, scroll }) => {
+            let area = centered_rect(f.size().width * 3 / 4, f.size().height * 3 / 4, f.size());
+            f.render_widget(Clear, area);
+            let lines = query_detail_lines(query, data.filters);
+            let limit = scroll_limit(&lines, area);
+            f.render_widget(make_query_detail(lines, (*scroll).min(limit)), area);
+            return limit;
+        }
+        Some(Popup::ConfirmRule { host, kind, scope }) => {
+            let area = centered_rect(64, 8, f.size());
+            f.render_widget(Clear, area);
+            f.render_widget(make_rule_confirmation(host, *kind, *scope), area);
+        }
+        Some(Popup::Search { input, status }) => {
+            let area = centered_rect(100, 8, f.size());
+            f.render_widget(Clear, area);
+            f.render_widget(make_search_prompt(input, *status), area);
+        }
+        Some(Popup::AddFilter(form)) => {
+            let area = centered_rect(100, 7, f.size());
+            f.render_widget(Clear, area);
+            f.render_widget(make_add_filter_form(form), area);
+        }
+        Some(Popup::ConfirmRemoveFilter { filter }) => {
+            let area = centered_rect(80, 6, f.size());
+            f.render_widget(Clear, area);
+            f.render_widget(make_remove_filter_confirmation(filter), area);
+        }
+        Some(Popup::UserRules(editor)) => {
+            let area = centered_rect(f.size().width * 3 / 4, f.size().height * 3 / 4, f.size());
+            f.render_widget(Clear, area);
+            f.render_widget(make_rules_editor(editor, area.height), area);
+        }
+        Some(Popup::FilterEffectiveness { scroll }) => {
+            let area = centered_rect(f.size().width * 3 / 4, f.size().height * 3 / 4, f.size());
+            f.render_widget(Clear, area);
+            let lines = filter_effectiveness_lines(data.filters, data.rule_hits, data.hits_since);
+            let limit = scroll_limit(&lines, area);
+            f.render_widget(make_filter_effectiveness(lines, (*scroll).min(limit)), area);
+            return limit;
+        }
+        None => {}
+    }
+    0
+}
+
+/// Renders the filters list, and the top domains / clients lists along the bottom
+fn render_bottom_lists<B: Backend>(
+    f: &mut Frame<B>,
+    app: &mut App,
+    chunks: &[Rect],
+    data: &Data,
+    width: u16,
+) {
+    let filters_list = make_filters_list(data.filters, data.rule_hits, width, app.focus == Focus::Filters);
+    f.render_stateful_widget(filters_list, chunks[0], &mut app.filters_list);
+
+    match data.stats {
+        Some(stats) => {
+            let top_queried_domains = make_list(
+                "Top Queried Domains", &stats.top_queried_domains, Color::Green, width, app.focus == Focus::TopQueried,
+            );
+            let top_blocked_domains = make_list(
+                "Top Blocked Domains", &stats.top_blocked_domains, Color::Red, width, app.focus == Focus::TopBlocked,
+            );
+            let top_clients = make_list("Top Clients", &stats.top_clients, Color::Cyan, width, false);
+            f.render_stateful_widget(top_queried_domains, chunks[1], &mut app.top_queried_list);
+            f.render_stateful_widget(top_blocked_domains, chunks[2], &mut app.top_blocked_list);
+            f.render_widget(top_clients, chunks[3]);
+        }
+        None => {
+            f.render_widget(make_loading_block("Top Queried Domains"), chunks[1]);
+            f.render_widget(make_loading_block("Top Blocked Domains"), chunks[2]);
+            f.render_widget(make_loading_block("Top Clients"), chunks[3]);
+        }
+    }
+}
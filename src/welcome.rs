@@ -1,15 +1,19 @@
 use std::{
     io:: {self, Write},
-    env,
     time::Duration
 };
-use reqwest::{Client, Error};
 use colored::*;
 
-use serde_json::Value;
 use serde::Deserialize;
 use semver::{Version};
 
+use crate::cli::GlobalArgs;
+use crate::config::{Resolver, Settings, Source};
+use crate::fetch::client::{AdGuardClient, AdGuardError, MIN_VERSION};
+
+/// How long to wait for AdGuard to respond when verifying the connection
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// Reusable function that just prints success messages to the console
 fn print_info(text: &str, is_secondary: bool) {
     if is_secondary {
@@ -36,12 +40,12 @@ fn print_ascii_art() {
 }
 
 /// Print error message, along with (optional) stack trace, then exit
-fn print_error(message: &str, sub_message: &str, error: Option<&Error>) {
+fn print_error(message: &str, sub_message: &str, error: Option<&AdGuardError>) {
     eprintln!(
         "{}{}{}",
-        format!("{}", message).red(),
+        message.red(),
         match error {
-            Some(err) => format!("\n{}", err).red().dimmed(),
+            Some(err) => format!("\n{}", error_chain(err)).red().dimmed(),
             None => "".red().dimmed(),
         },
         format!("\n{}", sub_message).yellow(),
@@ -50,37 +54,31 @@ fn print_error(message: &str, sub_message: &str, error: Option<&Error>) {
     std::process::exit(1);
 }
 
-/// Given a key, get the value from the environmental variables, and print it to the console
-fn get_env(key: &str) -> Result<String, env::VarError> {
-    env::var(key).map(|v| {
-        println!(
-            "{}",
-            format!(
-                "{} is set to {}",
-                key.bold(),
-                if key.contains("PASSWORD") { "******" } else { &v }
-            )
-            .green()
-        );
-        v
-    })
+/// Joins an error with each of its underlying causes, into a single line.
+/// Some errors already include their cause in their message, so those aren't repeated
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message.push_str(&format!(": {}", cause_message));
+        }
+        source = cause.source();
+    }
+    message
 }
 
-/// Given a possibly undefined version number, check if it's present and supported
-fn check_version(version: Option<&str>) {
-    let min_version = Version::parse("0.107.29").unwrap();
-    
-    match version {
+
+/// Explain why the AdGuard version isn't supported, then exit
+fn print_unsupported_version(found: Option<&str>) {
+    match found {
         Some(version_str) => {
-            let adguard_version = Version::parse(version_str.strip_prefix('v').unwrap_or(version_str)).unwrap();
-            
-            if adguard_version < min_version {
-                print_error(
-                    "AdGuard Home version is too old, and is now unsupported",
-                    format!("You're running AdGuard {}. Please upgrade to v{} or later.", version_str, min_version.to_string()).as_str(),
-                    None,
-                );
-            }
+            print_error(
+                "AdGuard Home version is too old, and is now unsupported",
+                format!("You're running AdGuard {}. Please upgrade to v{} or later.", version_str, MIN_VERSION).as_str(),
+                None,
+            );
         },
         None => {
             print_error(
@@ -90,7 +88,7 @@ fn check_version(version: Option<&str>) {
                         "Failed to get the version number of your AdGuard Home instance.\n",
                         "This usually means you're running an old, and unsupported version.\n",
                         "Please upgrade to v{} or later."
-                    ), min_version.to_string()
+                    ), MIN_VERSION
                 ).as_str(),
                 None,
             );
@@ -99,55 +97,33 @@ fn check_version(version: Option<&str>) {
 }
 
 /// With the users specified AdGuard details, verify the connection (exit on fail)
-async fn verify_connection(
-    client: &Client,
-    ip: String,
-    port: String,
-    protocol: String,
-    username: String,
-    password: String,
-) -> Result<(), Box<dyn std::error::Error>> {
+async fn verify_connection(client: &AdGuardClient, ip: &str, port: &str) {
     println!("{}", "\nVerifying connection to your AdGuard instance...".blue());
 
-    let auth_string = format!("{}:{}", username, password);
-    let auth_header_value = format!("Basic {}", base64::encode(&auth_string));
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert("Authorization", auth_header_value.parse()?);
-
-    let url = format!("{}://{}:{}/control/status", protocol, ip, port);
-
-    match client
-        .get(&url)
-        .headers(headers)
-        .timeout(Duration::from_secs(2))
-        .send()
-        .await {
-        Ok(res) if res.status().is_success() => {
-            // Get version string (if present), and check if valid - exit if not
-            let body: Value = res.json().await?;
-            check_version(body["version"].as_str());
-            // All good! Print success message :)
-            let safe_version = body["version"].as_str().unwrap_or("mystery version");
-            println!("{}", format!("AdGuard ({}) connection successful!\n", safe_version).green());
-            Ok(())
+    match client.verify_connection().await {
+        // All good! Print success message :)
+        Ok(version) => {
+            println!("{}", format!("AdGuard ({}) connection successful!\n", version).green());
+        }
+        // Version is missing or too old. Print error and exit
+        Err(AdGuardError::UnsupportedVersion { found }) => {
+            print_unsupported_version(found.as_deref());
         }
         // Connection failed to authenticate. Print error and exit
-        Ok(_) => {
+        Err(AdGuardError::Auth { .. }) => {
             print_error(
                 &format!("Authentication with AdGuard at {}:{} failed", ip, port),
-                "Please check your environmental variables and try again.",
+                "Please check your settings and try again.",
                 None,
             );
-            Ok(())
         },
         // Connection failed to establish. Print error and exit
         Err(e) => {
             print_error(
                 &format!("Failed to connect to AdGuard at: {}:{}", ip, port),
-                "Please check your environmental variables and try again.",
+                "Please check your settings and try again.",
                 Some(&e),
             );
-            Ok(())
         }
     }
 }
@@ -225,17 +201,58 @@ async fn check_for_updates() {
 }
 
 
+/// Print the value of a setting, and where it came from
+fn print_setting(key: &str, value: &str, source: Source) {
+    println!(
+        "{} {}",
+        format!(
+            "{} is set to {}",
+            key.bold(),
+            if key.contains("PASSWORD") || key.contains("TOKEN") { "******" } else { value }
+        )
+        .green(),
+        format!("(from {})", source.describe()).green().dimmed()
+    );
+}
+
+/// Ask the user to enter a value for a setting that isn't set anywhere
+fn prompt_for(key: &str) -> io::Result<String> {
+    println!(
+        "{}",
+        format!("The {} environmental variable is not yet set", key.bold()).yellow()
+    );
+    print!("{}", format!("› Enter a value for {}: ", key).blue().bold());
+    io::stdout().flush()?;
+
+    let mut value = String::new();
+    io::stdin().read_line(&mut value)?;
+    Ok(value.trim().to_string())
+}
+
+/// Get a setting from flags, env vars or the config file (in that order),
+/// falling back to the default, or prompting the user if there isn't one. Then print it
+fn get_setting(resolver: &Resolver, key: &str, default: Option<&str>) -> anyhow::Result<String> {
+    let (value, source) = match (resolver.get(key), default) {
+        (Some(found), _) => found,
+        (None, Some(default)) => (default.to_string(), Source::Default),
+        (None, None) => (prompt_for(key)?, Source::Prompt),
+    };
+    print_setting(key, &value, source);
+    Ok(value)
+}
+
 /// Initiate the welcome script
 /// This function will:
 /// - Print the AdGuardian ASCII art
 /// - Check if there's an update available
-/// - Check for the required environmental variables
-/// - Prompt the user to enter any missing variables
-/// - Verify the connection to the AdGuard instance
+/// - Load the config file, and select a profile (or several, to show together)
+/// - Check for the required settings, from flags, env vars or the config file
+/// - Prompt the user to enter any missing values
+/// - Verify the connection to each AdGuard instance
 /// - Verify authentication is successful
 /// - Verify the AdGuard Home version is supported
-/// - Then either print a success message, or show instructions to fix and exit
-pub async fn welcome() -> Result<(), Box<dyn std::error::Error>> {
+/// - Then either return the settings to use for each instance, or show instructions to fix and exit
+pub async fn welcome(args: &GlobalArgs) -> Result<Vec<Settings>, Box<dyn std::error::Error>> {
     print_ascii_art();
 
     // Check for updates
@@ -243,57 +260,21 @@ pub async fn welcome() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("{}", "\nStarting initialization checks...".blue());
 
-    let client = Client::new();
-
-    // List of available flags, ant their associated env vars
-    let flags = [
-        ("--adguard-ip", "ADGUARD_IP"),
-        ("--adguard-port", "ADGUARD_PORT"),
-        ("--adguard-username", "ADGUARD_USERNAME"),
-        ("--adguard-password", "ADGUARD_PASSWORD"),
-    ];
-
-    let protocol: String = env::var("ADGUARD_PROTOCOL").unwrap_or_else(|_| "http".into()).parse()?;
-    env::set_var("ADGUARD_PROTOCOL", protocol);
-
-    // Parse command line arguments
-    let mut args = std::env::args().peekable();
-    while let Some(arg) = args.next() {
-        for &(flag, var) in &flags {
-            if arg == flag {
-                if let Some(value) = args.peek() {
-                    env::set_var(var, value);
-                    args.next();
-                }
-            }
+    // Load the config file and pick the profiles, then grab the value of each setting,
+    // prompting the user to enter any that aren't set
+    let resolvers = Resolver::for_dashboard(args).map_err(|e| format!("{:#}", e))?;
+    let mut all_settings = Vec::new();
+    for resolver in resolvers {
+        if let Some((name, path)) = &resolver.profile_source {
+            println!("{}", format!("Using profile {} from {}", name.bold(), path.display()).green());
         }
-    }
+        let settings = resolver.settings(get_setting).map_err(|e| format!("{:#}", e))?;
 
-    // If any of the env variables or flags are not yet set, prompt the user to enter them
-    for &key in &["ADGUARD_IP", "ADGUARD_PORT", "ADGUARD_USERNAME", "ADGUARD_PASSWORD"] {
-        if env::var(key).is_err() {
-            println!(
-                "{}",
-                format!("The {} environmental variable is not yet set", key.bold()).yellow()
-            );
-            print!("{}", format!("› Enter a value for {}: ", key).blue().bold());
-            io::stdout().flush()?;
-
-            let mut value = String::new();
-            io::stdin().read_line(&mut value)?;
-            env::set_var(key, value.trim());
-        }
+        // Verify that we can connect, authenticate, and that version is supported (exit on failure)
+        let client = settings.client()?.with_timeout(VERIFY_TIMEOUT);
+        verify_connection(&client, &settings.ip, &settings.port).await;
+        all_settings.push(settings);
     }
 
-    // Grab the values of the (now set) environmental variables
-    let ip = get_env("ADGUARD_IP")?;
-    let port = get_env("ADGUARD_PORT")?;
-    let protocol = get_env("ADGUARD_PROTOCOL")?;
-    let username = get_env("ADGUARD_USERNAME")?;
-    let password = get_env("ADGUARD_PASSWORD")?;
-    
-    // Verify that we can connect, authenticate, and that version is supported (exit on failure)
-    verify_connection(&client, ip, port, protocol, username, password).await?;
-
-    Ok(())
+    Ok(all_settings)
 }
@@ -6,7 +6,7 @@ use tui::{
     symbols,
 };
 
-use crate::fetch::fetch_stats::{StatsResponse};
+use crate::fetch::fetch_stats::{StatsResponse, TimeUnit};
 
 
 pub fn make_history_chart(stats: &StatsResponse) -> Chart<'_> {
@@ -15,7 +15,7 @@ pub fn make_history_chart(stats: &StatsResponse) -> Chart<'_> {
     // Find uppermost x and y-axis bounds for chart
     let (x_bound, y_bound) = find_bounds(stats);
     // Generate incremental labels from data's values, to render on axis
-    let x_labels = generate_x_labels(stats.dns_queries.len() as i32, 5);
+    let x_labels = generate_x_labels(stats.dns_queries.len(), stats.time_units, 5);
     let y_labels = generate_y_labels(y_bound as i32, 5);
     // Create chart
     let chart = Chart::new(datasets)
@@ -29,7 +29,10 @@ pub fn make_history_chart(stats: &StatsResponse) -> Chart<'_> {
         )
         .x_axis(
             Axis::default()
-            .title("Time (Days ago)")
+            .title(match stats.time_units {
+                TimeUnit::Hours => "Time (Hourly)",
+                TimeUnit::Days => "Time (Daily)",
+            })
             .bounds([0.0, x_bound])
             .labels(x_labels),
         )
@@ -88,16 +91,25 @@ fn generate_y_labels(max: i32, count: usize) -> Vec<Span<'static>> {
       .collect::<Vec<Span<'static>>>()
 }
 
-// Generate periodic labels to render on the x-axis (days ago)
-fn generate_x_labels(max_days: i32, num_labels: i32) -> Vec<Span<'static>> {
-    let step = max_days / (num_labels - 1);
+// Generate evenly spaced labels to render on the x-axis, with the time each point starts at.
+// The last point is the current hour or day, which is shown in bold
+fn generate_x_labels(points: usize, unit: TimeUnit, num_labels: usize) -> Vec<Span<'static>> {
+    let last = points.saturating_sub(1);
+    let num_labels = num_labels.min(points).max(2);
+    let current = unit.current_start();
+    let format = match unit {
+        TimeUnit::Hours => "%H:%M",
+        TimeUnit::Days => "%b %d",
+    };
     (0..num_labels)
         .map(|i| {
-            let day = (max_days - i * step).to_string();
+            let point = (i * last + (num_labels - 1) / 2) / (num_labels - 1);
+            let time = current - unit.duration() * (last - point) as i32;
+            let label = time.format(format).to_string();
             if i == num_labels - 1 {
-                Span::styled("Today", Style::default().add_modifier(Modifier::BOLD))
+                Span::styled(label, Style::default().add_modifier(Modifier::BOLD))
             } else {
-                Span::raw(day)
+                Span::raw(label)
             }
         })
         .collect()
@@ -109,7 +121,7 @@ fn convert_to_chart_data(data: Vec<f64>) -> Vec<(f64, f64)> {
 }
 
 // Interpolates data, adding n number of points, to make the chart look smoother
-fn interpolate(input: &Vec<f64>, points_between: usize) -> Vec<f64> {
+fn interpolate(input: &[f64], points_between: usize) -> Vec<f64> {
     let mut output = Vec::new();
 
     for window in input.windows(2) {
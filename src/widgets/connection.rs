@@ -0,0 +1,60 @@
+use std::time::Instant;
+use tui::{
+  buffer::Buffer,
+  layout::{Alignment, Rect},
+  style::{Color, Modifier, Style},
+  text::{Span, Spans},
+  widgets::{Paragraph, Widget},
+};
+
+use crate::connection::ConnectionState;
+
+// One-line banner, showing whether we're connected, and when data was last updated
+pub fn make_connection_banner(state: &ConnectionState) -> Paragraph<'static> {
+  let bold = |color: Color| Style::default().fg(color).add_modifier(Modifier::BOLD);
+  let dimmed = Style::default().fg(Color::Gray);
+
+  let spans = match state {
+    ConnectionState::Connecting => vec![
+      Span::styled(" ◌ Connecting to AdGuard...", bold(Color::Blue)),
+    ],
+    ConnectionState::Connected { last_update } => vec![
+      Span::styled(" ● Connected", bold(Color::Green)),
+      Span::styled(format!(" · Last updated at {}", last_update.format("%H:%M:%S")), dimmed),
+    ],
+    ConnectionState::Retrying { attempt, retry_at, last_success, error } => {
+      let retry_in = retry_at.saturating_duration_since(Instant::now()).as_secs();
+      let last_success = match last_success {
+        Some(time) => format!("Showing stale data from {}", time.format("%H:%M:%S")),
+        None => "No data received yet".to_string(),
+      };
+      vec![
+        Span::styled(" ⚠ Connection lost", bold(Color::Red)),
+        Span::styled(format!(" · Retrying in {}s (attempt {})", retry_in, attempt), bold(Color::Yellow)),
+        Span::styled(format!(" · {}", last_success), bold(Color::Yellow)),
+        Span::styled(format!(" · {}", error), dimmed),
+      ]
+    },
+  };
+
+  Paragraph::new(Spans::from(spans))
+}
+
+// Message showing the outcome of the last action, e.g. toggling protection
+pub fn make_flash_message(message: &str, is_error: bool) -> Paragraph<'_> {
+  let color = if is_error { Color::Red } else { Color::Green };
+  Paragraph::new(Span::styled(
+    format!("{} ", message),
+    Style::default().fg(color).add_modifier(Modifier::BOLD),
+  ))
+  .alignment(Alignment::Right)
+}
+
+// Dims everything already rendered in an area, used to mark data as stale
+pub struct Dimmed;
+
+impl Widget for Dimmed {
+  fn render(self, area: Rect, buf: &mut Buffer) {
+    buf.set_style(area, Style::default().add_modifier(Modifier::DIM));
+  }
+}
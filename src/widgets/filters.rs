@@ -6,19 +6,28 @@ use tui::{
   style::{Color, Style, Modifier},
 };
 
-use crate::fetch::fetch_filters::Filter;
+use std::collections::HashMap;
+use chrono::{DateTime, Local};
+use tui::widgets::{Paragraph, Wrap};
 
-fn truncate(text: &str, width: usize) -> String {
-  if text.chars().count() <= width {
-      text.to_string()
-  } else {
-      text.chars().take(width - 3).collect::<String>() + "..."
-  }
-}
+use crate::fetch::fetch_filters::{AdGuardFilteringStatus, Filter};
+use crate::query_log::ListHits;
+use crate::widgets::list::{focus_style, name_width, truncate};
+use crate::widgets::popup::popup_block;
 
-pub fn make_filters_list(filters: &[Filter], width: u16) -> List {
-  let items: Vec<ListItem> = filters
-    .iter()
+// List of blocklists then allowlists (which have their names in green), showing whether each is enabled,
+// how many rules it has, and how many queries in the query log have matched one of its rules.
+// The title shows if filtering is off, or otherwise how often lists are updated
+pub fn make_filters_list<'a>(
+  filtering: Option<&'a AdGuardFilteringStatus>,
+  hits: &HashMap<i64, ListHits>,
+  width: u16,
+  focused: bool,
+) -> List<'a> {
+  let name_width = name_width(width);
+  let items: Vec<ListItem> = filtering
+    .into_iter()
+    .flat_map(|filtering| filtering.lists())
     .map(|filter| {
         let (status_text, color) = if filter.enabled {
             ("✔", Color::Green)
@@ -27,19 +36,130 @@ pub fn make_filters_list(filters: &[Filter], width: u16) -> List {
         };
         let status = Span::styled(status_text, Style::default().fg(color));
         let rule_count = Span::styled(format!(" ({})", filter.rules_count), Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD));
-        let name = Span::raw(format!(" {}", truncate(&filter.name, width as usize / 4 - 12)));
-        let content = Spans::from(vec![status, name, rule_count]);
+        let name_style = if filter.allowlist { Style::default().fg(Color::LightGreen) } else { Style::default() };
+        let name = Span::styled(format!(" {}", truncate(&filter.name, name_width)), name_style);
+        let hit_count = Span::styled(
+            format!(" {} hits", hits.get(&filter.id).copied().unwrap_or_default().matched),
+            Style::default().fg(Color::Red),
+        );
+        let content = Spans::from(vec![status, name, rule_count, hit_count]);
         ListItem::new(content)
     })
     .collect();
 
+  let mut title = vec![Span::styled("Filters", Style::default().add_modifier(Modifier::BOLD))];
+  match filtering {
+    Some(filtering) if !filtering.enabled => {
+      title.push(Span::styled(" (filtering off)", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)));
+    }
+    Some(filtering) if filtering.interval > 0 => {
+      title.push(Span::styled(format!(" (updated every {}h)", filtering.interval), Style::default().fg(Color::DarkGray)));
+    }
+    _ => {}
+  }
+
   List::new(items)
       .block(
           Block::default()
               .borders(Borders::ALL)
-              .title(Span::styled(
-                "Filters",
-                Style::default().add_modifier(Modifier::BOLD),
-              )),
+              .border_style(focus_style(focused))
+              .title(Spans::from(title)),
       )
+      .highlight_style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD))
+}
+
+// When a filter list was last downloaded, in local time
+fn last_updated(filter: &Filter) -> String {
+  filter.last_updated.as_deref()
+    .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+    .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+    .unwrap_or_else(|| "never".to_string())
+}
+
+// Filter lists (blocklists and allowlists) ranked by how many queries they've blocked, then matched,
+// so that lists which aren't doing anything can be spotted. Enabled lists which have never matched a query are highlighted
+pub fn filter_effectiveness_lines<'a>(
+  filters: Option<&'a AdGuardFilteringStatus>,
+  hits: &HashMap<i64, ListHits>,
+  since: DateTime<Local>,
+) -> Vec<Spans<'a>> {
+  let blocklists = filters.and_then(|filters| filters.filters.as_deref()).unwrap_or_default();
+  let allowlists = filters.and_then(|filters| filters.whitelist_filters.as_deref()).unwrap_or_default();
+  let mut ranked: Vec<(&Filter, bool, ListHits)> = blocklists.iter().map(|filter| (filter, false))
+    .chain(allowlists.iter().map(|filter| (filter, true)))
+    .map(|(filter, allowlist)| (filter, allowlist, hits.get(&filter.id).copied().unwrap_or_default()))
+    .collect();
+  ranked.sort_by(|(a, _, a_hits), (b, _, b_hits)| {
+    b_hits.blocked.cmp(&a_hits.blocked)
+      .then(b_hits.matched.cmp(&a_hits.matched))
+      .then_with(|| a.name.cmp(&b.name))
+  });
+
+  let mut lines = vec![
+    Spans::from(Span::styled(
+      format!("Counted from queries since {}", since.format("%Y-%m-%d %H:%M")),
+      Style::default().fg(Color::DarkGray),
+    )),
+    Spans::from(Span::styled(
+      format!("{:>3}  {:>9}  {:>9}  {:>9}  {:<16}  {}", "#", "Blocked", "Matched", "Rules", "Last Updated", "Name"),
+      Style::default().add_modifier(Modifier::BOLD),
+    )),
+  ];
+  if ranked.is_empty() {
+    lines.push(Spans::from(Span::styled("  (no filter lists)", Style::default().fg(Color::DarkGray))));
+  }
+  for (rank, (filter, allowlist, hits)) in ranked.into_iter().enumerate() {
+    let unused = filter.enabled && hits.matched == 0;
+    let color = if !filter.enabled {
+      Color::DarkGray
+    } else if unused {
+      Color::Yellow
+    } else {
+      Color::White
+    };
+    let mut spans = vec![Span::styled(
+      format!(
+        "{:>3}  {:>9}  {:>9}  {:>9}  {:<16}  {}",
+        rank + 1, hits.blocked, hits.matched, filter.rules_count, last_updated(filter), filter.name,
+      ),
+      Style::default().fg(color),
+    )];
+    if allowlist {
+      spans.push(Span::styled("  allowlist", Style::default().fg(Color::LightGreen)));
+    }
+    if unused {
+      spans.push(Span::styled("  never matched", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)));
+    } else if !filter.enabled {
+      spans.push(Span::styled("  disabled", Style::default().fg(Color::DarkGray)));
+    }
+    lines.push(Spans::from(spans));
+  }
+  lines
+}
+
+// Popup content, showing the ranked filter lists, scrolled down by a number of lines
+pub fn make_filter_effectiveness(lines: Vec<Spans>, scroll: u16) -> Paragraph {
+  Paragraph::new(lines)
+    .block(popup_block("Filter List Effectiveness (Esc to close, ↑/↓ to scroll)"))
+    .wrap(Wrap { trim: false })
+    .scroll((scroll, 0))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn truncates_names_in_narrow_terminals() {
+    assert_eq!(truncate("AdGuard DNS filter", 10), "AdGuard...");
+    assert_eq!(truncate("AdGuard DNS filter", 2), "...");
+
+    let filtering: AdGuardFilteringStatus = serde_json::from_value(json!({
+      "filters": [{ "id": 1, "name": "AdGuard DNS filter", "url": "https://a/list.txt", "rules_count": 1, "enabled": true }],
+    })).unwrap();
+    for width in [0, 20, 47, 200] {
+      make_filters_list(Some(&filtering), &HashMap::new(), width, false);
+    }
+  }
 }
@@ -0,0 +1,52 @@
+use tui::{
+  style::{Color, Modifier, Style},
+  text::{Span, Spans},
+  widgets::Paragraph,
+};
+
+use crate::connection::ConnectionState;
+use crate::instances::{InstanceSummary, View};
+
+// Label for one of the views, highlighted if it's the one being shown
+fn view_label(label: String, selected: bool) -> Span<'static> {
+  if selected {
+    Span::styled(label, Style::default().fg(Color::Black).bg(Color::Cyan).add_modifier(Modifier::BOLD))
+  } else {
+    Span::styled(label, Style::default().add_modifier(Modifier::BOLD))
+  }
+}
+
+// One-line bar listing every instance, with its connection, version, and whether it's running
+// and protecting. The one being shown (or "All", when they're combined) is highlighted
+pub fn make_instances_bar(instances: &[InstanceSummary], view: View) -> Paragraph<'static> {
+  let dimmed = Style::default().fg(Color::DarkGray);
+  let on_off = |label: &str, on: bool| Span::styled(
+    format!(" {}", label),
+    Style::default().fg(if on { Color::Green } else { Color::Red }),
+  );
+
+  let mut spans = vec![Span::raw(" "), view_label(" All ".to_string(), view == View::All)];
+  for (index, instance) in instances.iter().enumerate() {
+    spans.push(Span::styled(" │ ", dimmed));
+    let (symbol, color) = match instance.connection {
+      ConnectionState::Connecting => ("◌", Color::Blue),
+      ConnectionState::Connected { .. } => ("●", Color::Green),
+      ConnectionState::Retrying { .. } => ("⚠", Color::Red),
+    };
+    spans.push(Span::styled(format!("{} ", symbol), Style::default().fg(color)));
+    spans.push(view_label(format!(" {} ", instance.name), view == View::Single(index)));
+    match &instance.status {
+      Some(status) => {
+        spans.push(Span::styled(format!(" {}", status.version), dimmed));
+        spans.push(on_off(if status.running { "running" } else { "stopped" }, status.running));
+        spans.push(on_off(
+          if status.protection_enabled { "protected" } else { "unprotected" },
+          status.protection_enabled,
+        ));
+      }
+      None => spans.push(Span::styled(" loading...", dimmed)),
+    }
+  }
+  spans.push(Span::styled("  (i to switch)", dimmed));
+  Paragraph::new(Spans::from(spans))
+}
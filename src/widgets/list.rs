@@ -8,20 +8,34 @@ use tui::{
   style::{Color, Style, Modifier},
 };
 
-fn truncate(text: &str, width: usize) -> String {
+// Border style for a panel, highlighted if it's the one that keys currently act on
+pub fn focus_style(focused: bool) -> Style {
+  if focused { Style::default().fg(Color::Cyan) } else { Style::default() }
+}
+
+// Fewest characters of a name to show in the lists along the bottom, however narrow the terminal is
+const MIN_NAME_WIDTH: usize = 8;
+
+// Characters of a name to show in one of the lists along the bottom, which each get a quarter of the width,
+// less room for the borders and counts
+pub fn name_width(width: u16) -> usize {
+  (width as usize / 4).saturating_sub(12).max(MIN_NAME_WIDTH)
+}
+
+pub fn truncate(text: &str, width: usize) -> String {
   if text.chars().count() <= width {
       text.to_string()
   } else {
-      text.chars().take(width - 3).collect::<String>() + "..."
+      text.chars().take(width.saturating_sub(3)).collect::<String>() + "..."
   }
 }
 
-pub fn make_list<'a>(title: &'a str, data: &[DomainData], color: Color, width: u16) -> List<'a> {
+pub fn make_list<'a>(title: &'a str, data: &[DomainData], color: Color, width: u16, focused: bool) -> List<'a> {
   let items: Vec<ListItem> = data
       .iter()
       .map(|data| {
 
-          let name = Span::raw(format!(" {}", truncate(&data.name, width as usize / 4 - 12)));
+          let name = Span::raw(format!(" {}", truncate(&data.name, name_width(width))));
           let count = Span::styled(format!(" ({})", data.count), Style::default().fg(color).add_modifier(Modifier::BOLD));
           ListItem::new(Spans::from(vec![name, count]))
       })
@@ -29,10 +43,11 @@ pub fn make_list<'a>(title: &'a str, data: &[DomainData], color: Color, width: u
 
   List::new(items)
       .block(Block::default().borders(Borders::ALL)
+      .border_style(focus_style(focused))
       .title(Span::styled(
         title,
         Style::default().add_modifier(Modifier::BOLD),
       )))
-      .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+      .highlight_style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD))
 }
 
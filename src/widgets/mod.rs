@@ -1,6 +1,12 @@
 pub mod gauge;
+pub mod instances;
 pub mod table;
 pub mod chart;
 pub mod list;
 pub mod status;
 pub mod filters;
+pub mod connection;
+pub mod popup;
+pub mod query_detail;
+pub mod rules_editor;
+pub mod theme;
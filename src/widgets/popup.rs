@@ -0,0 +1,256 @@
+use tui::{
+  layout::{Constraint, Direction, Layout, Rect},
+  style::{Color, Modifier, Style},
+  text::{Span, Spans},
+  widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
+};
+
+use crate::app::{FilterField, FilterForm, PAUSE_OPTIONS};
+use crate::fetch::fetch_filters::Filter;
+use crate::fetch::fetch_query_log::ResponseStatus;
+use crate::rules::{make_rule, rule_target, RuleKind, RuleScope};
+
+// How far some lines can be scrolled in a bordered popup of the given size, before the last line
+// reaches the bottom. Lines are word wrapped (roughly as a wrapping Paragraph does) to fit its width
+pub fn scroll_limit(lines: &[Spans], area: Rect) -> u16 {
+  let width = area.width.saturating_sub(2).max(1) as usize;
+  let rows: usize = lines.iter().map(|line| {
+    let text: String = line.0.iter().map(|span| span.content.as_ref()).collect();
+    let mut rows = 1;
+    let mut used = 0;
+    for word in text.split_inclusive(' ') {
+      let word_width = Span::raw(word).width();
+      let visible_width = Span::raw(word.trim_end()).width();
+      if used > 0 && used + visible_width > width {
+        rows += 1;
+        used = 0;
+      }
+      used += word_width;
+      // Spaces hanging off the end of a row don't need a row of their own
+      if used > width && used - (word_width - visible_width) <= width {
+        used = width;
+      }
+      // Words too long for a whole row are broken across several
+      while used > width {
+        rows += 1;
+        used -= width;
+      }
+    }
+    rows
+  }).sum();
+  rows.saturating_sub(area.height.saturating_sub(2) as usize).min(u16::MAX as usize) as u16
+}
+
+// Returns a rectangle of the given size, centered within the area, for drawing a popup in
+pub fn centered_rect(width: u16, height: u16, area: Rect) -> Rect {
+  let vertical = Layout::default()
+    .direction(Direction::Vertical)
+    .constraints([
+      Constraint::Length(area.height.saturating_sub(height) / 2),
+      Constraint::Length(height.min(area.height)),
+      Constraint::Min(0),
+    ].as_ref())
+    .split(area);
+
+  Layout::default()
+    .direction(Direction::Horizontal)
+    .constraints([
+      Constraint::Length(area.width.saturating_sub(width) / 2),
+      Constraint::Length(width.min(area.width)),
+      Constraint::Min(0),
+    ].as_ref())
+    .split(vertical[1])[1]
+}
+
+// Shared block for popups, with a bold title
+pub fn popup_block(title: &str) -> Block<'_> {
+  Block::default()
+    .borders(Borders::ALL)
+    .border_style(Style::default().fg(Color::Yellow))
+    .title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD)))
+}
+
+// List of durations that protection can be paused for
+pub fn make_pause_picker() -> List<'static> {
+  let items: Vec<ListItem> = PAUSE_OPTIONS
+    .iter()
+    .map(|(label, _)| ListItem::new(format!(" {}", label)))
+    .collect();
+
+  List::new(items)
+    .block(popup_block("Pause protection for..."))
+    .highlight_style(Style::default().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD))
+    .highlight_symbol("› ")
+}
+
+// Confirmation for adding a block or allow rule, with a choice of which domain it applies to
+pub fn make_rule_confirmation(host: &str, kind: RuleKind, scope: RuleScope) -> Paragraph<'static> {
+  let (title, color) = match kind {
+    RuleKind::Block => ("Block domain?", Color::Red),
+    RuleKind::Allow => ("Allow domain?", Color::Green),
+  };
+  let option = |option_scope: RuleScope, label: &str| {
+    let selected = option_scope == scope;
+    Spans::from(vec![
+      Span::raw(if selected { " (•) " } else { " ( ) " }),
+      Span::styled(
+        format!("{}: {}", label, rule_target(host, option_scope)),
+        if selected { Style::default().add_modifier(Modifier::BOLD) } else { Style::default() },
+      ),
+    ])
+  };
+  let rule = make_rule(&rule_target(host, scope), kind);
+
+  let lines = vec![
+    option(RuleScope::ExactHost, "Exact host"),
+    option(RuleScope::ParentDomain, "Parent domain"),
+    Spans::from(""),
+    Spans::from(vec![
+      Span::raw(" Rule: "),
+      Span::styled(rule, Style::default().fg(color).add_modifier(Modifier::BOLD)),
+    ]),
+    Spans::from(""),
+    Spans::from(Span::styled(
+      " Enter to add · ←/→ to change domain · Esc to cancel",
+      Style::default().fg(Color::DarkGray),
+    )),
+  ];
+
+  Paragraph::new(lines)
+    .block(popup_block(title).border_style(Style::default().fg(color)))
+    .wrap(Wrap { trim: false })
+}
+
+// Prompt for searching the query log, with the text typed so far, and a choice of response status
+pub fn make_search_prompt(input: &str, status: ResponseStatus) -> Paragraph<'static> {
+  let mut statuses = vec![Span::raw(" Status: ")];
+  for option in ResponseStatus::ALL {
+    let style = if option == status {
+      Style::default().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD)
+    } else {
+      Style::default().fg(Color::Gray)
+    };
+    statuses.push(Span::styled(format!(" {} ", option.label()), style));
+    statuses.push(Span::raw(" "));
+  }
+
+  let lines = vec![
+    Spans::from(vec![
+      Span::raw(" Domain or client: "),
+      Span::styled(input.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+      Span::styled("█", Style::default().fg(Color::Yellow)),
+    ]),
+    Spans::from(""),
+    Spans::from(statuses),
+    Spans::from(""),
+    Spans::from(Span::styled(
+      " Enter to search · Tab to change status · Ctrl+U to clear · Esc to cancel",
+      Style::default().fg(Color::DarkGray),
+    )),
+  ];
+
+  Paragraph::new(lines)
+    .block(popup_block("Search Query Log"))
+    .wrap(Wrap { trim: false })
+}
+
+// Form for adding a filter list, with a cursor on the field being typed in
+pub fn make_add_filter_form(form: &FilterForm) -> Paragraph<'static> {
+  let label = |text: &str, field: FilterField| {
+    let style = if form.field == field { Style::default().add_modifier(Modifier::BOLD) } else { Style::default().fg(Color::Gray) };
+    Span::styled(format!(" {:<6}", text), style)
+  };
+  let input = |text: &str, value: &str, field: FilterField| {
+    let mut spans = vec![label(text, field), Span::styled(value.to_string(), Style::default().add_modifier(Modifier::BOLD))];
+    if form.field == field {
+      spans.push(Span::styled("█", Style::default().fg(Color::Yellow)));
+    }
+    Spans::from(spans)
+  };
+  let mut kinds = vec![label("Type:", FilterField::Kind)];
+  for (text, allowlist) in [("Blocklist", false), ("Allowlist", true)] {
+    let style = if allowlist == form.allowlist {
+      Style::default().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD)
+    } else {
+      Style::default().fg(Color::Gray)
+    };
+    kinds.push(Span::styled(format!(" {} ", text), style));
+    kinds.push(Span::raw(" "));
+  }
+
+  let lines = vec![
+    input("Name:", &form.name, FilterField::Name),
+    input("URL:", &form.url, FilterField::Url),
+    Spans::from(kinds),
+    Spans::from(""),
+    Spans::from(Span::styled(
+      " Enter to add · Tab to switch field · ←/→ to change type · Ctrl+U to clear · Esc to cancel",
+      Style::default().fg(Color::DarkGray),
+    )),
+  ];
+
+  Paragraph::new(lines)
+    .block(popup_block("Add Filter List"))
+    .wrap(Wrap { trim: false })
+}
+
+// Confirmation before removing a filter list
+pub fn make_remove_filter_confirmation(filter: &Filter) -> Paragraph<'static> {
+  let lines = vec![
+    Spans::from(vec![
+      Span::raw(" Remove "),
+      Span::styled(filter.name.clone(), Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+      Span::raw(format!(" ({} rules)?", filter.rules_count)),
+    ]),
+    Spans::from(Span::styled(format!(" {}", filter.url), Style::default().fg(Color::Gray))),
+    Spans::from(""),
+    Spans::from(Span::styled(" Enter to remove · Esc to cancel", Style::default().fg(Color::DarkGray))),
+  ];
+
+  Paragraph::new(lines)
+    .block(popup_block("Remove filter list?").border_style(Style::default().fg(Color::Red)))
+    .wrap(Wrap { trim: false })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tui::{backend::TestBackend, Terminal};
+
+  // Renders the lines in a bordered popup, scrolled as far as it can be, returning each row of text
+  fn render_scrolled(lines: Vec<Spans<'static>>, width: u16, height: u16) -> Vec<String> {
+    let area = Rect::new(0, 0, width, height);
+    let limit = scroll_limit(&lines, area);
+    let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+    terminal.draw(|f| {
+      let paragraph = Paragraph::new(lines).block(popup_block("Test")).wrap(Wrap { trim: false }).scroll((limit, 0));
+      f.render_widget(paragraph, area);
+    }).unwrap();
+    let buffer = terminal.backend().buffer();
+    (0..height).map(|y| (0..width).map(|x| buffer.get(x, y).symbol.clone()).collect()).collect()
+  }
+
+  #[test]
+  fn content_that_fits_cant_be_scrolled() {
+    let lines = vec![Spans::from("one"), Spans::from("two")];
+    assert_eq!(scroll_limit(&lines, Rect::new(0, 0, 20, 10)), 0);
+  }
+
+  #[test]
+  fn scrolls_until_the_last_line_is_at_the_bottom() {
+    let lines: Vec<Spans> = (1..=12).map(|n| Spans::from(format!("line {}", n))).collect();
+    assert_eq!(scroll_limit(&lines, Rect::new(0, 0, 20, 7)), 7);
+    let rows = render_scrolled(lines, 20, 7);
+    assert!(rows[5].contains("line 12"));
+  }
+
+  #[test]
+  fn counts_rows_taken_up_by_wrapped_lines() {
+    let long = "a fairly long line of words that has to be wrapped over several rows".to_string();
+    let unbroken = "x".repeat(40);
+    let lines = vec![Spans::from(long), Spans::from(unbroken), Spans::from("last")];
+    let rows = render_scrolled(lines, 20, 6);
+    assert!(rows[4].contains("last"));
+    assert!(!rows[3].contains("last"));
+  }
+}
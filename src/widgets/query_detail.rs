@@ -0,0 +1,122 @@
+use tui::{
+  style::{Color, Modifier, Style},
+  text::{Span, Spans},
+  widgets::{Paragraph, Wrap},
+};
+use serde_json::Value;
+
+use crate::fetch::fetch_filters::AdGuardFilteringStatus;
+use crate::fetch::fetch_query_log::{Answer, Query};
+use crate::widgets::popup::popup_block;
+use crate::widgets::table::{block_status_text, list_name, make_row_color, matched_rule_text, time_ago};
+
+// A line with a label, and a value in the given colour
+fn field<'a>(label: &'a str, value: String, color: Color) -> Spans<'a> {
+  Spans::from(vec![
+    Span::styled(format!("{}: ", label), Style::default().add_modifier(Modifier::BOLD)),
+    Span::styled(value, Style::default().fg(color)),
+  ])
+}
+
+// A heading, for a section which lists multiple values
+fn heading(label: &str) -> Spans<'_> {
+  Spans::from(Span::styled(
+    format!("{}:", label),
+    Style::default().add_modifier(Modifier::BOLD),
+  ))
+}
+
+// One line for each answer record, or a single line if there are none
+fn answer_lines(answers: &[Answer]) -> Vec<Spans<'static>> {
+  if answers.is_empty() {
+    return vec![Spans::from(Span::styled("  (none)", Style::default().fg(Color::DarkGray)))];
+  }
+  answers.iter().map(|answer| {
+    let value = match &answer.value {
+      Value::String(value) => value.clone(),
+      other => other.to_string(),
+    };
+    Spans::from(vec![
+      Span::styled(format!("  {} ", answer.answer_type), Style::default().fg(Color::Magenta)),
+      Span::raw(value),
+      Span::styled(format!(" (TTL {}s)", answer.ttl), Style::default().fg(Color::DarkGray)),
+    ])
+  }).collect()
+}
+
+// Every field AdGuard returned for a single query, one per line, for the popup
+pub fn query_detail_lines<'a>(query: &'a Query, filter_lists: Option<&AdGuardFilteringStatus>) -> Vec<Spans<'a>> {
+  let (status_text, status_color) = block_status_text(query);
+  let ago = time_ago(&query.time).unwrap_or_else(|_| "unknown".to_string());
+
+  let mut lines = vec![
+    field("Domain", query.question.name.clone(), Color::White),
+    field("Type", format!("{} ({})", query.question.question_type, query.question.class), Color::Magenta),
+    field("Time", format!("{} ({})", query.time, ago), Color::Gray),
+    field("Status", format!("{} ({})", status_text, query.reason.as_str()), status_color),
+  ];
+  if let Some(matched) = matched_rule_text(query, filter_lists) {
+    lines.push(field("Rule", matched, status_color));
+  }
+  if let Some(instance) = &query.instance {
+    lines.push(field("Instance", instance.to_string(), Color::Magenta));
+  }
+  if query.gap_before {
+    lines.push(field("Gap", "queries from just before this one were missed".to_string(), Color::Yellow));
+  }
+  lines.extend([
+    field("Response Code", query.status.clone(), Color::Cyan),
+    field("Cached", query.cached.to_string(), Color::Cyan),
+    field("DNSSEC", query.answer_dnssec.to_string(), Color::Cyan),
+    field("Elapsed", format!("{} ms", query.elapsed_ms), Color::Yellow),
+    field("Upstream", query.upstream.clone(), Color::Blue),
+    field("Client", query.client.clone(), Color::Blue),
+  ]);
+
+  if let Some(info) = &query.client_info {
+    if !info.name.is_empty() {
+      lines.push(field("Client Name", info.name.clone(), Color::Blue));
+    }
+    let mut whois: Vec<_> = info.whois.iter().collect();
+    whois.sort();
+    for (key, value) in whois {
+      lines.push(field("Client Whois", format!("{}: {}", key, value), Color::Blue));
+    }
+    if info.disallowed {
+      lines.push(field("Client Disallowed", "true".to_string(), Color::Red));
+    }
+  }
+  if !query.client_proto.is_empty() {
+    lines.push(field("Client Protocol", query.client_proto.clone(), Color::Blue));
+  }
+  if let Some(service) = query.blocked_service() {
+    lines.push(field("Blocked Service", service.to_string(), make_row_color(&query.reason)));
+  }
+
+  lines.push(heading("Matched Rules"));
+  if query.rules.is_empty() {
+    lines.push(Spans::from(Span::styled("  (none)", Style::default().fg(Color::DarkGray))));
+  }
+  for rule in &query.rules {
+    lines.push(Spans::from(vec![
+      Span::styled(format!("  [{}] ", list_name(rule.filter_list_id, filter_lists)), Style::default().fg(Color::DarkGray)),
+      Span::styled(rule.text.clone(), Style::default().fg(Color::Yellow)),
+    ]));
+  }
+
+  lines.push(heading("Answers"));
+  lines.extend(answer_lines(&query.answer));
+  if !query.original_answer.is_empty() {
+    lines.push(heading("Original Answers"));
+    lines.extend(answer_lines(&query.original_answer));
+  }
+  lines
+}
+
+// Popup content, showing the lines for a single query, scrolled down by a number of lines
+pub fn make_query_detail(lines: Vec<Spans>, scroll: u16) -> Paragraph {
+  Paragraph::new(lines)
+    .block(popup_block("Query Details (Esc to close, ↑/↓ to scroll)"))
+    .wrap(Wrap { trim: false })
+    .scroll((scroll, 0))
+}
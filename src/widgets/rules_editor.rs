@@ -0,0 +1,75 @@
+use tui::{
+  style::{Color, Modifier, Style},
+  text::{Span, Spans},
+  widgets::Paragraph,
+};
+
+use crate::app::RulesEditor;
+use crate::rules::validate_rule;
+use crate::widgets::popup::popup_block;
+
+// Style for a rule, based on what kind of rule it is
+fn rule_style(line: &str) -> Style {
+  let line = line.trim_start();
+  if line.starts_with('!') || line.starts_with('#') {
+    Style::default().fg(Color::DarkGray)
+  } else if line.starts_with("@@") {
+    Style::default().fg(Color::Green)
+  } else {
+    Style::default()
+  }
+}
+
+// A single line of the editor, with a line number (or a warning, if the rule doesn't look valid),
+// and the cursor drawn over the character it's on
+fn editor_line(editor: &RulesEditor, row: usize) -> Spans<'static> {
+  let line = &editor.lines[row];
+  let valid = validate_rule(line).is_ok();
+  let gutter = if valid {
+    Span::styled(format!("{:>4} ", row + 1), Style::default().fg(Color::DarkGray))
+  } else {
+    Span::styled(format!("{:>4} ", "!"), Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD))
+  };
+  let style = if valid { rule_style(line) } else { Style::default().fg(Color::Yellow) };
+
+  if row != editor.row {
+    return Spans::from(vec![gutter, Span::styled(line.clone(), style)]);
+  }
+  let before: String = line.chars().take(editor.col).collect();
+  let cursor: String = line.chars().nth(editor.col).map_or(" ".to_string(), String::from);
+  let after: String = line.chars().skip(editor.col + 1).collect();
+  Spans::from(vec![
+    gutter,
+    Span::styled(before, style),
+    Span::styled(cursor, style.fg(Color::Black).bg(Color::Yellow)),
+    Span::styled(after, style),
+  ])
+}
+
+// Popup for editing the custom user rules, scrolled to keep the cursor in view, given the popup's height.
+// Rules which don't look valid are marked, and the first problem is explained at the bottom,
+// though they can still be saved
+pub fn make_rules_editor(editor: &RulesEditor, height: u16) -> Paragraph<'static> {
+  // Leave room for the borders, and the two lines at the bottom
+  let visible = (height as usize).saturating_sub(4).max(1);
+  let scroll = editor.row.saturating_sub(visible - 1);
+
+  let mut lines: Vec<Spans> = (scroll..editor.lines.len().min(scroll + visible))
+    .map(|row| editor_line(editor, row))
+    .collect();
+  lines.resize(visible, Spans::from(""));
+
+  lines.push(match editor.first_invalid() {
+    Some((row, e)) => Spans::from(Span::styled(
+      format!(" Line {}: {} (it can still be saved, if AdGuard accepts it)", row + 1, e),
+      Style::default().fg(Color::Yellow),
+    )),
+    None => Spans::from(Span::styled(" All rules are valid", Style::default().fg(Color::Green))),
+  });
+  lines.push(Spans::from(Span::styled(
+    " Ctrl+S to save · Esc to cancel · Lines starting with ! are comments",
+    Style::default().fg(Color::DarkGray),
+  )));
+
+  Paragraph::new(lines).block(popup_block("Custom Rules"))
+}
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use tui::{
   style::{Color, Style, Modifier},
   text::{Span, Spans},
@@ -7,10 +8,40 @@ use tui::{
 use crate::fetch::fetch_status::StatusResponse;
 use crate::fetch::fetch_stats::StatsResponse;
 
-pub fn render_status_paragraph<'a>(status: &'a StatusResponse, stats: &'a StatsResponse) -> Paragraph<'a> {
+// Formats a duration as hours, minutes and seconds, e.g. "1h 05m 09s"
+fn format_countdown(duration: Duration) -> String {
+  let secs = duration.as_secs();
+  match (secs / 3600, secs % 3600 / 60, secs % 60) {
+    (0, 0, s) => format!("{}s", s),
+    (0, m, s) => format!("{}m {:02}s", m, s),
+    (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+  }
+}
+
+// Warning line for when TLS certificates aren't verified, shown above the status, or in place of it while loading
+pub fn make_insecure_warning_line(warning: &str) -> Spans<'static> {
+  Spans::from(Span::styled(
+    format!("⚠ {} ⚠", warning),
+    // Reverse video, rather than a background, so it still stands out in the monochrome theme
+    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD | Modifier::REVERSED),
+  ))
+}
 
+pub fn render_status_paragraph<'a>(
+  status: &'a StatusResponse,
+  stats: &'a StatsResponse,
+  protection_resumes_in: Option<Duration>,
+  insecure_warning: Option<String>,
+) -> Paragraph<'a> {
+
+  // Skipping TLS verification is dangerous, so the panel's border turns red too, to make sure it's noticed
+  let border_style = match insecure_warning {
+    Some(_) => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+    None => Style::default(),
+  };
   let block = Block::default()
         .borders(Borders::ALL)
+        .border_style(border_style)
         .title(Span::styled(
             "Status",
             Style::default().add_modifier(Modifier::BOLD),
@@ -27,7 +58,11 @@ pub fn render_status_paragraph<'a>(status: &'a StatusResponse, stats: &'a StatsR
     };
       
 
-  let text = vec![
+  let mut text = Vec::new();
+  if let Some(warning) = insecure_warning {
+    text.push(make_insecure_warning_line(&warning));
+  }
+  text.extend([
       Spans::from(vec![
         Span::styled("Running: ", Style::default()),
         Span::styled(
@@ -41,6 +76,12 @@ pub fn render_status_paragraph<'a>(status: &'a StatusResponse, stats: &'a StatsR
             format!("{}", status.protection_enabled),
             Style::default().fg(get_color(status.protection_enabled)).add_modifier(Modifier::BOLD)
         ),
+        Span::styled(
+            protection_resumes_in
+              .map(|remaining| format!(" (resumes in {})", format_countdown(remaining)))
+              .unwrap_or_default(),
+            Style::default().fg(Color::Yellow)
+        ),
     ]),
       Spans::from(vec![
         Span::styled("DHCP Available: ", Style::default()),
@@ -81,7 +122,7 @@ pub fn render_status_paragraph<'a>(status: &'a StatusResponse, stats: &'a StatsR
       Span::styled("Safe Search: ", Style::default()),
       Span::styled(stats.num_replaced_safesearch.to_string(), coloured(Color::Cyan)),
     ]),
-  ];
+  ]);
   Paragraph::new(text)
     .wrap(Wrap { trim: true })
     .block(block)
@@ -2,20 +2,47 @@
 use tui::{
   style::{Color, Modifier, Style},
   widgets::{Block, Borders, Cell, Row, Table},
-  text::{Span},
+  text::{Span, Spans},
   layout::{Constraint},
 };
 use chrono::{DateTime, Utc};
 
-use crate::fetch::fetch_query_log::{Query, Question};
-pub fn make_query_table(data: &[Query], width: u16) -> Table<'_> {
+use crate::fetch::fetch_filters::AdGuardFilteringStatus;
+use crate::fetch::fetch_query_log::{Query, QueryFilter, QueryReason, Question, ResponseStatus};
+use crate::widgets::list::focus_style;
+// Table of the given rows from the query log, out of `total` entries held which match the filter.
+// Filter lists are used to name the list that each matched rule came from
+pub fn make_query_table<'a>(
+  data: &'a [Query],
+  total: usize,
+  filter: &QueryFilter,
+  filter_lists: Option<&AdGuardFilteringStatus>,
+  width: u16,
+  focused: bool,
+) -> Table<'a> {
   let rows = data.iter().map(|query| {
-      let time = Cell::from(
-          time_ago(query.time.as_str()).unwrap_or("unknown".to_string())
-      ).style(Style::default().fg(Color::Gray));
+      let mut time = vec![Span::styled(
+          time_ago(query.time.as_str()).unwrap_or("unknown".to_string()),
+          Style::default().fg(Color::Gray),
+      )];
+      // Entries just before this one were never fetched
+      if query.gap_before {
+          time.push(Span::styled(" (gap)", Style::default().fg(Color::Yellow)));
+      }
+      let time = Cell::from(Spans::from(time));
       
-      let question = Cell::from(make_request_cell(&query.question).unwrap())
-          .style(Style::default().add_modifier(Modifier::BOLD));
+      // When showing several instances, each entry is tagged with the one it came from
+      let mut request: Vec<Span> = query.instance.iter()
+          .map(|instance| Span::styled(format!("[{}] ", instance), Style::default().fg(Color::Magenta)))
+          .collect();
+      request.push(Span::styled(
+          make_request_cell(&query.question).unwrap(),
+          Style::default().add_modifier(Modifier::BOLD),
+      ));
+      if let Some(matched) = matched_rule_text(query, filter_lists) {
+          request.push(Span::styled(format!("  {}", matched), Style::default().fg(Color::DarkGray)));
+      }
+      let question = Cell::from(Spans::from(request));
 
       let client = Cell::from(query.client.as_str())
           .style(Style::default().fg(Color::Blue));
@@ -23,25 +50,41 @@ pub fn make_query_table(data: &[Query], width: u16) -> Table<'_> {
       let (time_taken, elapsed_color) = make_time_taken_and_color(&query.elapsed_ms).unwrap();
       let elapsed_ms = Cell::from(time_taken).style(Style::default().fg(elapsed_color));
 
-      let (status_txt, status_color) = block_status_text(&query.reason, query.cached);
+      let (status_txt, status_color) = block_status_text(query);
       let status = Cell::from(status_txt).style(Style::default().fg(status_color));
 
       let upstream = Cell::from(query.upstream.as_str()).style(Style::default().fg(Color::Blue));
 
-      let color = make_row_color(&query.reason);
+      let mut style = Style::default().fg(make_row_color(&query.reason));
+      if query.gap_before {
+          style = style.add_modifier(Modifier::UNDERLINED);
+      }
       Row::new(vec![time, question, status, elapsed_ms, client, upstream])
-          .style(Style::default().fg(color))
+          .style(style)
   }).collect::<Vec<Row>>();
 
   
+  let title = if filter.is_empty() {
+    format!("Query Log ({} entries)", total)
+  } else {
+    let mut matching = Vec::new();
+    if !filter.search.is_empty() {
+      matching.push(format!("\"{}\"", filter.search));
+    }
+    if filter.response_status != ResponseStatus::All {
+      matching.push(filter.response_status.label().to_lowercase());
+    }
+    format!("Query Log - matching {} ({} entries, Esc to clear)", matching.join(", "), total)
+  };
   let title = Span::styled(
-    "Query Log",
+    title,
     Style::default().add_modifier(Modifier::BOLD),
   );
 
   let block = Block::default()
       .title(title)
-      .borders(Borders::ALL);
+      .borders(Borders::ALL)
+      .border_style(focus_style(focused));
 
   let mut headers = vec![
       Cell::from(Span::raw("Time")),
@@ -69,6 +112,7 @@ pub fn make_query_table(data: &[Query], width: u16) -> Table<'_> {
           .header(Row::new(headers))
           .widths(widths)
           .block(block)
+          .highlight_style(highlight_style())
   } else {
       let widths = &[
           Constraint::Percentage(20),
@@ -81,11 +125,17 @@ pub fn make_query_table(data: &[Query], width: u16) -> Table<'_> {
           .header(Row::new(headers))
           .widths(widths)
           .block(block)
+          .highlight_style(highlight_style())
   }
 }
 
+// Style for the currently selected row
+fn highlight_style() -> Style {
+  Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD)
+}
+
 // Given a timestamp, return a string representing how long ago that was
-fn time_ago(timestamp: &str) -> Result<String, anyhow::Error> {
+pub fn time_ago(timestamp: &str) -> Result<String, anyhow::Error> {
   let datetime = DateTime::parse_from_rfc3339(timestamp)?;
   let datetime_utc = datetime.with_timezone(&Utc);
   let now = Utc::now();
@@ -119,29 +169,53 @@ fn make_time_taken_and_color(elapsed: &str) -> Result<(String, Color), anyhow::E
   Ok((time_taken, color))
 }
 
+// Describes the first rule that matched a query, and the list it came from,
+// e.g. "Blocked by AdGuard DNS filter: ||ads.example.com^"
+pub fn matched_rule_text(query: &Query, filter_lists: Option<&AdGuardFilteringStatus>) -> Option<String> {
+  let rule = query.rules.first()?;
+  let verb = match query.reason {
+      QueryReason::Allowlisted => "Allowed by",
+      QueryReason::Rewritten | QueryReason::RewrittenHosts | QueryReason::RewrittenRule => "Rewritten by",
+      ref reason if reason.is_blocked() => "Blocked by",
+      _ => "Matched",
+  };
+  Some(format!("{} {}: {}", verb, list_name(rule.filter_list_id, filter_lists), rule.text))
+}
+
+// Name of the filter list with the given ID, or its ID if the list isn't known (e.g. it's since been removed)
+pub fn list_name(id: i64, filter_lists: Option<&AdGuardFilteringStatus>) -> String {
+  filter_lists
+      .and_then(|lists| lists.list_name(id))
+      .map(str::to_string)
+      .unwrap_or_else(|| format!("filter list {}", id))
+}
+
 // Return color for a row, based on the allow/block reason
-fn make_row_color(reason: &str) -> Color {
-  if reason == "NotFilteredNotFound" {
-      Color::Green
-  } else if reason == "FilteredBlackList" {
-      Color::Red
-  } else {
-      Color::Yellow
+pub fn make_row_color(reason: &QueryReason) -> Color {
+  match reason {
+      QueryReason::NotFiltered => Color::Green,
+      QueryReason::Allowlisted => Color::LightGreen,
+      QueryReason::Error => Color::LightYellow,
+      QueryReason::Blocked => Color::Red,
+      QueryReason::SafeBrowsing => Color::LightRed,
+      QueryReason::Parental => Color::Magenta,
+      QueryReason::Invalid => Color::DarkGray,
+      QueryReason::SafeSearch => Color::Yellow,
+      QueryReason::BlockedService => Color::LightMagenta,
+      QueryReason::Rewritten | QueryReason::RewrittenHosts | QueryReason::RewrittenRule => Color::LightBlue,
+      QueryReason::Other(_) => Color::White,
   }
 }
 
-// Return text and color for the status cell based on allow/ block reason
-fn block_status_text(reason: &str, cached: bool) -> (String, Color) {
-  let (text, color) =
-  if reason == "FilteredBlackList" {
-      ("Blacklisted".to_string(), Color::Red)
-  } else if cached {
-      ("Cached".to_string(), Color::Cyan)
-  } else if reason == "NotFilteredNotFound" {
-      ("Allowed".to_string(), Color::Green)
-  } else {
-      ("Other Block".to_string(), Color::Yellow)
-  };
-  (text, color)
+// Return text and color for the status cell, based on the allow/block reason.
+// Allowed queries which were answered from the cache are shown as cached
+pub fn block_status_text(query: &Query) -> (String, Color) {
+  if let Some(service) = query.blocked_service() {
+      return (format!("Blocked: {}", service), make_row_color(&query.reason));
+  }
+  if query.cached && query.reason == QueryReason::NotFiltered {
+      return ("Cached".to_string(), Color::Cyan);
+  }
+  (query.reason.label().to_string(), make_row_color(&query.reason))
 }
 
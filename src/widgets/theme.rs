@@ -0,0 +1,28 @@
+use tui::{
+  buffer::Buffer,
+  layout::Rect,
+  style::{Color, Modifier},
+  widgets::Widget,
+};
+
+// Backgrounds used to highlight a selection, which become reverse video when colours are removed.
+// Any other background (e.g. the gauge's) is purely decorative, so is dropped
+const HIGHLIGHT_BACKGROUNDS: [Color; 2] = [Color::DarkGray, Color::Yellow];
+
+// Strips the colour from everything already rendered in an area, for the monochrome theme
+pub struct Monochrome;
+
+impl Widget for Monochrome {
+  fn render(self, area: Rect, buf: &mut Buffer) {
+    for y in area.top()..area.bottom() {
+      for x in area.left()..area.right() {
+        let cell = buf.get_mut(x, y);
+        if HIGHLIGHT_BACKGROUNDS.contains(&cell.bg) {
+          cell.modifier.insert(Modifier::REVERSED);
+        }
+        cell.fg = Color::Reset;
+        cell.bg = Color::Reset;
+      }
+    }
+  }
+}